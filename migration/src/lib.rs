@@ -4,6 +4,15 @@ mod m20220101_000001_create_table;
 mod m20250722_152740_create_users;
 mod m20250724_161551_sessions;
 mod m20250727_234302_albums;
+mod m20261017_101500_session_owner;
+mod m20261017_143000_user_provider;
+mod m20261018_090000_identities;
+mod m20261018_120000_user_profile;
+mod m20261018_150000_user_role;
+mod m20261019_090000_device_authorization;
+mod m20261019_120000_api_token_scopes;
+mod m20261019_150000_session_client;
+mod m20261020_090000_user_email_verified;
 
 pub struct Migrator;
 
@@ -15,6 +24,15 @@ impl MigratorTrait for Migrator {
             Box::new(m20250722_152740_create_users::Migration),
             Box::new(m20250724_161551_sessions::Migration),
             Box::new(m20250727_234302_albums::Migration),
+            Box::new(m20261017_101500_session_owner::Migration),
+            Box::new(m20261017_143000_user_provider::Migration),
+            Box::new(m20261018_090000_identities::Migration),
+            Box::new(m20261018_120000_user_profile::Migration),
+            Box::new(m20261018_150000_user_role::Migration),
+            Box::new(m20261019_090000_device_authorization::Migration),
+            Box::new(m20261019_120000_api_token_scopes::Migration),
+            Box::new(m20261019_150000_session_client::Migration),
+            Box::new(m20261020_090000_user_email_verified::Migration),
         ]
     }
 }
@@ -0,0 +1,62 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+use crate::m20250722_152740_create_users::User;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[derive(DeriveIden)]
+enum Session {
+    Table,
+    UserId,
+    IdpSessionId,
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let table = Table::alter()
+            .table(Session::Table)
+            .add_column(uuid_null(Session::UserId))
+            .add_column(text_null(Session::IdpSessionId))
+            .add_foreign_key(
+                TableForeignKey::new()
+                    .name("fk_session_user")
+                    .from_tbl(Session::Table)
+                    .from_col(Session::UserId)
+                    .to_tbl(User::Table)
+                    .to_col(User::Id)
+                    .on_delete(ForeignKeyAction::Cascade),
+            )
+            .to_owned();
+        manager.alter_table(table).await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_session_user_id")
+                    .table(Session::Table)
+                    .col(Session::UserId)
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_session_idp_session_id")
+                    .table(Session::Table)
+                    .col(Session::IdpSessionId)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let table = Table::alter()
+            .table(Session::Table)
+            .drop_foreign_key(Alias::new("fk_session_user"))
+            .drop_column(Session::UserId)
+            .drop_column(Session::IdpSessionId)
+            .to_owned();
+        manager.alter_table(table).await
+    }
+}
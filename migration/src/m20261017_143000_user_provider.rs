@@ -0,0 +1,63 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+use crate::m20250722_152740_create_users::User;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[derive(DeriveIden)]
+enum UserProvider {
+    Provider,
+}
+
+/// Existing users signed in through the single, unnamed, provider
+const LEGACY_PROVIDER: &str = "default";
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let table = Table::alter()
+            .table(User::Table)
+            .add_column(text(UserProvider::Provider).default(LEGACY_PROVIDER))
+            .to_owned();
+        manager.alter_table(table).await?;
+        manager
+            .get_connection()
+            .execute_unprepared(r#"ALTER TABLE "user" DROP CONSTRAINT IF EXISTS "user_sid_key""#)
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_user_provider_sid")
+                    .table(User::Table)
+                    .col(UserProvider::Provider)
+                    .col(User::Sid)
+                    .unique()
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_index(
+                Index::drop()
+                    .name("idx_user_provider_sid")
+                    .table(User::Table)
+                    .to_owned(),
+            )
+            .await?;
+        let table = Table::alter()
+            .table(User::Table)
+            .drop_column(UserProvider::Provider)
+            .to_owned();
+        manager.alter_table(table).await?;
+        manager
+            .get_connection()
+            .execute_unprepared(
+                r#"ALTER TABLE "user" ADD CONSTRAINT "user_sid_key" UNIQUE ("sid")"#,
+            )
+            .await
+            .map(|_| ())
+    }
+}
@@ -0,0 +1,113 @@
+use sea_orm_migration::{
+    prelude::*,
+    schema::{timestamp_with_time_zone as timestamp_tz, *},
+};
+
+use crate::m20250722_152740_create_users::User;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[derive(DeriveIden)]
+enum Identity {
+    Table,
+    Id,
+    Provider,
+    Subject,
+    UserId,
+    Email,
+    #[sea_orm(iden = "_created_at")]
+    CreatedAt,
+}
+
+#[derive(DeriveIden)]
+enum UserProvider {
+    Provider,
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let table = Table::create()
+            .table(Identity::Table)
+            .if_not_exists()
+            .col(
+                uuid(Identity::Id)
+                    .primary_key()
+                    .default(Expr::cust("uuid_generate_v1()")),
+            )
+            .col(text(Identity::Provider))
+            .col(text(Identity::Subject))
+            .col(uuid(Identity::UserId))
+            .col(text_null(Identity::Email))
+            .col(timestamp_tz(Identity::CreatedAt).default(Expr::current_timestamp()))
+            .foreign_key(
+                ForeignKey::create()
+                    .name("fk_identity_user")
+                    .from(Identity::Table, Identity::UserId)
+                    .to(User::Table, User::Id)
+                    .on_delete(ForeignKeyAction::Cascade),
+            )
+            .to_owned();
+        manager.create_table(table).await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_identity_provider_subject")
+                    .table(Identity::Table)
+                    .col(Identity::Provider)
+                    .col(Identity::Subject)
+                    .unique()
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .get_connection()
+            .execute_unprepared(
+                r#"INSERT INTO "identity" ("provider", "subject", "user_id", "email")
+                SELECT "provider", "sid", "id", "email" FROM "user""#,
+            )
+            .await?;
+        let table = Table::alter()
+            .table(User::Table)
+            .drop_column(UserProvider::Provider)
+            .drop_column(User::Sid)
+            .to_owned();
+        manager.alter_table(table).await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let table = Table::alter()
+            .table(User::Table)
+            .add_column(text_null(User::Sid))
+            .add_column(text(UserProvider::Provider).default("default"))
+            .to_owned();
+        manager.alter_table(table).await?;
+        // Users linked to several identities keep the oldest one
+        manager
+            .get_connection()
+            .execute_unprepared(
+                r#"UPDATE "user" SET "provider" = i."provider", "sid" = i."subject"
+                FROM (
+                    SELECT DISTINCT ON ("user_id") "user_id", "provider", "subject"
+                    FROM "identity" ORDER BY "user_id", "_created_at"
+                ) i
+                WHERE i."user_id" = "user"."id""#,
+            )
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_user_provider_sid")
+                    .table(User::Table)
+                    .col(UserProvider::Provider)
+                    .col(User::Sid)
+                    .unique()
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .drop_table(Table::drop().table(Identity::Table).to_owned())
+            .await
+    }
+}
@@ -0,0 +1,42 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+use crate::m20250722_152740_create_users::User;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[derive(DeriveIden)]
+enum UserProfile {
+    GivenName,
+    FamilyName,
+    PreferredUsername,
+    Picture,
+    Locale,
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let table = Table::alter()
+            .table(User::Table)
+            .add_column(text_null(UserProfile::GivenName))
+            .add_column(text_null(UserProfile::FamilyName))
+            .add_column(text_null(UserProfile::PreferredUsername))
+            .add_column(text_null(UserProfile::Picture))
+            .add_column(text_null(UserProfile::Locale))
+            .to_owned();
+        manager.alter_table(table).await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let table = Table::alter()
+            .table(User::Table)
+            .drop_column(UserProfile::GivenName)
+            .drop_column(UserProfile::FamilyName)
+            .drop_column(UserProfile::PreferredUsername)
+            .drop_column(UserProfile::Picture)
+            .drop_column(UserProfile::Locale)
+            .to_owned();
+        manager.alter_table(table).await
+    }
+}
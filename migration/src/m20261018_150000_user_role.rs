@@ -0,0 +1,31 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+use crate::m20250722_152740_create_users::User;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[derive(DeriveIden)]
+enum UserRole {
+    Role,
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // Everybody could do everything so far, keep it that way until the next login
+        let table = Table::alter()
+            .table(User::Table)
+            .add_column(text(UserRole::Role).default("editor"))
+            .to_owned();
+        manager.alter_table(table).await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let table = Table::alter()
+            .table(User::Table)
+            .drop_column(UserRole::Role)
+            .to_owned();
+        manager.alter_table(table).await
+    }
+}
@@ -0,0 +1,104 @@
+use sea_orm_migration::{
+    prelude::*,
+    schema::{timestamp_with_time_zone as timestamp_tz, *},
+};
+
+use crate::m20250722_152740_create_users::User;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[derive(DeriveIden)]
+enum DeviceAuthorization {
+    Table,
+    Id,
+    DeviceCodeHash,
+    UserCode,
+    ClientName,
+    UserId,
+    Denied,
+    Interval,
+    LastPolledAt,
+    ExpiresAt,
+    #[sea_orm(iden = "_created_at")]
+    CreatedAt,
+}
+
+#[derive(DeriveIden)]
+enum ApiToken {
+    Table,
+    Id,
+    UserId,
+    TokenHash,
+    Name,
+    ExpiresAt,
+    LastUsedAt,
+    #[sea_orm(iden = "_created_at")]
+    CreatedAt,
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let table = Table::create()
+            .table(DeviceAuthorization::Table)
+            .if_not_exists()
+            .col(
+                uuid(DeviceAuthorization::Id)
+                    .primary_key()
+                    .default(Expr::cust("uuid_generate_v1()")),
+            )
+            .col(text_uniq(DeviceAuthorization::DeviceCodeHash))
+            .col(text_uniq(DeviceAuthorization::UserCode))
+            .col(text(DeviceAuthorization::ClientName))
+            .col(uuid_null(DeviceAuthorization::UserId))
+            .col(boolean(DeviceAuthorization::Denied).default(false))
+            .col(integer(DeviceAuthorization::Interval))
+            .col(timestamp_with_time_zone_null(
+                DeviceAuthorization::LastPolledAt,
+            ))
+            .col(timestamp_tz(DeviceAuthorization::ExpiresAt))
+            .col(timestamp_tz(DeviceAuthorization::CreatedAt).default(Expr::current_timestamp()))
+            .foreign_key(
+                ForeignKey::create()
+                    .name("fk_device_authorization_user")
+                    .from(DeviceAuthorization::Table, DeviceAuthorization::UserId)
+                    .to(User::Table, User::Id)
+                    .on_delete(ForeignKeyAction::Cascade),
+            )
+            .to_owned();
+        manager.create_table(table).await?;
+        let table = Table::create()
+            .table(ApiToken::Table)
+            .if_not_exists()
+            .col(
+                uuid(ApiToken::Id)
+                    .primary_key()
+                    .default(Expr::cust("uuid_generate_v1()")),
+            )
+            .col(uuid(ApiToken::UserId))
+            .col(text_uniq(ApiToken::TokenHash))
+            .col(text(ApiToken::Name))
+            .col(timestamp_with_time_zone_null(ApiToken::ExpiresAt))
+            .col(timestamp_with_time_zone_null(ApiToken::LastUsedAt))
+            .col(timestamp_tz(ApiToken::CreatedAt).default(Expr::current_timestamp()))
+            .foreign_key(
+                ForeignKey::create()
+                    .name("fk_api_token_user")
+                    .from(ApiToken::Table, ApiToken::UserId)
+                    .to(User::Table, User::Id)
+                    .on_delete(ForeignKeyAction::Cascade),
+            )
+            .to_owned();
+        manager.create_table(table).await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(ApiToken::Table).to_owned())
+            .await?;
+        manager
+            .drop_table(Table::drop().table(DeviceAuthorization::Table).to_owned())
+            .await
+    }
+}
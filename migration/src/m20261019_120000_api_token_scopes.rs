@@ -0,0 +1,30 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[derive(DeriveIden)]
+enum ApiToken {
+    Table,
+    Scopes,
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // Device logins issued so far could do everything
+        let table = Table::alter()
+            .table(ApiToken::Table)
+            .add_column(text(ApiToken::Scopes).default("albums:read albums:write"))
+            .to_owned();
+        manager.alter_table(table).await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let table = Table::alter()
+            .table(ApiToken::Table)
+            .drop_column(ApiToken::Scopes)
+            .to_owned();
+        manager.alter_table(table).await
+    }
+}
@@ -0,0 +1,32 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[derive(DeriveIden)]
+enum Session {
+    Table,
+    Ip,
+    UserAgent,
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let table = Table::alter()
+            .table(Session::Table)
+            .add_column(text_null(Session::Ip))
+            .add_column(text_null(Session::UserAgent))
+            .to_owned();
+        manager.alter_table(table).await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let table = Table::alter()
+            .table(Session::Table)
+            .drop_column(Session::Ip)
+            .drop_column(Session::UserAgent)
+            .to_owned();
+        manager.alter_table(table).await
+    }
+}
@@ -0,0 +1,32 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+use crate::m20250722_152740_create_users::User;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[derive(DeriveIden)]
+enum UserEmail {
+    EmailVerified,
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // Nobody checked so far, existing emails count as unverified until the
+        // provider vouches for them at the next login
+        let table = Table::alter()
+            .table(User::Table)
+            .add_column(boolean(UserEmail::EmailVerified).default(false))
+            .to_owned();
+        manager.alter_table(table).await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let table = Table::alter()
+            .table(User::Table)
+            .drop_column(UserEmail::EmailVerified)
+            .to_owned();
+        manager.alter_table(table).await
+    }
+}
@@ -0,0 +1,475 @@
+//! Small in-process OIDC provider for offline development and tests.
+//!
+//! It signs tokens with a key committed to the repository and lets anybody
+//! pick any configured user, never expose it.
+
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    io,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    Form, Json, Router,
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode, header},
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::{get, post},
+};
+use base64ct::{Base64, Base64UrlUnpadded, Encoding};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation, decode, encode};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value, json};
+use sha2::{Digest, Sha256};
+use tokio::net::TcpListener;
+use uuid::Uuid;
+
+const SIGNING_KEY: &str = include_str!("signing_key.pem");
+const KEY_ID: &str = "mock-oidc";
+/// Public part of [`SIGNING_KEY`]
+const KEY_X: &str = "LcqxAMeXQ2uir5WwZkWrY64xNpdKTgPG43r4VE9YPkQ";
+const KEY_Y: &str = "x7BYx6w3Bn1iNZ60-lBIY9vjXt-K9d-Xh1_NgUt5Duw";
+const TOKEN_LIFETIME_SECS: i64 = 300;
+const BACKCHANNEL_LOGOUT_EVENT: &str = "http://schemas.openid.net/event/backchannel-logout";
+
+/// A user offered on the authorize page
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MockUser {
+    pub sub: String,
+    pub name: String,
+    pub email: String,
+    #[serde(default = "verified")]
+    pub email_verified: bool,
+    /// Extra claims added to the tokens and userinfo, e.g. `realm_access`
+    #[serde(flatten)]
+    pub claims: Map<String, Value>,
+}
+
+fn verified() -> bool {
+    true
+}
+
+impl MockUser {
+    #[must_use]
+    pub fn new(sub: &str, name: &str, roles: &[&str]) -> Self {
+        let mut claims = Map::new();
+        claims.insert("realm_access".to_string(), json!({ "roles": roles }));
+        Self {
+            sub: sub.to_string(),
+            name: name.to_string(),
+            email: format!("{sub}@example.com"),
+            email_verified: true,
+            claims,
+        }
+    }
+
+    fn userinfo(&self) -> Map<String, Value> {
+        let mut info = self.claims.clone();
+        info.insert("sub".to_string(), json!(self.sub));
+        info.insert("name".to_string(), json!(self.name));
+        info.insert("email".to_string(), json!(self.email));
+        info.insert("email_verified".to_string(), json!(self.email_verified));
+        info
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MockOidcConfig {
+    /// Base URL the provider is reachable at, e.g. `http://localhost:8090`
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub users: Vec<MockUser>,
+}
+
+impl MockOidcConfig {
+    /// An admin, an editor and a reader, using the Keycloak `realm_access.roles` claim
+    #[must_use]
+    pub fn default_users() -> Vec<MockUser> {
+        vec![
+            MockUser::new("alice", "Alice Admin", &["transversal-admin"]),
+            MockUser::new("bob", "Bob Editor", &["transversal-user"]),
+            MockUser::new("carol", "Carol Reader", &[]),
+        ]
+    }
+
+    /// A back-channel `logout_token` for the client, `claims` name the
+    /// sessions to end with `sub` or `sid` and override any other claim
+    pub fn logout_token(
+        &self,
+        claims: &Map<String, Value>,
+    ) -> jsonwebtoken::errors::Result<String> {
+        let now = chrono::Utc::now().timestamp();
+        let mut token = Map::new();
+        for (key, value) in [
+            ("iss", json!(self.issuer)),
+            ("aud", json!(self.client_id)),
+            ("iat", json!(now)),
+            ("exp", json!(now + TOKEN_LIFETIME_SECS)),
+            ("jti", json!(Uuid::new_v4())),
+            ("events", json!({ BACKCHANNEL_LOGOUT_EVENT: {} })),
+        ] {
+            token.insert(key.to_string(), value);
+        }
+        token.extend(claims.clone());
+        sign(&token)
+    }
+}
+
+/// What a code or refresh token was issued for
+#[derive(Clone, Debug)]
+struct Grant {
+    sub: String,
+    sid: String,
+    nonce: Option<String>,
+    redirect_uri: String,
+    code_challenge: Option<String>,
+    auth_time: i64,
+}
+
+#[derive(Clone)]
+pub struct MockOidc {
+    config: Arc<MockOidcConfig>,
+    codes: Arc<Mutex<HashMap<String, Grant>>>,
+    refresh_tokens: Arc<Mutex<HashMap<String, Grant>>>,
+}
+
+impl MockOidc {
+    #[must_use]
+    pub fn new(config: MockOidcConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+            codes: Arc::default(),
+            refresh_tokens: Arc::default(),
+        }
+    }
+
+    pub fn router(self) -> Router {
+        Router::new()
+            .route("/.well-known/openid-configuration", get(discovery))
+            .route("/authorize", get(authorize))
+            .route("/authorize/approve", get(approve))
+            .route("/token", post(token))
+            .route("/userinfo", get(userinfo))
+            .route("/introspect", post(introspect))
+            .route("/jwks", get(jwks))
+            .route("/logout", get(logout))
+            .with_state(self)
+    }
+
+    pub async fn serve(self, listener: TcpListener) -> io::Result<()> {
+        log::info!("Mock OIDC provider listening at {}", self.config.issuer);
+        axum::serve(listener, self.router()).await
+    }
+
+    /// Binds an ephemeral local port and serves in the background, for tests
+    pub async fn spawn(
+        client_id: &str,
+        client_secret: &str,
+        users: Vec<MockUser>,
+    ) -> io::Result<MockOidcConfig> {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+        let config = MockOidcConfig {
+            issuer: format!("http://{}", listener.local_addr()?),
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            users,
+        };
+        tokio::spawn(Self::new(config.clone()).serve(listener));
+        Ok(config)
+    }
+
+    fn user(&self, sub: &str) -> Option<&MockUser> {
+        self.config.users.iter().find(|user| user.sub == sub)
+    }
+
+    fn issue_tokens(&self, grant: Grant) -> Response {
+        let Some(user) = self.user(&grant.sub) else {
+            return oauth_error("invalid_grant");
+        };
+        let now = chrono::Utc::now().timestamp();
+        let mut claims = user.claims.clone();
+        for (key, value) in [
+            ("iss", json!(self.config.issuer)),
+            ("sub", json!(user.sub)),
+            ("aud", json!(self.config.client_id)),
+            ("iat", json!(now)),
+            ("exp", json!(now + TOKEN_LIFETIME_SECS)),
+            ("sid", json!(grant.sid)),
+        ] {
+            claims.insert(key.to_string(), value);
+        }
+        let mut access_claims = claims.clone();
+        access_claims.insert("typ".to_string(), json!("Bearer"));
+        let mut id_claims = claims;
+        id_claims.extend(user.userinfo());
+        id_claims.insert("typ".to_string(), json!("ID"));
+        id_claims.insert("auth_time".to_string(), json!(grant.auth_time));
+        if let Some(nonce) = &grant.nonce {
+            id_claims.insert("nonce".to_string(), json!(nonce));
+        }
+        let (access_token, id_token) = match (sign(&access_claims), sign(&id_claims)) {
+            (Ok(access_token), Ok(id_token)) => (access_token, id_token),
+            (Err(err), _) | (_, Err(err)) => {
+                log::error!("Mock OIDC provider failed to sign a token: {err}");
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        };
+        let refresh_token = Uuid::new_v4().to_string();
+        let body = json!({
+            "access_token": access_token,
+            "id_token": id_token,
+            "refresh_token": refresh_token,
+            "token_type": "Bearer",
+            "expires_in": TOKEN_LIFETIME_SECS,
+        });
+        lock(&self.refresh_tokens).insert(refresh_token, grant);
+        Json(body).into_response()
+    }
+
+    /// Claims of an access token this provider issued
+    fn verify(&self, token: &str) -> Option<Map<String, Value>> {
+        let key = DecodingKey::from_ec_components(KEY_X, KEY_Y).ok()?;
+        let mut validation = Validation::new(Algorithm::ES256);
+        validation.set_issuer(&[&self.config.issuer]);
+        validation.set_audience(&[&self.config.client_id]);
+        decode::<Map<String, Value>>(token, &key, &validation)
+            .ok()
+            .map(|data| data.claims)
+            .filter(|claims| claims.get("typ") == Some(&json!("Bearer")))
+    }
+
+    /// Accepts `client_secret_basic` and `client_secret_post`
+    fn client_authenticated(&self, headers: &HeaderMap, form: &HashMap<String, String>) -> bool {
+        let basic = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Basic "))
+            .and_then(|value| Base64::decode_vec(value).ok())
+            .and_then(|value| String::from_utf8(value).ok());
+        let (id, secret) = match &basic {
+            Some(basic) => basic.split_once(':').unwrap_or((basic, "")),
+            None => (
+                form.get("client_id").map_or("", String::as_str),
+                form.get("client_secret").map_or("", String::as_str),
+            ),
+        };
+        id == self.config.client_id && secret == self.config.client_secret
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+fn oauth_error(error: &str) -> Response {
+    (StatusCode::BAD_REQUEST, Json(json!({ "error": error }))).into_response()
+}
+
+fn sign(claims: &Map<String, Value>) -> jsonwebtoken::errors::Result<String> {
+    let key = EncodingKey::from_ec_pem(SIGNING_KEY.as_bytes())?;
+    let mut header = Header::new(Algorithm::ES256);
+    header.kid = Some(KEY_ID.to_string());
+    encode(&header, claims, &key)
+}
+
+async fn discovery(State(mock): State<MockOidc>) -> Json<Value> {
+    let issuer = &mock.config.issuer;
+    Json(json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{issuer}/authorize"),
+        "token_endpoint": format!("{issuer}/token"),
+        "userinfo_endpoint": format!("{issuer}/userinfo"),
+        "introspection_endpoint": format!("{issuer}/introspect"),
+        "end_session_endpoint": format!("{issuer}/logout"),
+        "jwks_uri": format!("{issuer}/jwks"),
+        "response_types_supported": ["code"],
+        "grant_types_supported": ["authorization_code", "refresh_token"],
+        "code_challenge_methods_supported": ["S256"],
+        "id_token_signing_alg_values_supported": ["ES256"],
+        "token_endpoint_auth_methods_supported": ["client_secret_basic", "client_secret_post"],
+    }))
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct AuthorizeQuery {
+    client_id: String,
+    redirect_uri: String,
+    state: Option<String>,
+    nonce: Option<String>,
+    code_challenge: Option<String>,
+    login_hint: Option<String>,
+}
+
+/// The pick-a-user page, skipped when `login_hint` names a user
+async fn authorize(State(mock): State<MockOidc>, Query(query): Query<AuthorizeQuery>) -> Response {
+    if query.client_id != mock.config.client_id {
+        return oauth_error("unauthorized_client");
+    }
+    if let Some(user) = query.login_hint.as_deref().and_then(|sub| mock.user(sub)) {
+        return issue_code(&mock, user.sub.clone(), query);
+    }
+    let mut links = String::new();
+    for user in &mock.config.users {
+        let params = AuthorizeQuery {
+            login_hint: Some(user.sub.clone()),
+            ..query.clone()
+        };
+        let params = html_escape(&serde_urlencoded::to_string(&params).unwrap_or_default());
+        let _ = write!(
+            links,
+            "<li><a href=\"/authorize/approve?{params}\">{} &lt;{}&gt;</a></li>",
+            html_escape(&user.name),
+            html_escape(&user.email)
+        );
+    }
+    Html(format!(
+        "<!DOCTYPE html><html><body><h1>Mock sign in</h1><p>Pick a test user</p><ul>{links}</ul></body></html>"
+    ))
+    .into_response()
+}
+
+async fn approve(State(mock): State<MockOidc>, Query(query): Query<AuthorizeQuery>) -> Response {
+    if query.client_id != mock.config.client_id {
+        return oauth_error("unauthorized_client");
+    }
+    match query.login_hint.as_deref().and_then(|sub| mock.user(sub)) {
+        Some(user) => issue_code(&mock, user.sub.clone(), query),
+        None => oauth_error("access_denied"),
+    }
+}
+
+fn issue_code(mock: &MockOidc, sub: String, query: AuthorizeQuery) -> Response {
+    let code = Uuid::new_v4().to_string();
+    let grant = Grant {
+        sub,
+        sid: Uuid::new_v4().to_string(),
+        nonce: query.nonce,
+        redirect_uri: query.redirect_uri.clone(),
+        code_challenge: query.code_challenge,
+        auth_time: chrono::Utc::now().timestamp(),
+    };
+    lock(&mock.codes).insert(code.clone(), grant);
+    let mut params = vec![("code", code)];
+    params.extend(query.state.map(|state| ("state", state)));
+    let params = serde_urlencoded::to_string(params).unwrap_or_default();
+    let separator = if query.redirect_uri.contains('?') {
+        '&'
+    } else {
+        '?'
+    };
+    Redirect::to(&format!("{}{separator}{params}", query.redirect_uri)).into_response()
+}
+
+async fn token(
+    State(mock): State<MockOidc>,
+    headers: HeaderMap,
+    Form(form): Form<HashMap<String, String>>,
+) -> Response {
+    if !mock.client_authenticated(&headers, &form) {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({ "error": "invalid_client" })),
+        )
+            .into_response();
+    }
+    let field = |key: &str| form.get(key).map(String::as_str);
+    let grant = match field("grant_type") {
+        Some("authorization_code") => {
+            let code = field("code").unwrap_or_default();
+            let Some(grant) = lock(&mock.codes).remove(code) else {
+                return oauth_error("invalid_grant");
+            };
+            let verifier = field("code_verifier").unwrap_or_default();
+            let challenge = Base64UrlUnpadded::encode_string(&Sha256::digest(verifier));
+            if field("redirect_uri") != Some(grant.redirect_uri.as_str())
+                || grant
+                    .code_challenge
+                    .as_ref()
+                    .is_some_and(|expected| *expected != challenge)
+            {
+                return oauth_error("invalid_grant");
+            }
+            grant
+        }
+        Some("refresh_token") => {
+            let token = field("refresh_token").unwrap_or_default();
+            match lock(&mock.refresh_tokens).remove(token) {
+                Some(grant) => Grant {
+                    nonce: None,
+                    ..grant
+                },
+                None => return oauth_error("invalid_grant"),
+            }
+        }
+        _ => return oauth_error("unsupported_grant_type"),
+    };
+    mock.issue_tokens(grant)
+}
+
+async fn userinfo(State(mock): State<MockOidc>, headers: HeaderMap) -> Response {
+    let user = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .and_then(|token| mock.verify(token))
+        .and_then(|claims| mock.user(claims.get("sub")?.as_str()?).cloned());
+    match user {
+        Some(user) => Json(user.userinfo()).into_response(),
+        None => StatusCode::UNAUTHORIZED.into_response(),
+    }
+}
+
+async fn introspect(
+    State(mock): State<MockOidc>,
+    headers: HeaderMap,
+    Form(form): Form<HashMap<String, String>>,
+) -> Response {
+    if !mock.client_authenticated(&headers, &form) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    match form.get("token").and_then(|token| mock.verify(token)) {
+        Some(mut claims) => {
+            claims.insert("active".to_string(), json!(true));
+            Json(claims).into_response()
+        }
+        None => Json(json!({ "active": false })).into_response(),
+    }
+}
+
+async fn jwks() -> Json<Value> {
+    Json(json!({
+        "keys": [{
+            "kty": "EC",
+            "crv": "P-256",
+            "x": KEY_X,
+            "y": KEY_Y,
+            "kid": KEY_ID,
+            "use": "sig",
+            "alg": "ES256",
+        }]
+    }))
+}
+
+#[derive(Deserialize)]
+struct LogoutQuery {
+    post_logout_redirect_uri: Option<String>,
+}
+
+async fn logout(Query(query): Query<LogoutQuery>) -> Response {
+    match query.post_logout_redirect_uri {
+        Some(uri) => Redirect::to(&uri).into_response(),
+        None => Html("Signed out").into_response(),
+    }
+}
+
+fn html_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,211 @@
+use std::{
+    fmt,
+    io::{Error as IOError, ErrorKind},
+    str::FromStr,
+};
+
+use jsonwebtoken::{Algorithm, EncodingKey, Header, encode};
+use serde_json::json;
+use uuid::Uuid;
+
+/// Lifetime of a `private_key_jwt` client assertion
+const ASSERTION_LIFETIME_SECS: i64 = 60;
+const JWT_BEARER_ASSERTION: &str = "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+
+/// Token endpoint client authentication methods, as named in the discovery
+/// `token_endpoint_auth_methods_supported`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClientAuthMethod {
+    ClientSecretBasic,
+    ClientSecretPost,
+    PrivateKeyJwt,
+    /// Public client, PKCE only
+    None,
+}
+
+impl ClientAuthMethod {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::ClientSecretBasic => "client_secret_basic",
+            Self::ClientSecretPost => "client_secret_post",
+            Self::PrivateKeyJwt => "private_key_jwt",
+            Self::None => "none",
+        }
+    }
+}
+
+impl fmt::Display for ClientAuthMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ClientAuthMethod {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        [
+            Self::ClientSecretBasic,
+            Self::ClientSecretPost,
+            Self::PrivateKeyJwt,
+            Self::None,
+        ]
+        .into_iter()
+        .find(|method| method.as_str() == value.trim())
+        .ok_or_else(|| format!("Unknown client authentication method {value}"))
+    }
+}
+
+/// Key a client registered with instead of a secret
+#[derive(Clone)]
+pub struct ClientKey {
+    pub key: EncodingKey,
+    pub alg: Algorithm,
+    pub kid: Option<String>,
+}
+
+impl fmt::Debug for ClientKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientKey")
+            .field("alg", &self.alg)
+            .field("kid", &self.kid)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Secret and or key of a client, the method is picked per request from what
+/// the provider supports unless forced.
+#[derive(Clone, Debug, Default)]
+pub struct ClientCredentials {
+    pub secret: Option<String>,
+    pub key: Option<ClientKey>,
+    pub method: Option<ClientAuthMethod>,
+}
+
+/// How to authenticate one request, HTTP Basic credentials and or form fields
+#[derive(Debug, Default)]
+pub struct ClientAuth {
+    pub basic: Option<(String, String)>,
+    pub form: Vec<(&'static str, String)>,
+}
+
+impl ClientCredentials {
+    /// Preferred method among the `supported` ones which the credentials allow,
+    /// providers advertising nothing default to `client_secret_basic`.
+    pub fn select(&self, supported: &[String]) -> Result<ClientAuthMethod, IOError> {
+        if let Some(method) = self.method {
+            return Ok(method);
+        }
+        let supports = |method: ClientAuthMethod| {
+            if supported.is_empty() {
+                return method == ClientAuthMethod::ClientSecretBasic;
+            }
+            supported.iter().any(|x| x == method.as_str())
+        };
+        let mut candidates = vec![];
+        if self.key.is_some() {
+            candidates.push(ClientAuthMethod::PrivateKeyJwt);
+        }
+        if self.secret.is_some() {
+            candidates.push(ClientAuthMethod::ClientSecretBasic);
+            candidates.push(ClientAuthMethod::ClientSecretPost);
+        }
+        if self.key.is_none() && self.secret.is_none() {
+            return Ok(ClientAuthMethod::None);
+        }
+        candidates
+            .into_iter()
+            .find(|x| supports(*x))
+            .ok_or_else(|| {
+                IOError::new(
+                    ErrorKind::Unsupported,
+                    format!("No supported client authentication method among {supported:?}"),
+                )
+            })
+    }
+
+    /// Authentication for a request to `audience`, the endpoint URL
+    pub fn authenticate(
+        &self,
+        client_id: &str,
+        supported: &[String],
+        audience: &str,
+    ) -> Result<ClientAuth, IOError> {
+        let missing =
+            |what: &str| IOError::new(ErrorKind::InvalidInput, format!("No client {what}"));
+        let mut auth = ClientAuth::default();
+        match self.select(supported)? {
+            ClientAuthMethod::ClientSecretBasic => {
+                let secret = self.secret.clone().ok_or_else(|| missing("secret"))?;
+                auth.basic = Some((client_id.to_string(), secret));
+            }
+            ClientAuthMethod::ClientSecretPost => {
+                let secret = self.secret.clone().ok_or_else(|| missing("secret"))?;
+                auth.form.push(("client_secret", secret));
+            }
+            ClientAuthMethod::PrivateKeyJwt => {
+                let key = self.key.as_ref().ok_or_else(|| missing("key"))?;
+                auth.form
+                    .push(("client_assertion_type", JWT_BEARER_ASSERTION.to_string()));
+                auth.form
+                    .push(("client_assertion", key.assertion(client_id, audience)?));
+            }
+            ClientAuthMethod::None => {}
+        }
+        Ok(auth)
+    }
+}
+
+impl ClientKey {
+    /// RFC 7523 client assertion
+    fn assertion(&self, client_id: &str, audience: &str) -> Result<String, IOError> {
+        let now = chrono::Utc::now().timestamp();
+        let claims = json!({
+            "iss": client_id,
+            "sub": client_id,
+            "aud": audience,
+            "jti": Uuid::new_v4().to_string(),
+            "iat": now,
+            "exp": now + ASSERTION_LIFETIME_SECS,
+        });
+        let mut header = Header::new(self.alg);
+        header.kid.clone_from(&self.kid);
+        encode(&header, &claims, &self.key).map_err(IOError::other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use googletest::prelude::*;
+    use rstest::rstest;
+
+    fn credentials(secret: bool, key: bool) -> ClientCredentials {
+        ClientCredentials {
+            secret: secret.then(|| "secret".to_string()),
+            key: key.then(|| ClientKey {
+                key: EncodingKey::from_secret(b"not a real key"),
+                alg: Algorithm::HS256,
+                kid: None,
+            }),
+            method: None,
+        }
+    }
+
+    #[rstest]
+    #[case(credentials(true, false), &[], Some(ClientAuthMethod::ClientSecretBasic))]
+    #[case(credentials(true, false), &["client_secret_post"], Some(ClientAuthMethod::ClientSecretPost))]
+    #[case(credentials(true, true), &["client_secret_basic", "private_key_jwt"], Some(ClientAuthMethod::PrivateKeyJwt))]
+    #[case(credentials(false, true), &["client_secret_basic"], None)]
+    #[case(credentials(false, false), &["client_secret_basic"], Some(ClientAuthMethod::None))]
+    #[gtest]
+    fn selects_a_supported_method(
+        #[case] credentials: ClientCredentials,
+        #[case] supported: &[&str],
+        #[case] expected: Option<ClientAuthMethod>,
+    ) {
+        let supported: Vec<String> = supported.iter().map(ToString::to_string).collect();
+        expect_eq!(credentials.select(&supported).ok(), expected);
+    }
+}
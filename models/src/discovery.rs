@@ -0,0 +1,78 @@
+use std::{sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use tokio::sync::RwLock;
+
+use crate::oauth::OpenIdConfiguration;
+
+/// First retry delay after a failed discovery, doubled on every failure
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Snapshot of a provider discovery, shown by the health check
+#[derive(Clone, Debug, Default)]
+pub struct DiscoveryStatus {
+    pub config: Option<Arc<OpenIdConfiguration>>,
+    pub last_success: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    /// Failures since the last success
+    pub failures: u32,
+}
+
+/// Shared handle on the discovery document of a provider, filled and
+/// refreshed in the background so the app starts without the provider.
+#[derive(Clone, Debug, Default)]
+pub struct Discovery {
+    inner: Arc<RwLock<DiscoveryStatus>>,
+}
+
+impl Discovery {
+    /// The last discovered configuration, `None` until discovery succeeds once
+    pub async fn config(&self) -> Option<Arc<OpenIdConfiguration>> {
+        self.inner.read().await.config.clone()
+    }
+
+    pub async fn status(&self) -> DiscoveryStatus {
+        self.inner.read().await.clone()
+    }
+
+    pub async fn succeeded(&self, config: OpenIdConfiguration) {
+        let mut status = self.inner.write().await;
+        status.config = Some(Arc::new(config));
+        status.last_success = Some(Utc::now());
+        status.last_error = None;
+        status.failures = 0;
+    }
+
+    /// Records a failure, a previously discovered configuration is kept.
+    /// Returns how long to wait before trying again.
+    pub async fn failed(&self, error: String) -> Duration {
+        let mut status = self.inner.write().await;
+        status.last_error = Some(error);
+        status.failures = status.failures.saturating_add(1);
+        backoff(status.failures)
+    }
+}
+
+fn backoff(failures: u32) -> Duration {
+    let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+    INITIAL_BACKOFF.saturating_mul(factor).min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use googletest::prelude::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(1, 1)]
+    #[case(2, 2)]
+    #[case(4, 8)]
+    #[case(7, 60)]
+    #[case(u32::MAX, 60)]
+    #[gtest]
+    fn backs_off_exponentially(#[case] failures: u32, #[case] secs: u64) {
+        expect_eq!(backoff(failures), Duration::from_secs(secs));
+    }
+}
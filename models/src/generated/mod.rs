@@ -3,5 +3,8 @@
 pub mod prelude;
 
 pub mod album;
+pub mod api_token;
+pub mod device_authorization;
+pub mod identity;
 pub mod session;
 pub mod user;
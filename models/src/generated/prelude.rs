@@ -1,5 +1,8 @@
 //! `SeaORM` Entity, @generated by sea-orm-codegen 1.1.14
 
 pub use super::album::Entity as Album;
+pub use super::api_token::Entity as ApiToken;
+pub use super::device_authorization::Entity as DeviceAuthorization;
+pub use super::identity::Entity as Identity;
 pub use super::session::Entity as Session;
 pub use super::user::Entity as User;
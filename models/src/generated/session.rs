@@ -17,9 +17,31 @@ pub struct Model {
     #[sea_orm(column_name = "_created_at")]
     #[serde(skip)]
     pub created_at: DateTimeWithTimeZone,
+    pub user_id: Option<Uuid>,
+    #[sea_orm(column_type = "Text", nullable)]
+    pub idp_session_id: Option<String>,
+    #[sea_orm(column_type = "Text", nullable)]
+    pub ip: Option<String>,
+    #[sea_orm(column_type = "Text", nullable)]
+    pub user_agent: Option<String>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
-pub enum Relation {}
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::user::Entity",
+        from = "Column::UserId",
+        to = "super::user::Column::Id",
+        on_update = "NoAction",
+        on_delete = "Cascade"
+    )]
+    User,
+}
+
+impl Related<super::user::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::User.def()
+    }
+}
 
 impl ActiveModelBehavior for ActiveModel {}
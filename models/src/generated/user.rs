@@ -9,8 +9,6 @@ pub struct Model {
     #[sea_orm(primary_key, auto_increment = false)]
     pub id: Uuid,
     #[sea_orm(column_type = "Text", unique)]
-    pub sid: String,
-    #[sea_orm(column_type = "Text", unique)]
     pub email: String,
     #[sea_orm(column_type = "Text")]
     pub name: String,
@@ -20,12 +18,34 @@ pub struct Model {
     #[sea_orm(column_name = "_updated_at")]
     #[serde(skip)]
     pub updated_at: DateTimeWithTimeZone,
+    #[sea_orm(column_type = "Text", nullable)]
+    pub given_name: Option<String>,
+    #[sea_orm(column_type = "Text", nullable)]
+    pub family_name: Option<String>,
+    #[sea_orm(column_type = "Text", nullable)]
+    pub preferred_username: Option<String>,
+    #[sea_orm(column_type = "Text", nullable)]
+    pub picture: Option<String>,
+    #[sea_orm(column_type = "Text", nullable)]
+    pub locale: Option<String>,
+    #[serde(default)]
+    pub role: crate::role::Role,
+    #[serde(default)]
+    pub email_verified: bool,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
 pub enum Relation {
     #[sea_orm(has_many = "super::album::Entity")]
     Album,
+    #[sea_orm(has_many = "super::api_token::Entity")]
+    ApiToken,
+    #[sea_orm(has_many = "super::device_authorization::Entity")]
+    DeviceAuthorization,
+    #[sea_orm(has_many = "super::identity::Entity")]
+    Identity,
+    #[sea_orm(has_many = "super::session::Entity")]
+    Session,
 }
 
 impl Related<super::album::Entity> for Entity {
@@ -34,4 +54,28 @@ impl Related<super::album::Entity> for Entity {
     }
 }
 
+impl Related<super::api_token::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::ApiToken.def()
+    }
+}
+
+impl Related<super::device_authorization::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::DeviceAuthorization.def()
+    }
+}
+
+impl Related<super::identity::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Identity.def()
+    }
+}
+
+impl Related<super::session::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Session.def()
+    }
+}
+
 impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,104 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use jsonwebtoken::jwk::{Jwk, JwkSet, PublicKeyUse};
+use tokio::sync::RwLock;
+
+/// Minimum time between two remote fetches triggered by unknown key ids
+const MIN_REFETCH_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+struct CachedKeys {
+    keys: JwkSet,
+    fetched_at: Option<Instant>,
+}
+
+/// Process wide cache of the identity provider signing keys.
+///
+/// Keys are looked up by `kid`, a miss means the provider probably rotated its
+/// keys and the caller should re-fetch the set, see [`JwksCache::should_refetch`].
+#[derive(Clone, Debug)]
+pub struct JwksCache {
+    inner: Arc<RwLock<CachedKeys>>,
+}
+
+impl Default for JwksCache {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(CachedKeys {
+                keys: JwkSet { keys: vec![] },
+                fetched_at: None,
+            })),
+        }
+    }
+}
+
+impl JwksCache {
+    /// Finds a signature key by its id, or the first signature key when the
+    /// token header carries no `kid`.
+    pub async fn find(&self, kid: Option<&str>) -> Option<Jwk> {
+        let cached = self.inner.read().await;
+        let mut sig_keys = cached.keys.keys.iter().filter(|&k| is_sig_key(k));
+        match kid {
+            Some(kid) => sig_keys.find(|k| k.common.key_id.as_deref() == Some(kid)),
+            None => sig_keys.next(),
+        }
+        .cloned()
+    }
+
+    /// Whether enough time passed since the last fetch to hit the provider again
+    pub async fn should_refetch(&self) -> bool {
+        self.inner
+            .read()
+            .await
+            .fetched_at
+            .is_none_or(|at| at.elapsed() >= MIN_REFETCH_INTERVAL)
+    }
+
+    pub async fn replace(&self, keys: JwkSet) {
+        let mut cached = self.inner.write().await;
+        cached.keys = keys;
+        cached.fetched_at = Some(Instant::now());
+    }
+}
+
+/// Keys without an explicit `use` are accepted, Keycloak and others publish
+/// encryption keys in the same set so those must be skipped.
+fn is_sig_key(key: &Jwk) -> bool {
+    key.common
+        .public_key_use
+        .as_ref()
+        .is_none_or(|k| *k == PublicKeyUse::Signature)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use googletest::prelude::*;
+
+    fn key_set() -> JwkSet {
+        serde_json::from_value(serde_json::json!({
+            "keys": [
+                { "kid": "enc", "use": "enc", "kty": "RSA", "alg": "RSA-OAEP", "n": "AQAB", "e": "AQAB" },
+                { "kid": "sig", "use": "sig", "kty": "RSA", "alg": "RS256", "n": "AQAB", "e": "AQAB" }
+            ]
+        }))
+        .unwrap()
+    }
+
+    #[gtest]
+    #[tokio::test]
+    async fn finds_signature_keys_only() {
+        let cache = JwksCache::default();
+        expect_true!(cache.should_refetch().await);
+        cache.replace(key_set()).await;
+
+        let kid = |k: Option<Jwk>| k.and_then(|k| k.common.key_id);
+        expect_that!(kid(cache.find(Some("sig")).await), some(eq("sig")));
+        expect_that!(kid(cache.find(None).await), some(eq("sig")));
+        expect_that!(cache.find(Some("enc")).await, none());
+        expect_false!(cache.should_refetch().await);
+    }
+}
@@ -3,11 +3,19 @@ pub use sea_orm::entity::prelude::*;
 pub use sea_orm::{ActiveValue, DatabaseConnection, Value};
 use std::io::{self, Error as IOError};
 
+pub mod client_auth;
+pub mod discovery;
 pub mod generated;
+pub mod jwks;
 pub mod oauth;
+pub mod provider;
 pub mod repositories;
+pub mod role;
+pub mod scope;
 pub mod session;
 pub mod state;
+#[cfg(test)]
+mod test_db;
 pub mod user_auth;
 
 pub async fn get_database(db_url: &str) -> Result<DatabaseConnection, IOError> {
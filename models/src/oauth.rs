@@ -1,16 +1,117 @@
 use base64ct::{Base64Url, Encoding};
+use chrono::{DateTime, TimeDelta, Utc};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use uuid::Uuid;
 
+use crate::session::SessionValue;
+
+/// Provider chooser, also offers the keep me signed in option
 pub const OAUTH_LOGIN_ENDPOINT: &str = "/auth/login";
 pub const OAUTH_LOGOUT_ENDPOINT: &str = "/auth/logout";
-pub const OAUTH_CALLBACK_ENDPOINT: &str = "/auth/callback";
+pub const OAUTH_PROVIDER_LOGIN_ENDPOINT: &str = "/auth/{provider}/login";
+/// Links the provider login to the signed in user
+pub const OAUTH_LINK_ENDPOINT: &str = "/auth/{provider}/link";
+pub const OAUTH_CALLBACK_ENDPOINT: &str = "/auth/{provider}/callback";
+pub const OAUTH_BACKCHANNEL_LOGOUT_ENDPOINT: &str = "/auth/{provider}/backchannel-logout";
+/// Confirms the action interrupted by a step-up re-authentication
+pub const OAUTH_RESUME_ENDPOINT: &str = "/auth/resume";
+/// Event a back-channel `logout_token` must carry
+pub const BACKCHANNEL_LOGOUT_EVENT: &str = "http://schemas.openid.net/event/backchannel-logout";
+/// Clock skew tolerated when checking `auth_time` against `max_age`
+const AUTH_TIME_LEEWAY_SECS: i64 = 60;
 
+/// Claims read from a verified ID token, `iss`, `aud` and `exp` are checked
+/// while decoding and therefore not kept around.
 #[derive(serde::Deserialize, Clone, Debug)]
 pub struct Claims {
     pub sub: String,
     pub email: Option<String>,
+    pub nonce: Option<String>,
+    pub sid: Option<String>,
+    pub auth_time: Option<i64>,
+    /// Remaining claims, looked up by the provider role mapping
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Claims {
+    /// Whether the user authenticated at the provider less than `max_age`
+    /// seconds ago, unknown when the provider sent no `auth_time`.
+    #[must_use]
+    pub fn authenticated_within(&self, max_age: u64) -> bool {
+        self.auth_time
+            .is_some_and(|auth_time| auth_time_within(auth_time, max_age))
+    }
+}
+
+/// Whether `auth_time`, in seconds since the epoch, is at most `max_age`
+/// seconds old
+#[must_use]
+pub fn auth_time_within(auth_time: i64, max_age: u64) -> bool {
+    let max_age = i64::try_from(max_age).unwrap_or(i64::MAX);
+    Utc::now().timestamp() - auth_time <= max_age.saturating_add(AUTH_TIME_LEEWAY_SECS)
+}
+
+/// When the user last authenticated at the provider, seconds since the epoch
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct AuthTime(pub i64);
+
+impl SessionValue for AuthTime {
+    const KEY: &'static str = "auth.time";
+}
+
+/// Request held back until the user re-authenticated, confirmed by the same
+/// user at [`OAUTH_RESUME_ENDPOINT`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingAction {
+    pub user: Uuid,
+    pub method: String,
+    pub path: String,
+}
+
+impl SessionValue for PendingAction {
+    const KEY: &'static str = "auth.pending_action";
+}
+
+/// Claims of a back-channel `logout_token`, at least one of `sid` or `sub`
+/// identifies the sessions to terminate.
+#[derive(Deserialize, Clone, Debug)]
+pub struct LogoutTokenClaims {
+    pub sub: Option<String>,
+    pub sid: Option<String>,
+    pub nonce: Option<String>,
+    #[serde(default)]
+    pub events: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Claims of a JWT access token presented to the API, `iss`, `aud` and `exp`
+/// are checked while decoding.
+#[derive(Deserialize, Clone, Debug)]
+pub struct AccessTokenClaims {
+    pub sub: String,
+    /// Keycloak token type, `ID` tokens share the signing keys
+    pub typ: Option<String>,
+}
+
+/// RFC 7662 token introspection request
+#[derive(Debug, Clone, Serialize)]
+pub struct IntrospectionPayload {
+    pub token: String,
+    pub token_type_hint: String,
+}
+
+/// RFC 7662 token introspection response, only `active` is mandatory
+#[derive(Debug, Clone, Deserialize)]
+pub struct IntrospectionResponse {
+    pub active: bool,
+    pub sub: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BackchannelLogoutForm {
+    pub logout_token: String,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -22,8 +123,19 @@ pub struct UserInfo {
     pub preferred_username: Option<String>,
     pub picture: Option<String>,
     pub email: String,
+    pub email_verified: Option<bool>,
     pub birthdate: Option<String>,
     pub locale: Option<String>,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl UserInfo {
+    /// Whether the provider vouches for `email`, absent means it does not
+    #[must_use]
+    pub fn has_verified_email(&self) -> bool {
+        self.email_verified == Some(true)
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -33,13 +145,69 @@ pub struct OpenIdConfiguration {
     pub end_session_endpoint: String,
     pub token_endpoint: String,
     pub userinfo_endpoint: String,
-    // pub issuer: String,
+    pub issuer: String,
+    pub introspection_endpoint: Option<String>,
+    #[serde(default)]
+    pub token_endpoint_auth_methods_supported: Vec<String>,
     // pub backchannel_logout_supported: bool,
     // pub frontchannel_logout_supported: bool,
     // pub grant_types_supported: Vec<String>,
     // pub response_modes_supported: Vec<String>,
     // pub response_types_supported: Vec<String>,
-    // pub token_endpoint_auth_methods_supported: Vec<String>,
+}
+
+/// Query of the login routes, where to go once signed in and per login
+/// overrides of the provider [`AuthorizationOptions`]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct LoginQuery {
+    pub return_to: Option<String>,
+    pub prompt: Option<String>,
+    pub login_hint: Option<String>,
+    pub acr_values: Option<String>,
+    pub max_age: Option<u64>,
+    /// The "keep me signed in" checkbox, see [`crate::session::KeepSignedIn`]
+    pub keep_signed_in: Option<bool>,
+}
+
+/// Authorization request settings of a provider
+#[derive(Debug, Clone)]
+pub struct AuthorizationOptions {
+    pub scope: String,
+    pub audience: Option<String>,
+    pub response_mode: Option<String>,
+    pub prompt: Option<String>,
+    pub login_hint: Option<String>,
+    pub acr_values: Option<String>,
+    pub max_age: Option<u64>,
+}
+
+impl Default for AuthorizationOptions {
+    fn default() -> Self {
+        Self {
+            scope: "offline_access openid email profile".to_string(),
+            audience: None,
+            response_mode: None,
+            prompt: None,
+            login_hint: None,
+            acr_values: None,
+            max_age: None,
+        }
+    }
+}
+
+impl AuthorizationOptions {
+    /// Applies the overrides of a single login, e.g. `prompt=login` to force
+    /// re-authentication or the `login_hint` of an invite link
+    #[must_use]
+    pub fn overridden(&self, query: &LoginQuery) -> Self {
+        Self {
+            prompt: query.prompt.clone().or_else(|| self.prompt.clone()),
+            login_hint: query.login_hint.clone().or_else(|| self.login_hint.clone()),
+            acr_values: query.acr_values.clone().or_else(|| self.acr_values.clone()),
+            max_age: query.max_age.or(self.max_age),
+            ..self.clone()
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -53,7 +221,6 @@ pub struct TokenExchangePayload {
     pub code: String,
     pub grant_type: String,
     pub client_id: String,
-    pub client_secret: String,
     pub redirect_uri: String,
     pub code_verifier: String,
 }
@@ -63,7 +230,6 @@ pub struct RefreshPayload {
     pub grant_type: String,
     pub refresh_token: String,
     pub client_id: String,
-    pub client_secret: String,
     pub redirect_uri: String,
 }
 
@@ -71,23 +237,76 @@ pub struct RefreshPayload {
 pub struct TokenResponse {
     pub access_token: String,
     pub refresh_token: Option<String>,
-    // pub expires_in: i64,
+    pub id_token: Option<String>,
+    pub expires_in: Option<i64>,
     // pub refresh_expires_in: Option<i64>,
     // pub token_type: String,
-    // pub id_token: Option<String>,
     // pub session_state: String,
     // pub scope: String,
 }
 
+/// Token set kept in the session so the access token can be refreshed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenSet {
+    /// Name of the provider which issued the tokens
+    pub provider: String,
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    pub id_token: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl SessionValue for TokenSet {
+    const KEY: &'static str = "auth.tokens";
+}
+
+impl TokenSet {
+    #[must_use]
+    pub fn new(provider: String, resp: TokenResponse) -> Self {
+        TokenSet {
+            provider,
+            expires_at: resp
+                .expires_in
+                .map(|secs| Utc::now() + TimeDelta::seconds(secs)),
+            access_token: resp.access_token,
+            refresh_token: resp.refresh_token,
+            id_token: resp.id_token,
+        }
+    }
+
+    /// Whether the access token expires in less than `margin`, tokens without
+    /// a known lifetime never expire.
+    #[must_use]
+    pub fn expires_within(&self, margin: TimeDelta) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at - margin <= Utc::now())
+    }
+
+    /// Applies a refresh response, providers are free not to rotate the
+    /// refresh token or to omit the ID token so the previous ones are kept.
+    #[must_use]
+    pub fn refreshed(self, resp: TokenResponse) -> Self {
+        let mut tokens = TokenSet::new(self.provider, resp);
+        tokens.refresh_token = tokens.refresh_token.or(self.refresh_token);
+        tokens.id_token = tokens.id_token.or(self.id_token);
+        tokens
+    }
+}
+
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct AuthorizationParams {
     pub client_id: String,
     pub redirect_uri: String,
     pub state: String,
-    pub audience: String,
-    pub response_mode: String,
+    pub audience: Option<String>,
+    pub response_mode: Option<String>,
     pub response_type: String,
     pub scope: String,
+    pub nonce: String,
+    pub prompt: Option<String>,
+    pub login_hint: Option<String>,
+    pub acr_values: Option<String>,
+    pub max_age: Option<u64>,
     #[serde(skip)]
     pub code_verifier: String,
     pub code_challenge: String,
@@ -96,15 +315,42 @@ pub struct AuthorizationParams {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LoginAttempt {
+    /// Provider the browser was sent to, the callback must come from the same
+    pub provider: String,
     pub pkce: String,
     pub csrf: String,
+    pub nonce: String,
+    /// Set when a signed in user links another login instead of signing in
+    #[serde(default)]
+    pub link_user: Option<Uuid>,
+    /// Local path to restore after the callback, already validated
+    #[serde(default)]
+    pub return_to: Option<String>,
+    /// Requested `max_age`, the ID token `auth_time` must honour it
+    #[serde(default)]
+    pub max_age: Option<u64>,
+    #[serde(default)]
+    pub keep_signed_in: bool,
 }
 
-impl From<AuthorizationParams> for LoginAttempt {
-    fn from(params: AuthorizationParams) -> Self {
+/// Bump the version on changes older attempts can not be read as, they are
+/// then dropped and the user simply signs in again
+impl SessionValue for LoginAttempt {
+    const KEY: &'static str = "auth.login_attempt";
+}
+
+impl LoginAttempt {
+    #[must_use]
+    pub fn new(provider: String, params: AuthorizationParams) -> Self {
         LoginAttempt {
+            provider,
             pkce: params.code_verifier,
             csrf: params.state,
+            nonce: params.nonce,
+            link_user: None,
+            return_to: None,
+            max_age: params.max_age,
+            keep_signed_in: false,
         }
     }
 }
@@ -116,7 +362,11 @@ fn generate_pkce() -> String {
 
 impl AuthorizationParams {
     #[must_use]
-    pub fn new(oauth_client_id: String, redirect_uri: String) -> Self {
+    pub fn new(
+        oauth_client_id: String,
+        redirect_uri: String,
+        options: &AuthorizationOptions,
+    ) -> Self {
         let pkce_verifier = generate_pkce();
         let mut hasher = Sha256::new();
         hasher.update(&pkce_verifier);
@@ -129,13 +379,60 @@ impl AuthorizationParams {
             client_id: oauth_client_id,
             redirect_uri,
             state: crsf.to_string(),
-            response_mode: "query".to_string(),
-            audience: "hyper-tarot".to_string(),
+            response_mode: options.response_mode.clone(),
+            audience: options.audience.clone(),
             response_type: "code".to_string(),
-            scope: "offline_access openid email profile".to_string(),
-            code_verifier: pkce_verifier.to_string(),
+            scope: options.scope.clone(),
+            nonce: Uuid::new_v4().to_string(),
+            prompt: options.prompt.clone(),
+            login_hint: options.login_hint.clone(),
+            acr_values: options.acr_values.clone(),
+            max_age: options.max_age,
+            code_verifier: pkce_verifier.clone(),
             code_challenge: pkce_challenge.to_string(),
             code_challenge_method: "S256".to_string(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use googletest::prelude::*;
+
+    #[gtest]
+    fn login_query_overrides_provider_options() {
+        let options = AuthorizationOptions {
+            prompt: Some("consent".to_string()),
+            acr_values: Some("silver".to_string()),
+            ..Default::default()
+        };
+        let query = LoginQuery {
+            prompt: Some("login".to_string()),
+            login_hint: Some("freddie@example.com".to_string()),
+            ..Default::default()
+        };
+        let params = AuthorizationParams::new(
+            "transversal".to_string(),
+            "http://localhost/callback".to_string(),
+            &options.overridden(&query),
+        );
+        let encoded = serde_urlencoded::to_string(&params).unwrap();
+        expect_that!(encoded, contains_substring("prompt=login"));
+        expect_that!(
+            encoded,
+            contains_substring("login_hint=freddie%40example.com")
+        );
+        expect_that!(encoded, contains_substring("acr_values=silver"));
+        expect_that!(encoded, not(contains_substring("audience")));
+        expect_that!(encoded, not(contains_substring("max_age")));
+    }
+
+    #[gtest]
+    fn checks_auth_time_against_max_age() {
+        let now = Utc::now().timestamp();
+        expect_true!(auth_time_within(now - 300, 600));
+        expect_true!(auth_time_within(now - 630, 600));
+        expect_false!(auth_time_within(now - 3600, 600));
+    }
+}
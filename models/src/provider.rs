@@ -0,0 +1,103 @@
+use std::{
+    io::{Error as IOError, ErrorKind},
+    sync::Arc,
+};
+
+use crate::{
+    client_auth::ClientCredentials,
+    discovery::Discovery,
+    jwks::JwksCache,
+    oauth::{AuthorizationOptions, OpenIdConfiguration},
+    role::RoleMapping,
+};
+
+/// Placeholder in the per provider routes, e.g. `/auth/{provider}/login`
+const PROVIDER_PLACEHOLDER: &str = "{provider}";
+
+/// How API bearer tokens issued by a provider are validated
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AccessTokenValidation {
+    /// Locally, as JWTs signed with the provider keys
+    #[default]
+    Jwt,
+    /// Remotely, through the RFC 7662 introspection endpoint
+    Introspection,
+}
+
+/// An identity provider users can sign in with
+#[derive(Clone, Debug)]
+pub struct OAuthProvider {
+    /// Unique slug used in routes and to namespace subjects
+    pub name: String,
+    pub display_name: String,
+    pub autodiscover_url: String,
+    pub client_id: String,
+    pub credentials: ClientCredentials,
+    pub discovery: Discovery,
+    pub jwks: JwksCache,
+    pub roles: RoleMapping,
+    pub authorization: AuthorizationOptions,
+    pub access_tokens: AccessTokenValidation,
+    /// Audience API access tokens must be issued for
+    pub api_audience: String,
+}
+
+impl OAuthProvider {
+    /// Fills a per provider route template with this provider name
+    #[must_use]
+    pub fn path(&self, route: &str) -> String {
+        route.replace(PROVIDER_PLACEHOLDER, &self.name)
+    }
+
+    /// The discovered configuration, an [`ErrorKind::NotConnected`] error
+    /// while the provider could not be reached yet.
+    pub async fn oauth(&self) -> Result<Arc<OpenIdConfiguration>, IOError> {
+        self.discovery.config().await.ok_or_else(|| {
+            IOError::new(
+                ErrorKind::NotConnected,
+                format!("Login with {} is currently unavailable", self.display_name),
+            )
+        })
+    }
+}
+
+/// Named providers in configuration order, the order shown to users
+#[derive(Clone, Debug, Default)]
+pub struct ProviderRegistry {
+    providers: Vec<OAuthProvider>,
+}
+
+impl ProviderRegistry {
+    /// # Panics
+    /// if two providers share the same name
+    #[must_use]
+    pub fn new(providers: Vec<OAuthProvider>) -> Self {
+        for (idx, provider) in providers.iter().enumerate() {
+            assert!(
+                providers[..idx].iter().all(|p| p.name != provider.name),
+                "Duplicated OAuth provider {}",
+                provider.name
+            );
+        }
+        Self { providers }
+    }
+
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<&OAuthProvider> {
+        self.providers.iter().find(|p| p.name == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &OAuthProvider> {
+        self.providers.iter()
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.providers.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.providers.is_empty()
+    }
+}
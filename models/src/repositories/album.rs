@@ -94,8 +94,7 @@ fn sanitize_payload(value: &mut serde_json::Value) {
     });
     let in_year = value
         .get("year")
-        .and_then(|x| x.as_str())
-        .and_then(|y| y.parse::<i128>().ok())
+        .and_then(|y| y.as_i64().or_else(|| y.as_str()?.parse().ok()))
         .unwrap_or_default();
     value["id"] = json!(Uuid::nil().to_string());
     value["year"] = json!(in_year);
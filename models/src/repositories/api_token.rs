@@ -0,0 +1,101 @@
+use base64ct::{Base64UrlUnpadded, Encoding};
+use chrono::Utc;
+use sea_orm::{ActiveValue, DatabaseConnection, QueryOrder, entity::prelude::*};
+use sha2::{Digest, Sha256};
+use std::io::Error as IOError;
+use uuid::Uuid;
+
+use crate::{
+    generated::{api_token, user},
+    scope::TokenScopes,
+};
+
+/// Marks tokens issued by us, telling them apart from provider access tokens
+pub const API_TOKEN_PREFIX: &str = "tvat_";
+
+/// Only the hash of a token is stored, a leaked table grants nothing
+pub(crate) fn hash_secret(secret: &str) -> String {
+    Base64UrlUnpadded::encode_string(&Sha256::digest(secret.as_bytes()))
+}
+
+/// 244 random bits
+pub(crate) fn generate_secret() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+/// Issues a token for `user`, the only time its clear text is known
+pub async fn create_api_token(
+    db: &impl ConnectionTrait,
+    user: &user::Model,
+    name: &str,
+    scopes: &TokenScopes,
+    expires_at: Option<DateTimeWithTimeZone>,
+) -> Result<(api_token::Model, String), IOError> {
+    let token = format!("{API_TOKEN_PREFIX}{}", generate_secret());
+    let model = api_token::ActiveModel {
+        id: ActiveValue::NotSet,
+        user_id: ActiveValue::Set(user.id),
+        token_hash: ActiveValue::Set(hash_secret(&token)),
+        name: ActiveValue::Set(name.to_owned()),
+        scopes: ActiveValue::Set(scopes.to_string()),
+        expires_at: ActiveValue::Set(expires_at),
+        ..Default::default()
+    }
+    .insert(db)
+    .await
+    .map_err(IOError::other)?;
+    Ok((model, token))
+}
+
+/// Owner and scopes of an unexpired token, recording its use
+pub async fn get_user_by_api_token(
+    db: &DatabaseConnection,
+    token: &str,
+) -> Result<Option<(user::Model, TokenScopes)>, IOError> {
+    let found = api_token::Entity::find()
+        .find_also_related(user::Entity)
+        .filter(api_token::Column::TokenHash.eq(hash_secret(token)))
+        .one(db)
+        .await
+        .map_err(IOError::other)?;
+    let Some((token, Some(user))) = found else {
+        return Ok(None);
+    };
+    let now = Utc::now();
+    if token.expires_at.is_some_and(|expires_at| expires_at < now) {
+        log::debug!("API token {} expired", token.id);
+        return Ok(None);
+    }
+    let scopes = TokenScopes::parse(&token.scopes);
+    let mut token: api_token::ActiveModel = token.into();
+    token.last_used_at = ActiveValue::Set(Some(now.into()));
+    token.update(db).await.map_err(IOError::other)?;
+    Ok(Some((user, scopes)))
+}
+
+pub async fn list_api_tokens(
+    db: &DatabaseConnection,
+    user: &user::Model,
+) -> Result<Vec<api_token::Model>, IOError> {
+    api_token::Entity::find()
+        .filter(api_token::Column::UserId.eq(user.id))
+        .order_by_desc(api_token::Column::CreatedAt)
+        .all(db)
+        .await
+        .map_err(IOError::other)
+}
+
+/// Revokes one of the tokens of `user`, false when there is no such token
+pub async fn delete_api_token(
+    db: &DatabaseConnection,
+    user: &user::Model,
+    id: &Uuid,
+) -> Result<bool, IOError> {
+    api_token::Entity::delete_many()
+        .filter(api_token::Column::Id.eq(*id))
+        .filter(api_token::Column::UserId.eq(user.id))
+        .exec(db)
+        .await
+        .map(|res| res.rows_affected > 0)
+        .map_err(IOError::other)
+}
@@ -0,0 +1,214 @@
+use chrono::{TimeDelta, Utc};
+use sea_orm::{ActiveValue, DatabaseConnection, TransactionTrait, entity::prelude::*};
+use std::io::Error as IOError;
+use uuid::Uuid;
+
+use crate::{
+    generated::{device_authorization, user},
+    repositories::api_token::{create_api_token, generate_secret, hash_secret},
+    scope::{Scope, TokenScopes},
+};
+
+/// How long a user has to enter the code
+pub const DEVICE_CODE_LIFETIME: TimeDelta = TimeDelta::minutes(10);
+/// Seconds a device waits between polls, grown on `slow_down`
+pub const DEVICE_POLL_INTERVAL: i32 = 5;
+/// No vowels so codes never spell words, no digits to avoid 0/O confusion
+const USER_CODE_ALPHABET: &[u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+const USER_CODE_LENGTH: usize = 8;
+
+/// Outcome of a device polling the token endpoint, RFC 8628 section 3.5
+#[derive(Debug)]
+pub enum DevicePoll {
+    Pending,
+    SlowDown,
+    Denied,
+    Expired,
+    /// Approved, the code is spent and the user got the API `token`
+    Approved {
+        user: Box<user::Model>,
+        client_name: String,
+        token: String,
+    },
+    Unknown,
+}
+
+/// `XXXX-XXXX` user code
+fn generate_user_code() -> String {
+    let mut random = Uuid::new_v4().as_u128();
+    let base = USER_CODE_ALPHABET.len() as u128;
+    let code: String = (0..USER_CODE_LENGTH)
+        .map(|_| {
+            let letter = USER_CODE_ALPHABET[(random % base) as usize];
+            random /= base;
+            char::from(letter)
+        })
+        .collect();
+    format!("{}-{}", &code[..4], &code[4..])
+}
+
+/// Canonical form of a code typed by the user, ignoring case and separators
+#[must_use]
+pub fn normalize_user_code(input: &str) -> Option<String> {
+    let code: String = input
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|x| x.to_ascii_uppercase())
+        .collect();
+    let valid =
+        code.len() == USER_CODE_LENGTH && code.bytes().all(|x| USER_CODE_ALPHABET.contains(&x));
+    valid.then(|| format!("{}-{}", &code[..4], &code[4..]))
+}
+
+/// Starts a device login, returning the stored request and the device code
+pub async fn start_device_authorization(
+    db: &DatabaseConnection,
+    client_name: &str,
+) -> Result<(device_authorization::Model, String), IOError> {
+    let device_code = generate_secret();
+    let model = device_authorization::ActiveModel {
+        id: ActiveValue::NotSet,
+        device_code_hash: ActiveValue::Set(hash_secret(&device_code)),
+        user_code: ActiveValue::Set(generate_user_code()),
+        client_name: ActiveValue::Set(client_name.to_owned()),
+        interval: ActiveValue::Set(DEVICE_POLL_INTERVAL),
+        expires_at: ActiveValue::Set((Utc::now() + DEVICE_CODE_LIFETIME).into()),
+        ..Default::default()
+    }
+    .insert(db)
+    .await
+    .map_err(IOError::other)?;
+    Ok((model, device_code))
+}
+
+/// Unexpired request still waiting for a user decision
+pub async fn get_pending_device_authorization(
+    db: &DatabaseConnection,
+    user_code: &str,
+) -> Result<Option<device_authorization::Model>, IOError> {
+    device_authorization::Entity::find()
+        .filter(device_authorization::Column::UserCode.eq(user_code))
+        .filter(device_authorization::Column::UserId.is_null())
+        .filter(device_authorization::Column::Denied.eq(false))
+        .filter(device_authorization::Column::ExpiresAt.gt(Utc::now()))
+        .one(db)
+        .await
+        .map_err(IOError::other)
+}
+
+/// Approves the request for `user` or denies it
+pub async fn decide_device_authorization(
+    db: &DatabaseConnection,
+    request: device_authorization::Model,
+    user: &user::Model,
+    approve: bool,
+) -> Result<(), IOError> {
+    let mut request: device_authorization::ActiveModel = request.into();
+    if approve {
+        request.user_id = ActiveValue::Set(Some(user.id));
+    } else {
+        request.denied = ActiveValue::Set(true);
+    }
+    request.update(db).await.map_err(IOError::other)?;
+    Ok(())
+}
+
+/// Checks on a device login, polling faster than the interval is answered
+/// with [`DevicePoll::SlowDown`] and a longer interval. Once approved the
+/// code is spent for an API token lasting `token_lifetime`.
+pub async fn poll_device_authorization(
+    db: &DatabaseConnection,
+    device_code: &str,
+    token_lifetime: TimeDelta,
+) -> Result<DevicePoll, IOError> {
+    let found = device_authorization::Entity::find()
+        .find_also_related(user::Entity)
+        .filter(device_authorization::Column::DeviceCodeHash.eq(hash_secret(device_code)))
+        .one(db)
+        .await
+        .map_err(IOError::other)?;
+    let Some((request, user)) = found else {
+        return Ok(DevicePoll::Unknown);
+    };
+    let now = Utc::now();
+    if request.expires_at < now {
+        request.delete(db).await.map_err(IOError::other)?;
+        return Ok(DevicePoll::Expired);
+    }
+    if request.denied {
+        request.delete(db).await.map_err(IOError::other)?;
+        return Ok(DevicePoll::Denied);
+    }
+    if let Some(user) = user {
+        return redeem_device_authorization(db, request, user, token_lifetime).await;
+    }
+    let too_soon = request.last_polled_at.is_some_and(|last_polled_at| {
+        now - last_polled_at.to_utc() < TimeDelta::seconds(request.interval.into())
+    });
+    let interval = request.interval;
+    let mut request: device_authorization::ActiveModel = request.into();
+    request.last_polled_at = ActiveValue::Set(Some(now.into()));
+    if too_soon {
+        request.interval = ActiveValue::Set(interval + DEVICE_POLL_INTERVAL);
+    }
+    request.update(db).await.map_err(IOError::other)?;
+    Ok(if too_soon {
+        DevicePoll::SlowDown
+    } else {
+        DevicePoll::Pending
+    })
+}
+
+/// Spends the approved `request` for a token, only one of concurrent polls
+/// gets to delete it and the others find the code used
+async fn redeem_device_authorization(
+    db: &DatabaseConnection,
+    request: device_authorization::Model,
+    user: user::Model,
+    token_lifetime: TimeDelta,
+) -> Result<DevicePoll, IOError> {
+    let txn = db.begin().await.map_err(IOError::other)?;
+    let spent = device_authorization::Entity::delete_many()
+        .filter(device_authorization::Column::Id.eq(request.id))
+        .filter(device_authorization::Column::UserId.eq(user.id))
+        .exec(&txn)
+        .await
+        .map_err(IOError::other)?;
+    if spent.rows_affected != 1 {
+        return Ok(DevicePoll::Expired);
+    }
+    // Devices act on behalf of the user, like the browser does
+    let scopes = TokenScopes(Scope::ALL.to_vec());
+    let expires_at = Some((Utc::now() + token_lifetime).into());
+    let (_, token) =
+        create_api_token(&txn, &user, &request.client_name, &scopes, expires_at).await?;
+    txn.commit().await.map_err(IOError::other)?;
+    Ok(DevicePoll::Approved {
+        user: Box::new(user),
+        client_name: request.client_name,
+        token,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use googletest::prelude::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("bcdf-ghjk", Some("BCDF-GHJK"))]
+    #[case(" BCDFGHJK ", Some("BCDF-GHJK"))]
+    #[case("BCDF-GHJ", None)]
+    #[case("ABCD-EFGH", None)]
+    #[gtest]
+    fn normalizes_user_codes(#[case] input: &str, #[case] expected: Option<&str>) {
+        expect_eq!(normalize_user_code(input).as_deref(), expected);
+    }
+
+    #[gtest]
+    fn generates_valid_user_codes() {
+        let code = generate_user_code();
+        expect_eq!(normalize_user_code(&code), Some(code));
+    }
+}
@@ -0,0 +1,63 @@
+use sea_orm::{ActiveValue, ConnectionTrait, DatabaseConnection, QueryOrder, entity::prelude::*};
+use std::io::Error as IOError;
+
+use crate::generated::{identity, user};
+
+pub async fn get_user_by_identity(
+    db: &DatabaseConnection,
+    provider: &str,
+    subject: &str,
+) -> Result<Option<user::Model>, IOError> {
+    user::Entity::find()
+        .inner_join(identity::Entity)
+        .filter(identity::Column::Provider.eq(provider))
+        .filter(identity::Column::Subject.eq(subject))
+        .one(db)
+        .await
+        .map_err(IOError::other)
+}
+
+pub(crate) async fn get_identity(
+    db: &DatabaseConnection,
+    provider: &str,
+    subject: &str,
+) -> Result<Option<identity::Model>, IOError> {
+    identity::Entity::find()
+        .filter(identity::Column::Provider.eq(provider))
+        .filter(identity::Column::Subject.eq(subject))
+        .one(db)
+        .await
+        .map_err(IOError::other)
+}
+
+pub(crate) async fn insert_identity<C: ConnectionTrait>(
+    db: &C,
+    user: &user::Model,
+    provider: &str,
+    subject: &str,
+    email: Option<String>,
+) -> Result<identity::Model, IOError> {
+    identity::ActiveModel {
+        id: ActiveValue::Set(Uuid::new_v4()),
+        provider: ActiveValue::Set(provider.to_owned()),
+        subject: ActiveValue::Set(subject.to_owned()),
+        user_id: ActiveValue::Set(user.id),
+        email: ActiveValue::Set(email),
+        ..Default::default()
+    }
+    .insert(db)
+    .await
+    .map_err(IOError::other)
+}
+
+pub async fn list_identities(
+    db: &DatabaseConnection,
+    user: &user::Model,
+) -> Result<Vec<identity::Model>, IOError> {
+    identity::Entity::find()
+        .filter(identity::Column::UserId.eq(user.id))
+        .order_by_asc(identity::Column::CreatedAt)
+        .all(db)
+        .await
+        .map_err(IOError::other)
+}
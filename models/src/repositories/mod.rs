@@ -1,2 +1,5 @@
 pub mod album;
-pub(crate) mod user;
+pub mod api_token;
+pub mod device;
+pub mod identity;
+pub mod user;
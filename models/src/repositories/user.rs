@@ -1,38 +1,204 @@
-use sea_orm::{ActiveValue, DatabaseConnection, entity::prelude::*, sea_query::OnConflict};
+use sea_orm::{
+    ActiveValue, ConnectionTrait, DatabaseConnection, QueryOrder, SqlErr, entity::prelude::*,
+};
 use std::io::Error as IOError;
 
 use crate::generated::user;
 use crate::oauth::UserInfo;
+use crate::role::Role;
 
-pub(crate) async fn get_user_by_sub(
+pub async fn list_users(db: &DatabaseConnection) -> Result<Vec<user::Model>, IOError> {
+    user::Entity::find()
+        .order_by_asc(user::Column::Email)
+        .all(db)
+        .await
+        .map_err(IOError::other)
+}
+
+pub(crate) async fn get_user_by_email(
     db: &DatabaseConnection,
-    sub: &str,
+    email: &str,
 ) -> Result<Option<user::Model>, IOError> {
     user::Entity::find()
-        .filter(user::Column::Sid.eq(sub))
+        .filter(user::Column::Email.eq(email))
         .one(db)
         .await
         .map_err(IOError::other)
 }
 
-pub(crate) async fn upsert_user(
-    db: &DatabaseConnection,
-    new_user: UserInfo,
+pub(crate) async fn insert_user<C: ConnectionTrait>(
+    db: &C,
+    new_user: &UserInfo,
+    role: Role,
 ) -> Result<user::Model, IOError> {
-    let model = user::ActiveModel {
-        sid: ActiveValue::Set(new_user.sub),
-        email: ActiveValue::Set(new_user.email),
-        name: ActiveValue::Set(new_user.name),
-        id: ActiveValue::NotSet,
+    user::ActiveModel {
+        email: ActiveValue::Set(new_user.email.clone()),
+        name: ActiveValue::Set(new_user.name.clone()),
+        given_name: ActiveValue::Set(new_user.given_name.clone()),
+        family_name: ActiveValue::Set(new_user.family_name.clone()),
+        preferred_username: ActiveValue::Set(new_user.preferred_username.clone()),
+        picture: ActiveValue::Set(new_user.picture.clone()),
+        locale: ActiveValue::Set(new_user.locale.clone()),
+        role: ActiveValue::Set(role),
+        email_verified: ActiveValue::Set(new_user.has_verified_email()),
+        id: ActiveValue::Set(Uuid::new_v4()),
         ..Default::default()
+    }
+    .insert(db)
+    .await
+    .map_err(IOError::other)
+}
+
+/// Reconciles the stored profile and role with fresh provider claims,
+/// returning the updated user and the names of the columns which changed.
+///
+/// The email is only taken when the provider verified it, otherwise anyone
+/// could claim the email of another user at a lax provider and have the
+/// verified logins of its owner linked to them. It is left untouched when
+/// another user already owns it.
+pub(crate) async fn sync_profile(
+    db: &DatabaseConnection,
+    user: user::Model,
+    info: &UserInfo,
+    role: Role,
+) -> Result<(user::Model, Vec<&'static str>), IOError> {
+    let mut changed = vec![];
+    let mut model = user::ActiveModel::from(user.clone());
+    if info.has_verified_email() {
+        if user.email != info.email {
+            model.email = ActiveValue::Set(info.email.clone());
+            changed.push("email");
+        }
+        if !user.email_verified {
+            model.email_verified = ActiveValue::Set(true);
+            changed.push("email_verified");
+        }
+    }
+    if user.name != info.name {
+        model.name = ActiveValue::Set(info.name.clone());
+        changed.push("name");
+    }
+    if user.role != role {
+        model.role = ActiveValue::Set(role);
+        changed.push("role");
+    }
+    let optional_fields = [
+        (
+            &user.given_name,
+            &info.given_name,
+            &mut model.given_name,
+            "given_name",
+        ),
+        (
+            &user.family_name,
+            &info.family_name,
+            &mut model.family_name,
+            "family_name",
+        ),
+        (
+            &user.preferred_username,
+            &info.preferred_username,
+            &mut model.preferred_username,
+            "preferred_username",
+        ),
+        (&user.picture, &info.picture, &mut model.picture, "picture"),
+        (&user.locale, &info.locale, &mut model.locale, "locale"),
+    ];
+    for (stored, fresh, column, name) in optional_fields {
+        if stored != fresh {
+            *column = ActiveValue::Set(fresh.clone());
+            changed.push(name);
+        }
+    }
+    if changed.is_empty() {
+        return Ok((user, changed));
+    }
+    model.updated_at = ActiveValue::Set(chrono::Utc::now().into());
+    let err = match model.clone().update(db).await {
+        Ok(user) => return Ok((user, changed)),
+        Err(err) => err,
     };
-    let on_conflict = OnConflict::column(user::Column::Sid)
-        .update_column(user::Column::Email)
-        .update_column(user::Column::Name)
-        .to_owned();
-    user::Entity::insert(model)
-        .on_conflict(on_conflict)
-        .exec_with_returning(db)
-        .await
-        .map_err(IOError::other)
+    let taken = matches!(err.sql_err(), Some(SqlErr::UniqueConstraintViolation(_)));
+    if !taken || !changed.contains(&"email") {
+        return Err(IOError::other(err));
+    }
+    // Checking first would race with other logins, the constraint decides
+    log::warn!("Not syncing email of user {}, already in use", user.id);
+    model.email = ActiveValue::Unchanged(user.email.clone());
+    model.email_verified = ActiveValue::Unchanged(user.email_verified);
+    changed.retain(|name| !["email", "email_verified"].contains(name));
+    if changed.is_empty() {
+        return Ok((user, changed));
+    }
+    let user = model.update(db).await.map_err(IOError::other)?;
+    Ok((user, changed))
+}
+
+#[cfg(test)]
+mod tests {
+    use googletest::prelude::*;
+    use rstest::rstest;
+
+    use super::*;
+    use crate::test_db;
+
+    fn user_info(email: &str, name: &str, email_verified: bool) -> UserInfo {
+        serde_json::from_value(serde_json::json!({
+            "sub": "freddie",
+            "name": name,
+            "email": email,
+            "email_verified": email_verified,
+        }))
+        .unwrap()
+    }
+
+    async fn freddie(db: &DatabaseConnection) -> user::Model {
+        let info = user_info("freddie@example.com", "Freddie", true);
+        insert_user(db, &info, Role::Reader).await.unwrap()
+    }
+
+    #[gtest]
+    #[tokio::test]
+    async fn unchanged_profiles_are_not_written() {
+        let db = test_db::database().await;
+        let user = freddie(&db).await;
+        let info = user_info("freddie@example.com", "Freddie", true);
+        let (synced, changed) = sync_profile(&db, user.clone(), &info, Role::Reader)
+            .await
+            .unwrap();
+        expect_that!(changed, is_empty());
+        expect_eq!(synced.updated_at, user.updated_at);
+    }
+
+    #[gtest]
+    #[tokio::test]
+    async fn changed_profiles_are_synced() {
+        let db = test_db::database().await;
+        let user = freddie(&db).await;
+        let info = user_info("farrokh@example.com", "Farrokh", true);
+        let (synced, changed) = sync_profile(&db, user, &info, Role::Editor).await.unwrap();
+        expect_that!(
+            changed,
+            unordered_elements_are![eq(&"email"), eq(&"name"), eq(&"role")]
+        );
+        let stored = get_user_by_email(&db, "farrokh@example.com").await.unwrap();
+        expect_that!(stored, some(eq(&synced)));
+    }
+
+    #[rstest]
+    #[case::unverified("farrokh@example.com", false)]
+    #[case::taken("brian@example.com", true)]
+    #[gtest]
+    #[tokio::test]
+    async fn emails_are_kept(#[case] email: &str, #[case] email_verified: bool) {
+        let db = test_db::database().await;
+        let user = freddie(&db).await;
+        let brian = user_info("brian@example.com", "Brian", true);
+        insert_user(&db, &brian, Role::Reader).await.unwrap();
+        let info = user_info(email, "Farrokh", email_verified);
+        let (synced, changed) = sync_profile(&db, user, &info, Role::Reader).await.unwrap();
+        expect_that!(changed, elements_are![eq(&"name")]);
+        expect_eq!(synced.email, "freddie@example.com");
+        expect_eq!(synced.name, "Farrokh");
+    }
 }
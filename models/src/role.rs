@@ -0,0 +1,157 @@
+use std::{fmt, str::FromStr};
+
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::generated::user;
+
+/// Application roles, ordered so that each one includes the ones below it
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    EnumIter,
+    DeriveActiveEnum,
+    Serialize,
+    Deserialize,
+)]
+#[sea_orm(rs_type = "String", db_type = "Text")]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Can browse but not change anything
+    #[default]
+    #[sea_orm(string_value = "reader")]
+    Reader,
+    #[sea_orm(string_value = "editor")]
+    Editor,
+    #[sea_orm(string_value = "admin")]
+    Admin,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Reader => "reader",
+            Self::Editor => "editor",
+            Self::Admin => "admin",
+        })
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "reader" => Ok(Self::Reader),
+            "editor" => Ok(Self::Editor),
+            "admin" => Ok(Self::Admin),
+            other => Err(format!("Unknown role {other}")),
+        }
+    }
+}
+
+impl user::Model {
+    /// Whether the user holds `role` or a higher one, usable from templates
+    #[must_use]
+    pub fn has_role(&self, role: Role) -> bool {
+        self.role >= role
+    }
+}
+
+/// How a provider's claims translate into an application [`Role`]
+#[derive(Clone, Debug)]
+pub struct RoleMapping {
+    /// Dotted claim paths holding role or group names, e.g. `realm_access.roles`
+    pub claim_paths: Vec<String>,
+    /// Claim value granting each role, `(value, role)`
+    pub rules: Vec<(String, Role)>,
+    /// Role of users matching no rule, `None` refuses them
+    pub default_role: Option<Role>,
+}
+
+impl Default for RoleMapping {
+    fn default() -> Self {
+        Self {
+            claim_paths: vec!["realm_access.roles".to_string(), "groups".to_string()],
+            rules: vec![],
+            default_role: Some(Role::Editor),
+        }
+    }
+}
+
+impl RoleMapping {
+    /// Highest role granted by any of the claim sets, tried in order
+    #[must_use]
+    pub fn resolve(&self, claim_sets: &[&Map<String, Value>]) -> Option<Role> {
+        let values: Vec<&str> = claim_sets
+            .iter()
+            .flat_map(|claims| self.claim_paths.iter().map(|path| (*claims, path)))
+            .flat_map(|(claims, path)| claim_values(claims, path))
+            .collect();
+        self.rules
+            .iter()
+            .filter(|(value, _)| values.contains(&value.as_str()))
+            .map(|(_, role)| *role)
+            .max()
+            .or(self.default_role)
+    }
+}
+
+/// Strings found at a dotted path, either a single string or an array of them
+fn claim_values<'a>(claims: &'a Map<String, Value>, path: &str) -> Vec<&'a str> {
+    let mut segments = path.split('.');
+    let first = segments.next().and_then(|key| claims.get(key));
+    let value = segments.fold(first, |value, key| value.and_then(|v| v.get(key)));
+    match value {
+        Some(Value::String(value)) => vec![value.as_str()],
+        Some(Value::Array(values)) => values.iter().filter_map(Value::as_str).collect(),
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use googletest::prelude::*;
+    use rstest::rstest;
+    use serde_json::json;
+
+    fn mapping(default_role: Option<Role>) -> RoleMapping {
+        RoleMapping {
+            rules: vec![
+                ("transversal-admin".to_string(), Role::Admin),
+                ("/editors".to_string(), Role::Editor),
+                ("transversal-user".to_string(), Role::Reader),
+            ],
+            default_role,
+            ..Default::default()
+        }
+    }
+
+    #[rstest]
+    #[case(json!({ "realm_access": { "roles": ["transversal-user"] } }), Some(Role::Reader))]
+    #[case(json!({ "realm_access": { "roles": ["transversal-user"] }, "groups": ["/editors"] }), Some(Role::Editor))]
+    #[case(json!({ "groups": "transversal-admin" }), Some(Role::Admin))]
+    #[case(json!({ "groups": [] }), None)]
+    #[gtest]
+    fn maps_claims_to_highest_role(#[case] claims: Value, #[case] expected: Option<Role>) {
+        let claims = claims.as_object().unwrap();
+        expect_that!(mapping(None).resolve(&[claims]), eq(expected));
+    }
+
+    #[gtest]
+    fn falls_back_to_default_role() {
+        let claims = Map::new();
+        expect_that!(
+            mapping(Some(Role::Reader)).resolve(&[&claims]),
+            some(eq(Role::Reader))
+        );
+    }
+}
@@ -0,0 +1,94 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+/// What an API token may do, sessions are not limited by scopes
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Scope {
+    #[serde(rename = "albums:read")]
+    AlbumsRead,
+    #[serde(rename = "albums:write")]
+    AlbumsWrite,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 2] = [Scope::AlbumsRead, Scope::AlbumsWrite];
+
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::AlbumsRead => "albums:read",
+            Self::AlbumsWrite => "albums:write",
+        }
+    }
+
+    #[must_use]
+    pub fn description(self) -> &'static str {
+        match self {
+            Self::AlbumsRead => "List and read albums",
+            Self::AlbumsWrite => "Create, update and delete albums",
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|scope| scope.as_str() == value)
+            .ok_or_else(|| format!("Unknown scope {value}"))
+    }
+}
+
+/// Scopes of the token a request was authenticated with, set as a request
+/// extension next to the user, absent for session requests.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TokenScopes(pub Vec<Scope>);
+
+impl TokenScopes {
+    /// Space separated, as stored and as in OAuth `scope` parameters, unknown
+    /// scopes are ignored
+    #[must_use]
+    pub fn parse(value: &str) -> Self {
+        Self(
+            value
+                .split_whitespace()
+                .filter_map(|x| x.parse().ok())
+                .collect(),
+        )
+    }
+
+    #[must_use]
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.0.contains(&scope)
+    }
+}
+
+impl fmt::Display for TokenScopes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scopes: Vec<&str> = self.0.iter().map(|x| x.as_str()).collect();
+        f.write_str(&scopes.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use googletest::prelude::*;
+
+    #[gtest]
+    fn round_trips_stored_scopes() {
+        let scopes = TokenScopes::parse("albums:read  admin albums:write");
+        expect_eq!(scopes, TokenScopes(Scope::ALL.to_vec()));
+        expect_eq!(scopes.to_string(), "albums:read albums:write");
+        expect_false!(TokenScopes::parse("albums:read").allows(Scope::AlbumsWrite));
+    }
+}
@@ -0,0 +1,126 @@
+use std::{
+    fmt,
+    num::NonZeroUsize,
+    time::{Duration, Instant},
+};
+
+use lru::LruCache;
+use time::OffsetDateTime;
+use tokio::sync::Mutex;
+use tower_sessions::session::{Id, Record};
+
+/// Independent LRUs, so concurrent requests rarely wait on the same lock
+const SHARDS: usize = 16;
+
+struct Cached {
+    record: Record,
+    /// When the session was created, for its absolute lifetime
+    created_at: OffsetDateTime,
+    stored_at: Instant,
+}
+
+/// Process local copy of recently used sessions. Entries are trusted for
+/// `ttl` at most, so a missed invalidation from another instance only goes
+/// unnoticed that long.
+pub(crate) struct SessionCache {
+    /// Empty when caching is disabled
+    shards: Vec<Mutex<LruCache<Id, Cached>>>,
+    ttl: Duration,
+}
+
+impl fmt::Debug for SessionCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionCache")
+            .field("enabled", &self.is_enabled())
+            .field("ttl", &self.ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SessionCache {
+    /// A `capacity` of zero disables the cache, otherwise it is split
+    /// between the shards
+    pub(crate) fn new(capacity: usize, ttl: Duration) -> Self {
+        let shards = match NonZeroUsize::new(capacity.div_ceil(SHARDS)) {
+            Some(per_shard) => (0..SHARDS)
+                .map(|_| Mutex::new(LruCache::new(per_shard)))
+                .collect(),
+            None => Vec::new(),
+        };
+        Self { shards, ttl }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        !self.shards.is_empty()
+    }
+
+    fn shard(&self, id: &Id) -> Option<&Mutex<LruCache<Id, Cached>>> {
+        if self.shards.is_empty() {
+            return None;
+        }
+        // Ids are random so their low bits spread evenly
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let index = (id.0 as u128 % self.shards.len() as u128) as usize;
+        self.shards.get(index)
+    }
+
+    fn is_fresh(&self, cached: &Cached, now: OffsetDateTime) -> bool {
+        cached.stored_at.elapsed() < self.ttl && cached.record.expiry_date > now
+    }
+
+    /// The cached record and when it was created, unless it is expired or
+    /// older than the TTL
+    pub(crate) async fn get(&self, id: &Id) -> Option<(Record, OffsetDateTime)> {
+        let mut entries = self.shard(id)?.lock().await;
+        let cached = entries.get(id)?;
+        if !self.is_fresh(cached, OffsetDateTime::now_utc()) {
+            entries.pop(id);
+            return None;
+        }
+        Some((cached.record.clone(), cached.created_at))
+    }
+
+    pub(crate) async fn put(&self, record: &Record, created_at: OffsetDateTime) {
+        if let Some(shard) = self.shard(&record.id) {
+            let cached = Cached {
+                record: record.clone(),
+                created_at,
+                stored_at: Instant::now(),
+            };
+            shard.lock().await.put(record.id, cached);
+        }
+    }
+
+    pub(crate) async fn remove(&self, ids: &[Id]) {
+        for id in ids {
+            if let Some(shard) = self.shard(id) {
+                shard.lock().await.pop(id);
+            }
+        }
+    }
+
+    pub(crate) async fn clear(&self) {
+        for shard in &self.shards {
+            shard.lock().await.clear();
+        }
+    }
+
+    /// Drops expired and stale records, returns how many were dropped
+    pub(crate) async fn evict_expired(&self) -> u64 {
+        let now = OffsetDateTime::now_utc();
+        let mut evicted = 0;
+        for shard in &self.shards {
+            let mut entries = shard.lock().await;
+            let expired: Vec<Id> = entries
+                .iter()
+                .filter(|(_, cached)| !self.is_fresh(cached, now))
+                .map(|(id, _)| *id)
+                .collect();
+            for id in &expired {
+                entries.pop(id);
+            }
+            evicted += expired.len() as u64;
+        }
+        evicted
+    }
+}
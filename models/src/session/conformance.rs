@@ -0,0 +1,437 @@
+//! Behaviour every [`SessionStorage`] must share, checked through
+//! [`SessionBackend`] with caching disabled so each call reaches the storage
+
+use std::{collections::HashMap, sync::Arc};
+
+use chrono::Utc;
+use googletest::prelude::*;
+use rstest::rstest;
+use tempfile::TempDir;
+use time::OffsetDateTime;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream},
+    net::{TcpListener, TcpStream},
+    sync::Mutex,
+};
+use tower_sessions::{
+    SessionStore,
+    session::{Id, Record},
+    session_store::ExpiredDeletion,
+};
+use uuid::Uuid;
+
+use super::*;
+
+/// Keys with their value and expiry, and how many times each was written
+/// for `WATCH`
+#[derive(Default)]
+struct StandInKeys {
+    values: HashMap<Vec<u8>, (Vec<u8>, i64)>,
+    writes: HashMap<Vec<u8>, u64>,
+}
+
+impl StandInKeys {
+    fn written(&mut self, key: &[u8]) {
+        *self.writes.entry(key.to_vec()).or_default() += 1;
+    }
+
+    fn writes(&self, key: &[u8]) -> u64 {
+        self.writes.get(key).copied().unwrap_or_default()
+    }
+}
+
+/// Keys the stand-in lists per `SCAN` step
+const SCAN_STEP: usize = 2;
+
+/// Just enough of Redis for [`RespStorage`], returns its URL
+async fn resp_stand_in() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let keys = Arc::new(Mutex::new(StandInKeys::default()));
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(serve(BufStream::new(stream), keys.clone()));
+        }
+    });
+    format!("redis://:secret@{address}/1")
+}
+
+async fn serve(mut stream: BufStream<TcpStream>, keys: Arc<Mutex<StandInKeys>>) {
+    let mut watched = HashMap::new();
+    let mut queued: Option<Vec<Vec<Vec<u8>>>> = None;
+    while let Some(args) = read_command(&mut stream).await {
+        let mut keys = keys.lock().await;
+        let command = String::from_utf8_lossy(&args[0]).to_uppercase();
+        let reply = match (command.as_str(), queued.as_mut()) {
+            ("MULTI", None) => {
+                queued = Some(vec![]);
+                b"+OK\r\n".to_vec()
+            }
+            ("EXEC", Some(_)) => {
+                let commands = queued.take().unwrap_or_default();
+                let untouched = watched
+                    .drain()
+                    .all(|(key, writes): (Vec<u8>, u64)| keys.writes(&key) == writes);
+                if untouched {
+                    let replies = commands.iter().map(|args| execute(args, &mut keys));
+                    array(&replies.collect::<Vec<_>>())
+                } else {
+                    b"*-1\r\n".to_vec()
+                }
+            }
+            (_, Some(queue)) => {
+                queue.push(args);
+                b"+QUEUED\r\n".to_vec()
+            }
+            ("WATCH", None) => {
+                for key in &args[1..] {
+                    watched.insert(key.clone(), keys.writes(key));
+                }
+                b"+OK\r\n".to_vec()
+            }
+            ("UNWATCH", None) => {
+                watched.clear();
+                b"+OK\r\n".to_vec()
+            }
+            _ => execute(&args, &mut keys),
+        };
+        drop(keys);
+        if stream.write_all(&reply).await.is_err() || stream.flush().await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn read_command(stream: &mut BufStream<TcpStream>) -> Option<Vec<Vec<u8>>> {
+    let mut line = String::new();
+    stream.read_line(&mut line).await.ok()?;
+    let count: usize = line.trim().strip_prefix('*')?.parse().ok()?;
+    let mut args = vec![];
+    for _ in 0..count {
+        line.clear();
+        stream.read_line(&mut line).await.ok()?;
+        let len: usize = line.trim().strip_prefix('$')?.parse().ok()?;
+        let mut arg = vec![0; len + 2];
+        stream.read_exact(&mut arg).await.ok()?;
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Some(args)
+}
+
+fn bulk(value: Option<&Vec<u8>>) -> Vec<u8> {
+    let Some(value) = value else {
+        return b"$-1\r\n".to_vec();
+    };
+    let mut reply = format!("${}\r\n", value.len()).into_bytes();
+    reply.extend_from_slice(value);
+    reply.extend_from_slice(b"\r\n");
+    reply
+}
+
+fn array(items: &[Vec<u8>]) -> Vec<u8> {
+    let mut reply = format!("*{}\r\n", items.len()).into_bytes();
+    reply.extend(items.concat());
+    reply
+}
+
+fn execute(args: &[Vec<u8>], keys: &mut StandInKeys) -> Vec<u8> {
+    let now = Utc::now().timestamp_millis();
+    let expired: Vec<Vec<u8>> = keys
+        .values
+        .extract_if(|_, (_, expires_at)| *expires_at <= now)
+        .map(|(key, _)| key)
+        .collect();
+    for key in expired {
+        keys.written(&key);
+    }
+    let command = String::from_utf8_lossy(&args[0]).to_uppercase();
+    match (command.as_str(), &args[1..]) {
+        ("AUTH" | "SELECT", _) => b"+OK\r\n".to_vec(),
+        ("GET", [key]) => bulk(keys.values.get(key).map(|(value, _)| value)),
+        ("EXISTS", [key]) => {
+            format!(":{}\r\n", u8::from(keys.values.contains_key(key))).into_bytes()
+        }
+        ("SET", [key, value, options @ ..]) => {
+            let mut expires_at = i64::MAX;
+            let mut condition = None;
+            let mut options = options.iter();
+            while let Some(option) = options.next() {
+                match option.as_slice() {
+                    b"NX" | b"XX" => condition = Some(option.clone()),
+                    b"PXAT" => {
+                        let at = options.next().unwrap();
+                        expires_at = String::from_utf8_lossy(at).parse().unwrap();
+                    }
+                    _ => return b"-ERR syntax error\r\n".to_vec(),
+                }
+            }
+            let exists = keys.values.contains_key(key);
+            match condition.as_deref() {
+                Some(b"NX") if exists => return bulk(None),
+                Some(b"XX") if !exists => return bulk(None),
+                _ => {}
+            }
+            keys.values.insert(key.clone(), (value.clone(), expires_at));
+            keys.written(key);
+            b"+OK\r\n".to_vec()
+        }
+        ("DEL", deleted) => {
+            let count = deleted
+                .iter()
+                .filter(|key| {
+                    let removed = keys.values.remove(*key).is_some();
+                    keys.written(key);
+                    removed
+                })
+                .count();
+            format!(":{count}\r\n").into_bytes()
+        }
+        ("SCAN", [cursor, options @ ..]) => {
+            let Some([_, pattern]) = options.chunks(2).find(|x| x[0] == b"MATCH") else {
+                return b"-ERR syntax error\r\n".to_vec();
+            };
+            let prefix = pattern.strip_suffix(b"*").unwrap_or(pattern);
+            let mut matching: Vec<&Vec<u8>> = keys
+                .values
+                .keys()
+                .filter(|key| key.starts_with(prefix))
+                .collect();
+            matching.sort();
+            // COUNT is only a hint, small steps make scans take a few of them
+            let start: usize = String::from_utf8_lossy(cursor).parse().unwrap();
+            let end = start + SCAN_STEP;
+            let next = if end < matching.len() { end } else { 0 };
+            let step = matching.iter().skip(start).take(SCAN_STEP);
+            let step = array(&step.map(|key| bulk(Some(key))).collect::<Vec<_>>());
+            array(&[bulk(Some(&next.to_string().into_bytes())), step])
+        }
+        ("MGET", wanted) => {
+            let values = wanted
+                .iter()
+                .map(|key| bulk(keys.values.get(key).map(|(value, _)| value)));
+            array(&values.collect::<Vec<_>>())
+        }
+        _ => b"-ERR unknown command\r\n".to_vec(),
+    }
+}
+
+#[derive(Debug)]
+enum Kind {
+    SqliteMemory,
+    SqliteFile,
+    Memory,
+    Resp,
+}
+
+/// The backend, along with the directory of its database file which goes
+/// away once dropped
+async fn backend(kind: Kind) -> (SessionBackend, Option<TempDir>) {
+    let mut dir = None;
+    let storage: Arc<dyn SessionStorage> = match kind {
+        Kind::SqliteMemory => Arc::new(SeaStorage::sqlite("sqlite::memory:").await.unwrap()),
+        Kind::SqliteFile => {
+            let path = dir
+                .insert(TempDir::new().unwrap())
+                .path()
+                .join("sessions.db");
+            let url = format!("sqlite://{}", path.display());
+            Arc::new(SeaStorage::sqlite(&url).await.unwrap())
+        }
+        Kind::Memory => Arc::new(MemoryStorage::default()),
+        Kind::Resp => Arc::new(RespStorage::new(&resp_stand_in().await).unwrap()),
+    };
+    let backend = SessionBackend::new(storage).with_cache(0, DEFAULT_CACHE_TTL);
+    (backend, dir)
+}
+
+fn record(expiry_date: OffsetDateTime) -> Record {
+    Record {
+        id: Id::default(),
+        data: HashMap::from([("theme".to_string(), "dark".into())]),
+        expiry_date,
+    }
+}
+
+fn in_an_hour() -> OffsetDateTime {
+    OffsetDateTime::now_utc() + time::Duration::hours(1)
+}
+
+#[rstest]
+#[case::sqlite_memory(Kind::SqliteMemory)]
+#[case::sqlite_file(Kind::SqliteFile)]
+#[case::memory(Kind::Memory)]
+#[case::resp(Kind::Resp)]
+#[gtest]
+#[tokio::test]
+async fn stores_loads_saves_and_deletes(#[case] kind: Kind) {
+    let (backend, _dir) = backend(kind).await;
+    let mut record = record(in_an_hour());
+    backend.create(&mut record).await.unwrap();
+    let loaded = backend.load(&record.id).await.unwrap().unwrap();
+    expect_eq!(loaded.data, record.data);
+
+    record.data.insert("theme".to_string(), "light".into());
+    backend.save(&record).await.unwrap();
+    let loaded = backend.load(&record.id).await.unwrap().unwrap();
+    expect_eq!(loaded.data.get("theme"), Some(&"light".into()));
+
+    backend.delete(&record.id).await.unwrap();
+    expect_that!(backend.load(&record.id).await.unwrap(), none());
+}
+
+#[rstest]
+#[case::sqlite_memory(Kind::SqliteMemory)]
+#[case::sqlite_file(Kind::SqliteFile)]
+#[case::memory(Kind::Memory)]
+#[case::resp(Kind::Resp)]
+#[gtest]
+#[tokio::test]
+async fn expired_sessions_are_gone(#[case] kind: Kind) {
+    let (backend, _dir) = backend(kind).await;
+    let mut expired = record(OffsetDateTime::now_utc() - time::Duration::minutes(1));
+    backend.create(&mut expired).await.unwrap();
+    let mut live = record(in_an_hour());
+    backend.create(&mut live).await.unwrap();
+    expect_that!(backend.load(&expired.id).await.unwrap(), none());
+
+    backend.delete_expired().await.unwrap();
+    let stored = backend.storage.scan(&SessionFilter::All, None, u64::MAX);
+    let ids: Vec<String> = stored.await.unwrap().into_iter().map(|x| x.id).collect();
+    expect_eq!(ids, vec![live.id.to_string()]);
+}
+
+#[rstest]
+#[case::sqlite_memory(Kind::SqliteMemory)]
+#[case::sqlite_file(Kind::SqliteFile)]
+#[case::memory(Kind::Memory)]
+#[case::resp(Kind::Resp)]
+#[gtest]
+#[tokio::test]
+async fn colliding_ids_get_a_new_one(#[case] kind: Kind) {
+    let (backend, _dir) = backend(kind).await;
+    let mut first = record(in_an_hour());
+    backend.create(&mut first).await.unwrap();
+    let mut second = record(in_an_hour());
+    second.id = first.id;
+    second.data.insert("theme".to_string(), "light".into());
+    backend.create(&mut second).await.unwrap();
+
+    expect_ne!(second.id, first.id);
+    let loaded = backend.load(&first.id).await.unwrap().unwrap();
+    expect_eq!(loaded.data.get("theme"), Some(&"dark".into()));
+}
+
+#[rstest]
+#[case::sqlite_memory(Kind::SqliteMemory)]
+#[case::sqlite_file(Kind::SqliteFile)]
+#[case::memory(Kind::Memory)]
+#[case::resp(Kind::Resp)]
+#[gtest]
+#[tokio::test]
+async fn finds_sessions_by_owner(#[case] kind: Kind) {
+    let (backend, _dir) = backend(kind).await;
+    let user_id = Uuid::new_v4();
+    let mut signed_in = record(in_an_hour());
+    let user: user::Model =
+        serde_json::from_value(serde_json::json!({ "id": user_id, "email": "", "name": "" }))
+            .unwrap();
+    let idp_session = IdpSession("keycloak:sid".to_string());
+    for (key, value) in [
+        (user::Model::KEY, value::encode(&user)),
+        (IdpSession::KEY, value::encode(&idp_session)),
+    ] {
+        signed_in.data.insert(key.to_string(), value.unwrap());
+    }
+    backend.create(&mut signed_in).await.unwrap();
+    backend.create(&mut record(in_an_hour())).await.unwrap();
+
+    let sessions = backend.list_by_user(user_id).await.unwrap();
+    expect_eq!(sessions.len(), 1);
+    expect_eq!(
+        backend.delete_by_idp_session("keycloak:sid").await.unwrap(),
+        1
+    );
+    expect_that!(backend.list_by_user(user_id).await.unwrap(), is_empty());
+}
+
+#[rstest]
+#[case::sqlite_memory(Kind::SqliteMemory)]
+#[case::sqlite_file(Kind::SqliteFile)]
+#[case::memory(Kind::Memory)]
+#[case::resp(Kind::Resp)]
+#[gtest]
+#[tokio::test]
+async fn scans_in_id_order(#[case] kind: Kind) {
+    let (backend, _dir) = backend(kind).await;
+    let mut ids = vec![];
+    for _ in 0..5 {
+        let mut record = record(in_an_hour());
+        backend.create(&mut record).await.unwrap();
+        ids.push(record.id.to_string());
+    }
+    ids.sort();
+
+    let scan = |after, limit| backend.storage.scan(&SessionFilter::All, after, limit);
+    let all: Vec<String> = scan(None, u64::MAX)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|session| session.id)
+        .collect();
+    expect_eq!(all, ids);
+    let page = scan(Some(ids[1].as_str()), 2).await.unwrap();
+    expect_that!(
+        page,
+        elements_are![
+            field!(session::Model.id, eq(&ids[2])),
+            field!(session::Model.id, eq(&ids[3]))
+        ]
+    );
+}
+
+#[rstest]
+#[case::sqlite_memory(Kind::SqliteMemory)]
+#[case::sqlite_file(Kind::SqliteFile)]
+#[case::memory(Kind::Memory)]
+#[case::resp(Kind::Resp)]
+#[gtest]
+#[tokio::test]
+async fn replaces_and_swaps_in_one_step(#[case] kind: Kind) {
+    let (backend, _dir) = backend(kind).await;
+    let storage = &backend.storage;
+    let mut record = record(in_an_hour());
+    backend.create(&mut record).await.unwrap();
+    let old = storage.get(&record.id.to_string()).await.unwrap().unwrap();
+    let new = session::Model {
+        id: Id::default().to_string(),
+        ..old.clone()
+    };
+    expect_true!(storage.insert(&new, Some(&old.id)).await.unwrap());
+    expect_that!(storage.get(&old.id).await.unwrap(), none());
+    // Taken ids leave the session to replace in place
+    let other = session::Model {
+        id: Id::default().to_string(),
+        ..old.clone()
+    };
+    storage.insert(&other, None).await.unwrap();
+    expect_false!(storage.insert(&new, Some(&other.id)).await.unwrap());
+    expect_that!(storage.get(&other.id).await.unwrap(), some(anything()));
+
+    let swapped = serde_json::json!({ "theme": "light" });
+    let stale = serde_json::json!({ "theme": "blue" });
+    expect_false!(
+        storage
+            .swap_data(&new.id, &stale, swapped.clone())
+            .await
+            .unwrap()
+    );
+    expect_true!(
+        storage
+            .swap_data(&new.id, &new.data, swapped.clone())
+            .await
+            .unwrap()
+    );
+    let stored = storage.get(&new.id).await.unwrap().unwrap();
+    expect_eq!(stored.data, swapped);
+}
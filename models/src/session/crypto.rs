@@ -0,0 +1,324 @@
+use std::{collections::HashMap, fmt, io::Error as IOError, str::FromStr};
+
+use base64ct::{Base64, Base64UrlUnpadded, Encoding};
+use ring::{
+    aead::{AES_256_GCM, Aad, LessSafeKey, NONCE_LEN, Nonce, UnboundKey},
+    rand::{SecureRandom, SystemRandom},
+};
+use serde_json::Value;
+use tower_sessions::{
+    session::Id,
+    session_store::{self, Error as SessionStoreError},
+};
+
+use super::{SessionBackend, SessionFilter};
+use crate::generated::session;
+
+const KEY_LEN: usize = 32;
+
+#[derive(Clone)]
+struct SessionKey {
+    id: String,
+    bytes: [u8; KEY_LEN],
+}
+
+impl SessionKey {
+    fn aead(&self) -> LessSafeKey {
+        // The length is checked when parsing
+        LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &self.bytes).unwrap())
+    }
+}
+
+/// AES-256-GCM keys for session payloads, parsed from
+/// `<key id>:<base64 key>,<key id>:<base64 key>...`. The first key encrypts,
+/// the others only decrypt sessions written before a rotation.
+#[derive(Clone)]
+pub struct SessionKeyring {
+    keys: Vec<SessionKey>,
+}
+
+impl fmt::Debug for SessionKeyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ids: Vec<&str> = self.keys.iter().map(|key| key.id.as_str()).collect();
+        f.debug_struct("SessionKeyring")
+            .field("key_ids", &ids)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FromStr for SessionKeyring {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut keys: Vec<SessionKey> = vec![];
+        for entry in value.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+            let Some((id, key)) = entry.split_once(':') else {
+                return Err(format!("Session key {entry} must be <key id>:<base64 key>"));
+            };
+            if id.is_empty() || id.contains('.') {
+                return Err(format!(
+                    "Session key id {id} must be non empty without dots"
+                ));
+            }
+            if keys.iter().any(|key| key.id == id) {
+                return Err(format!("Session key id {id} is used twice"));
+            }
+            let bytes = Base64::decode_vec(key)
+                .ok()
+                .and_then(|bytes| <[u8; KEY_LEN]>::try_from(bytes).ok())
+                .ok_or_else(|| format!("Session key {id} must be {KEY_LEN} base64 bytes"))?;
+            keys.push(SessionKey {
+                id: id.to_string(),
+                bytes,
+            });
+        }
+        if keys.is_empty() {
+            return Err("No session key given".to_string());
+        }
+        Ok(Self { keys })
+    }
+}
+
+/// Id of the key an encrypted payload was sealed with
+pub(crate) fn sealed_key_id(sealed: &str) -> Option<&str> {
+    sealed.split_once('.').map(|(id, _)| id)
+}
+
+impl SessionKeyring {
+    /// Id of the key new payloads are encrypted with
+    #[must_use]
+    pub fn primary_id(&self) -> &str {
+        &self.keys[0].id
+    }
+
+    /// Seals `plaintext` as `<key id>.<base64url nonce and ciphertext>`,
+    /// bound to the session id so rows cannot be swapped
+    pub(crate) fn encrypt(&self, session_id: &Id, plaintext: &[u8]) -> Result<String, String> {
+        let key = &self.keys[0];
+        let mut nonce = [0; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| "Failed to generate a nonce".to_string())?;
+        let mut sealed = plaintext.to_vec();
+        key.aead()
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(session_id.to_string()),
+                &mut sealed,
+            )
+            .map_err(|_| "Failed to encrypt session".to_string())?;
+        let mut payload = nonce.to_vec();
+        payload.append(&mut sealed);
+        Ok(format!(
+            "{}.{}",
+            key.id,
+            Base64UrlUnpadded::encode_string(&payload)
+        ))
+    }
+
+    pub(crate) fn decrypt(&self, session_id: &Id, sealed: &str) -> Result<Vec<u8>, String> {
+        let Some((key_id, payload)) = sealed.split_once('.') else {
+            return Err("Malformed encrypted session".to_string());
+        };
+        let Some(key) = self.keys.iter().find(|key| key.id == key_id) else {
+            return Err(format!("Session encrypted with unknown key {key_id}"));
+        };
+        let mut payload =
+            Base64UrlUnpadded::decode_vec(payload).map_err(|_| "Malformed encrypted session")?;
+        if payload.len() < NONCE_LEN {
+            return Err("Malformed encrypted session".to_string());
+        }
+        let mut sealed = payload.split_off(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(&payload)
+            .map_err(|_| "Malformed encrypted session".to_string())?;
+        let plaintext = key
+            .aead()
+            .open_in_place(nonce, Aad::from(session_id.to_string()), &mut sealed)
+            .map_err(|_| format!("Session does not decrypt with key {key_id}"))?;
+        Ok(plaintext.to_vec())
+    }
+}
+
+impl SessionBackend {
+    pub(super) fn encode_data(
+        &self,
+        session_id: &Id,
+        data: &HashMap<String, Value>,
+    ) -> session_store::Result<Value> {
+        let encode_error = |_| SessionStoreError::Encode("Failed to encode session".to_owned());
+        let Some(keyring) = &self.keyring else {
+            return serde_json::to_value(data).map_err(encode_error);
+        };
+        let plaintext = serde_json::to_vec(data).map_err(encode_error)?;
+        keyring
+            .encrypt(session_id, &plaintext)
+            .map(Value::String)
+            .map_err(SessionStoreError::Encode)
+    }
+
+    /// Encrypted payloads are JSON strings, plaintext ones JSON objects
+    pub(super) fn decode_data(
+        &self,
+        session_id: &Id,
+        data: Value,
+    ) -> Result<HashMap<String, Value>, String> {
+        let Value::String(sealed) = data else {
+            return serde_json::from_value(data).map_err(|err| err.to_string());
+        };
+        let Some(keyring) = &self.keyring else {
+            return Err("Session is encrypted but no key is configured".to_string());
+        };
+        let plaintext = keyring.decrypt(session_id, &sealed)?;
+        serde_json::from_slice(&plaintext).map_err(|err| err.to_string())
+    }
+
+    /// Every stored session, in batches
+    async fn for_each_stored(
+        &self,
+        mut apply: impl AsyncFnMut(Vec<session::Model>) -> Result<(), IOError>,
+    ) -> Result<(), IOError> {
+        let mut after = None;
+        loop {
+            let sessions = self
+                .storage
+                .scan(&SessionFilter::All, after.as_deref(), self.reap_batch_size)
+                .await?;
+            let Some(last) = sessions.last() else {
+                return Ok(());
+            };
+            after = Some(last.id.clone());
+            apply(sessions).await?;
+        }
+    }
+
+    /// Rewrites the sessions not sealed with the primary key, plaintext ones
+    /// included, returns how many were. Sessions no configured key opens are
+    /// left for [`Self::purge_key`].
+    pub async fn reencrypt_all(&self) -> Result<u64, IOError> {
+        let Some(keyring) = self.keyring.clone() else {
+            return Err(IOError::other("Session encryption is not configured"));
+        };
+        let mut rewritten = 0;
+        self.for_each_stored(async |sessions| {
+            for session::Model { id, data, .. } in sessions {
+                if data.as_str().and_then(sealed_key_id) == Some(keyring.primary_id()) {
+                    continue;
+                }
+                let Ok(session_id) = Id::from_str(&id) else {
+                    continue;
+                };
+                let decoded = match self.decode_data(&session_id, data.clone()) {
+                    Ok(decoded) => decoded,
+                    Err(err) => {
+                        log::warn!("Not re-encrypting session: {err}");
+                        continue;
+                    }
+                };
+                let sealed = self
+                    .encode_data(&session_id, &decoded)
+                    .map_err(IOError::other)?;
+                // Unless the app changed it meanwhile
+                if self.storage.swap_data(&id, &data, sealed).await? {
+                    rewritten += 1;
+                }
+            }
+            Ok(())
+        })
+        .await?;
+        Ok(rewritten)
+    }
+
+    /// Deletes the sessions sealed with a retired key, returns how many
+    pub async fn purge_key(&self, key_id: &str) -> Result<u64, IOError> {
+        if let Some(keyring) = &self.keyring
+            && keyring.primary_id() == key_id
+        {
+            return Err(IOError::other(format!(
+                "Key {key_id} still encrypts new sessions"
+            )));
+        }
+        let mut purged = 0;
+        self.for_each_stored(async |sessions| {
+            let ids: Vec<String> = sessions
+                .into_iter()
+                .filter(|session| session.data.as_str().and_then(sealed_key_id) == Some(key_id))
+                .map(|session| session.id)
+                .collect();
+            if !ids.is_empty() {
+                purged += self.delete_ids(ids).await?;
+            }
+            Ok(())
+        })
+        .await?;
+        Ok(purged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::tests::{create_record, sqlite_backend};
+    use googletest::prelude::*;
+    use time::OffsetDateTime;
+    use tower_sessions::SessionStore;
+
+    const OLD_KEY: &str = "old:AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+    const NEW_KEY: &str = "new:Hx4dHBsaGRgXFhUUExIREA8ODQwLCgkIBwYFBAMCAQA=";
+
+    #[gtest]
+    fn rotated_keys_still_decrypt() {
+        let old: SessionKeyring = OLD_KEY.parse().unwrap();
+        let rotated: SessionKeyring = format!("{NEW_KEY},{OLD_KEY}").parse().unwrap();
+        let id = Id::default();
+        let sealed = old.encrypt(&id, b"{}").unwrap();
+        expect_eq!(sealed_key_id(&sealed), Some("old"));
+        expect_eq!(rotated.decrypt(&id, &sealed), Ok(b"{}".to_vec()));
+        expect_eq!(
+            sealed_key_id(&rotated.encrypt(&id, b"{}").unwrap()),
+            Some("new")
+        );
+
+        // Bound to the session it was written for
+        expect_that!(rotated.decrypt(&Id::default(), &sealed), err(anything()));
+        let retired: SessionKeyring = NEW_KEY.parse().unwrap();
+        expect_that!(retired.decrypt(&id, &sealed), err(anything()));
+    }
+
+    async fn stored_data(backend: &SessionBackend) -> Vec<Value> {
+        let sessions = backend.storage.scan(&SessionFilter::All, None, u64::MAX);
+        let sessions = sessions.await.unwrap();
+        sessions.into_iter().map(|session| session.data).collect()
+    }
+
+    #[gtest]
+    #[tokio::test]
+    async fn sessions_are_reencrypted_and_purged_by_key() {
+        let plain = sqlite_backend().await;
+        let old =
+            SessionBackend::new(plain.storage.clone()).with_encryption(OLD_KEY.parse().unwrap());
+        let rotated = SessionBackend::new(plain.storage.clone())
+            .with_encryption(format!("{NEW_KEY},{OLD_KEY}").parse().unwrap());
+        let expiry = OffsetDateTime::now_utc() + time::Duration::hours(1);
+        let plaintext_id = create_record(&plain, expiry).await;
+        let sealed_id = create_record(&old, expiry).await;
+        expect_that!(
+            stored_data(&plain).await,
+            contains(predicate(|data: &Value| data.is_object()))
+        );
+
+        expect_that!(rotated.load(&plaintext_id).await.unwrap(), some(anything()));
+        expect_that!(rotated.load(&sealed_id).await.unwrap(), some(anything()));
+        expect_eq!(rotated.reencrypt_all().await.unwrap(), 2);
+        expect_that!(
+            stored_data(&plain).await,
+            each(predicate(|data: &Value| {
+                data.as_str().and_then(sealed_key_id) == Some("new")
+            }))
+        );
+
+        create_record(&old, expiry).await;
+        expect_that!(rotated.purge_key("new").await, err(anything()));
+        expect_eq!(rotated.purge_key("old").await.unwrap(), 1);
+        expect_eq!(stored_data(&plain).await.len(), 2);
+    }
+}
@@ -0,0 +1,75 @@
+use std::{collections::HashMap, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use time::OffsetDateTime;
+
+use super::value::{self, SessionValue};
+
+/// Set at sign in when the user chose to stay signed in
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct KeepSignedIn;
+
+impl SessionValue for KeepSignedIn {
+    const KEY: &'static str = "auth.keep_signed_in";
+}
+
+/// How long a session lasts
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SessionLifetime {
+    /// Inactivity after which the session ends
+    pub idle: Duration,
+    /// Time since the session was created after which it ends, however
+    /// active it is
+    pub absolute: Duration,
+}
+
+impl SessionLifetime {
+    /// Whether a session created at `created_at` outlived the absolute limit
+    #[must_use]
+    pub fn outlived(&self, created_at: OffsetDateTime) -> bool {
+        time::Duration::try_from(self.absolute)
+            .ok()
+            .and_then(|absolute| created_at.checked_add(absolute))
+            .is_some_and(|deadline| deadline <= OffsetDateTime::now_utc())
+    }
+}
+
+/// Lifetimes of ordinary sessions and of those kept signed in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SessionLifetimes {
+    pub default: SessionLifetime,
+    /// Applies once [`KeepSignedIn`] is in the session
+    pub kept_signed_in: SessionLifetime,
+}
+
+impl Default for SessionLifetimes {
+    fn default() -> Self {
+        Self {
+            default: SessionLifetime {
+                idle: Duration::from_secs(2 * 60 * 60),
+                absolute: Duration::from_secs(12 * 60 * 60),
+            },
+            kept_signed_in: SessionLifetime {
+                idle: Duration::from_secs(7 * 24 * 60 * 60),
+                absolute: Duration::from_secs(30 * 24 * 60 * 60),
+            },
+        }
+    }
+}
+
+impl SessionLifetimes {
+    /// Lifetime of a session holding `data`
+    #[must_use]
+    pub fn of(&self, data: &HashMap<String, Value>) -> SessionLifetime {
+        let kept = data
+            .get(KeepSignedIn::KEY)
+            .cloned()
+            .and_then(value::decode::<KeepSignedIn>);
+        if kept.is_some() {
+            self.kept_signed_in
+        } else {
+            self.default
+        }
+    }
+}
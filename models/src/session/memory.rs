@@ -0,0 +1,89 @@
+use std::{collections::BTreeMap, io::Error as IOError, ops::Bound};
+
+use sea_orm::prelude::async_trait::async_trait;
+use serde_json::Value;
+use tokio::sync::Mutex;
+
+use super::storage::{SessionFilter, SessionStorage};
+use crate::generated::session;
+
+/// Sessions in process memory, lost on restart and not shared between
+/// instances, for tests and single instance deployments
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    sessions: Mutex<BTreeMap<String, session::Model>>,
+}
+
+#[async_trait]
+impl SessionStorage for MemoryStorage {
+    async fn insert(
+        &self,
+        session: &session::Model,
+        replaces: Option<&str>,
+    ) -> Result<bool, IOError> {
+        let mut sessions = self.sessions.lock().await;
+        if sessions.contains_key(&session.id) {
+            return Ok(false);
+        }
+        if let Some(old_id) = replaces {
+            sessions.remove(old_id);
+        }
+        sessions.insert(session.id.clone(), session.clone());
+        Ok(true)
+    }
+
+    async fn update(&self, session: &session::Model) -> Result<(), IOError> {
+        let mut sessions = self.sessions.lock().await;
+        let Some(stored) = sessions.get_mut(&session.id) else {
+            return Err(IOError::other("Session does not exist"));
+        };
+        *stored = session::Model {
+            created_at: stored.created_at,
+            ..session.clone()
+        };
+        Ok(())
+    }
+
+    async fn swap_data(&self, id: &str, current: &Value, data: Value) -> Result<bool, IOError> {
+        let mut sessions = self.sessions.lock().await;
+        match sessions.get_mut(id) {
+            Some(stored) if stored.data == *current => {
+                stored.data = data;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<session::Model>, IOError> {
+        Ok(self.sessions.lock().await.get(id).cloned())
+    }
+
+    async fn delete(&self, ids: &[String]) -> Result<u64, IOError> {
+        let mut sessions = self.sessions.lock().await;
+        Ok(ids
+            .iter()
+            .filter(|id| sessions.remove(*id).is_some())
+            .count() as u64)
+    }
+
+    async fn scan(
+        &self,
+        filter: &SessionFilter,
+        after: Option<&str>,
+        limit: u64,
+    ) -> Result<Vec<session::Model>, IOError> {
+        let start = after.map_or(Bound::Unbounded, |id| Bound::Excluded(id.to_string()));
+        let limit = usize::try_from(limit).unwrap_or(usize::MAX);
+        Ok(self
+            .sessions
+            .lock()
+            .await
+            .range((start, Bound::Unbounded))
+            .map(|(_, session)| session)
+            .filter(|session| filter.matches(session))
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+}
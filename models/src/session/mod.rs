@@ -0,0 +1,786 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::Error as IOError,
+    str::FromStr,
+    sync::{
+        Arc, Weak,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+use base64ct::{Base64UrlUnpadded, Encoding};
+use chrono::{DateTime, FixedOffset, Utc};
+use sea_orm::prelude::async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use time::{OffsetDateTime, UtcDateTime, UtcOffset};
+use tower_sessions::{
+    Session, SessionStore,
+    session::{Id, Record},
+    session_store::{self, Error as SessionStoreError, ExpiredDeletion},
+};
+
+use uuid::Uuid;
+
+use crate::generated::{session, user};
+use cache::SessionCache;
+pub use crypto::SessionKeyring;
+pub use lifetime::{KeepSignedIn, SessionLifetime, SessionLifetimes};
+pub use memory::MemoryStorage;
+pub use resp::RespStorage;
+pub use sea::SeaStorage;
+pub use storage::{SessionFilter, SessionStorage, SessionStoreConfig};
+pub use value::SessionValue;
+
+mod cache;
+#[cfg(test)]
+mod conformance;
+mod crypto;
+mod lifetime;
+mod memory;
+mod notify;
+mod resp;
+mod sea;
+mod storage;
+pub mod value;
+
+/// Session key naming the id a cycled session replaces until it is stored,
+/// see [`SessionBackend::cycle_id`]
+const ROTATED_FROM_KEY: &str = "rotated_from";
+
+/// Sessions kept in memory by default, see [`SessionBackend::with_cache`]
+pub const DEFAULT_CACHE_CAPACITY: usize = 1000;
+/// How long a cached session is trusted by default
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(60);
+/// Expiry extension below which unchanged sessions are not written
+pub const DEFAULT_REFRESH_THRESHOLD: Duration = Duration::from_secs(60);
+/// Expired sessions deleted per batch by [`ExpiredDeletion::delete_expired`]
+const DEFAULT_REAP_BATCH_SIZE: u64 = 500;
+
+/// Where a session was opened from, recorded at sign in
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SessionClient {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl SessionValue for SessionClient {
+    const KEY: &'static str = "auth.client";
+}
+
+/// Identity provider session the session was opened through, see
+/// [`provider_session_id`]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct IdpSession(pub String);
+
+impl SessionValue for IdpSession {
+    const KEY: &'static str = "auth.idp_session";
+}
+
+/// The signed in user
+impl SessionValue for user::Model {
+    const KEY: &'static str = "auth.user";
+}
+
+/// Stable public reference to a session, the id itself is the cookie value
+/// and must never be shown.
+#[must_use]
+pub fn session_handle(session_id: &str) -> String {
+    let digest = Sha256::digest(session_id.as_bytes());
+    Base64UrlUnpadded::encode_string(&digest[..12])
+}
+
+/// Provider session ids (`sid` claim) are only unique within their provider
+#[must_use]
+pub fn provider_session_id(provider: &str, sid: &str) -> String {
+    format!("{provider}:{sid}")
+}
+
+/// Running totals of the expired session reaper
+#[derive(Debug, Default)]
+pub struct ReaperCounters {
+    /// Completed [`ExpiredDeletion::delete_expired`] runs
+    pub runs: AtomicU64,
+    /// Runs that stopped on a storage error
+    pub failures: AtomicU64,
+    /// Expired sessions deleted from the storage
+    pub deleted: AtomicU64,
+    /// Expired records dropped from the cache
+    pub evicted: AtomicU64,
+}
+
+/// Running totals of session reads and writes, showing how much the cache
+/// and skipped refreshes save the storage
+#[derive(Debug, Default)]
+pub struct StoreCounters {
+    pub cache_hits: AtomicU64,
+    pub cache_misses: AtomicU64,
+    /// Saves that updated the stored session
+    pub writes: AtomicU64,
+    /// Saves of unchanged data within [`SessionBackend::with_refresh_threshold`]
+    pub writes_skipped: AtomicU64,
+}
+
+/// Sessions for [`tower_sessions`] over any [`SessionStorage`], with caching,
+/// encryption and the lookups of the account pages
+#[derive(Clone, Debug)]
+pub struct SessionBackend {
+    storage: Arc<dyn SessionStorage>,
+    cache: Arc<SessionCache>,
+    /// Tells our own change notifications apart from other instances' ones
+    instance: Uuid,
+    /// Ids being cycled, their session goes when the new one is created
+    rotating: Arc<Mutex<HashSet<Id>>>,
+    /// Sessions locked by [`Self::lock`], gone once nobody holds them
+    locks: Arc<Mutex<HashMap<Id, Weak<Mutex<()>>>>>,
+    reap_batch_size: u64,
+    refresh_threshold: Duration,
+    lifetimes: SessionLifetimes,
+    /// Payloads are stored encrypted when set
+    keyring: Option<Arc<SessionKeyring>>,
+    counters: Arc<ReaperCounters>,
+    store_counters: Arc<StoreCounters>,
+}
+
+impl SessionBackend {
+    #[must_use]
+    pub fn new(storage: Arc<dyn SessionStorage>) -> Self {
+        Self {
+            storage,
+            cache: Arc::new(SessionCache::new(DEFAULT_CACHE_CAPACITY, DEFAULT_CACHE_TTL)),
+            instance: Uuid::new_v4(),
+            rotating: Arc::default(),
+            locks: Arc::default(),
+            reap_batch_size: DEFAULT_REAP_BATCH_SIZE,
+            refresh_threshold: DEFAULT_REFRESH_THRESHOLD,
+            lifetimes: SessionLifetimes::default(),
+            keyring: None,
+            counters: Arc::default(),
+            store_counters: Arc::default(),
+        }
+    }
+
+    /// Keeps up to `capacity` sessions in memory for `ttl` at most, zero
+    /// disables caching. With several instances the cache is kept coherent
+    /// by [`Self::listen_for_changes`], the TTL bounds staleness meanwhile.
+    #[must_use]
+    pub fn with_cache(mut self, capacity: usize, ttl: Duration) -> Self {
+        self.cache = Arc::new(SessionCache::new(capacity, ttl));
+        self
+    }
+
+    /// Caps how many expired sessions a single delete removes, so the reaper
+    /// never holds long locks on the storage
+    #[must_use]
+    pub fn with_reap_batch_size(mut self, batch_size: u64) -> Self {
+        self.reap_batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Saves of unchanged data only extend the stored expiry once it moved by
+    /// `threshold`, so the stored session may expire that much before the
+    /// cookie does. Skipping relies on the cached copy, without a cache every
+    /// save writes.
+    #[must_use]
+    pub fn with_refresh_threshold(mut self, threshold: Duration) -> Self {
+        self.refresh_threshold = threshold;
+        self
+    }
+
+    /// Sessions older than their absolute lifetime no longer load, the idle
+    /// one is up to the session layer expiry
+    #[must_use]
+    pub fn with_lifetimes(mut self, lifetimes: SessionLifetimes) -> Self {
+        self.lifetimes = lifetimes;
+        self
+    }
+
+    /// Encrypts the stored session data, only the owner and client fields
+    /// stay readable. Sessions stored in plaintext before still load.
+    #[must_use]
+    pub fn with_encryption(mut self, keyring: SessionKeyring) -> Self {
+        self.keyring = Some(Arc::new(keyring));
+        self
+    }
+
+    #[must_use]
+    pub fn reaper_counters(&self) -> &ReaperCounters {
+        &self.counters
+    }
+
+    #[must_use]
+    pub fn store_counters(&self) -> &StoreCounters {
+        &self.store_counters
+    }
+
+    /// Serializes read-modify-write sequences on session `id`, e.g. token
+    /// refreshes racing with a rotated refresh token. Other instances are not
+    /// excluded, see [`Self::stored_value`] for what they stored.
+    pub async fn lock(&self, id: Id) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.locks.lock().await;
+            locks.retain(|_, lock| lock.strong_count() > 0);
+            if let Some(lock) = locks.get(&id).and_then(Weak::upgrade) {
+                lock
+            } else {
+                let lock = Arc::new(Mutex::new(()));
+                locks.insert(id, Arc::downgrade(&lock));
+                lock
+            }
+        };
+        lock.lock_owned().await
+    }
+
+    /// The `T` of session `id` as stored, concurrent requests may have
+    /// changed it since the current one loaded the session
+    pub async fn stored_value<T: SessionValue>(&self, id: &Id) -> Option<T> {
+        let record = self.load(id).await.ok()??;
+        record.data.get(T::KEY).cloned().and_then(value::decode)
+    }
+
+    /// Whether the stored session already matches `record` closely enough
+    async fn is_unchanged(&self, record: &Record) -> bool {
+        let Some((stored, _)) = self.cache.get(&record.id).await else {
+            return false;
+        };
+        let extended = record.expiry_date - stored.expiry_date;
+        stored.data == record.data
+            && extended >= time::Duration::ZERO
+            && extended < self.refresh_threshold
+    }
+
+    /// Deletes expired sessions every `period`, unlike
+    /// `ExpiredDeletion::continuously_delete_expired` a failed run is logged
+    /// and retried on the next tick instead of stopping the task.
+    pub async fn reap_expired(self, period: Duration) {
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            if let Err(err) = self.delete_expired().await {
+                self.counters.failures.fetch_add(1, Ordering::Relaxed);
+                log::error!("Failed to delete expired sessions: {err}");
+            }
+        }
+    }
+
+    /// Gives the session a new id, so an id known before signing in (session
+    /// fixation) or a privilege change is worthless afterwards. Unlike
+    /// [`Session::cycle_id`] alone the old session is deleted in the same step
+    /// the new one is stored, the session is saved right away.
+    pub async fn cycle_id(&self, session: &Session) -> Result<(), IOError> {
+        let Some(old_id) = session.id() else {
+            // Never stored so nobody knows its id
+            return Ok(());
+        };
+        session
+            .insert(ROTATED_FROM_KEY, old_id.to_string())
+            .await
+            .map_err(IOError::other)?;
+        self.rotating.lock().await.insert(old_id);
+        if let Err(err) = session.cycle_id().await {
+            self.rotating.lock().await.remove(&old_id);
+            return Err(IOError::other(err));
+        }
+        if let Err(err) = session.save().await {
+            // Never leave the old id usable
+            SessionStore::delete(self, &old_id).await.ok();
+            return Err(IOError::other(err));
+        }
+        Ok(())
+    }
+
+    /// Deletes every session opened through the given identity provider session
+    pub async fn delete_by_idp_session(&self, idp_session_id: &str) -> Result<u64, IOError> {
+        let filter = SessionFilter::IdpSession(idp_session_id.to_string());
+        self.delete_matching(&filter, None).await
+    }
+
+    /// Deletes every session belonging to the given user
+    pub async fn delete_by_user(&self, user_id: Uuid) -> Result<u64, IOError> {
+        self.delete_matching(&SessionFilter::User(user_id), None)
+            .await
+    }
+
+    /// Unexpired sessions of the given user, most recently used first
+    pub async fn list_by_user(&self, user_id: Uuid) -> Result<Vec<session::Model>, IOError> {
+        let now = Utc::now();
+        let mut sessions = self
+            .storage
+            .scan(&SessionFilter::User(user_id), None, u64::MAX)
+            .await?;
+        sessions.retain(|session| session.expires_at > now);
+        sessions.sort_by_key(|session| std::cmp::Reverse(session.refreshed_at));
+        Ok(sessions)
+    }
+
+    /// Deletes the session of the given user with the given
+    /// [`session_handle`], false when there is none
+    pub async fn delete_by_handle(&self, user_id: Uuid, handle: &str) -> Result<bool, IOError> {
+        let Some(session) = self
+            .list_by_user(user_id)
+            .await?
+            .into_iter()
+            .find(|session| session_handle(&session.id) == handle)
+        else {
+            return Ok(false);
+        };
+        Ok(self.delete_ids(vec![session.id]).await? > 0)
+    }
+
+    /// Deletes every session of the given user but the one in use
+    pub async fn delete_others(&self, user_id: Uuid, current: &Id) -> Result<u64, IOError> {
+        self.delete_matching(&SessionFilter::User(user_id), Some(current))
+            .await
+    }
+
+    async fn delete_matching(
+        &self,
+        filter: &SessionFilter,
+        except: Option<&Id>,
+    ) -> Result<u64, IOError> {
+        let except = except.map(ToString::to_string);
+        let ids: Vec<String> = self
+            .storage
+            .scan(filter, None, u64::MAX)
+            .await?
+            .into_iter()
+            .map(|session| session.id)
+            .filter(|id| except.as_ref() != Some(id))
+            .collect();
+        self.delete_ids(ids).await
+    }
+
+    async fn delete_ids(&self, ids: Vec<String>) -> Result<u64, IOError> {
+        let deleted = self.storage.delete(&ids).await?;
+        let ids: Vec<Id> = ids.iter().filter_map(|id| Id::from_str(id).ok()).collect();
+        self.cache.remove(&ids).await;
+        self.notify_changed(&ids).await;
+        Ok(deleted)
+    }
+
+    /// The stored form of `record`, `created_at` only counts for new sessions
+    fn to_stored(&self, record: &Record) -> session_store::Result<session::Model> {
+        let (user_id, idp_session_id, client) = session_owner(record);
+        let now = Utc::now().fixed_offset();
+        Ok(session::Model {
+            id: record.id.to_string(),
+            data: self.encode_data(&record.id, &record.data)?,
+            expires_at: convert_time(&record.expiry_date)?,
+            refreshed_at: now,
+            created_at: now,
+            user_id,
+            idp_session_id,
+            ip: client.ip,
+            user_agent: client.user_agent,
+        })
+    }
+}
+
+/// Owner of a session, read from its [`SessionValue`]s so sessions can be
+/// looked up by user or identity provider session.
+fn session_owner(record: &Record) -> (Option<Uuid>, Option<String>, SessionClient) {
+    fn stored<T: SessionValue>(record: &Record) -> Option<T> {
+        record.data.get(T::KEY).cloned().and_then(value::decode)
+    }
+    let user_id = stored::<user::Model>(record).map(|user| user.id);
+    let idp_session_id = stored::<IdpSession>(record).map(|sid| sid.0);
+    let client = stored::<SessionClient>(record).unwrap_or_default();
+    (user_id, idp_session_id, client)
+}
+
+#[async_trait]
+impl ExpiredDeletion for SessionBackend {
+    async fn delete_expired(&self) -> session_store::Result<()> {
+        let evicted = self.cache.evict_expired().await;
+        self.counters.evicted.fetch_add(evicted, Ordering::Relaxed);
+        let expired = SessionFilter::ExpiredAt(Utc::now().fixed_offset());
+        let mut deleted = 0;
+        loop {
+            let ids: Vec<String> = self
+                .storage
+                .scan(&expired, None, self.reap_batch_size)
+                .await
+                .map_err(|_| backend_error("Failed to find expired sessions"))?
+                .into_iter()
+                .map(|session| session.id)
+                .collect();
+            let found = ids.len() as u64;
+            if found == 0 {
+                break;
+            }
+            let removed = self
+                .storage
+                .delete(&ids)
+                .await
+                .map_err(|_| backend_error("Failed to delete expired sessions"))?;
+            self.counters.deleted.fetch_add(removed, Ordering::Relaxed);
+            deleted += removed;
+            let ids: Vec<Id> = ids.iter().filter_map(|id| Id::from_str(id).ok()).collect();
+            self.cache.remove(&ids).await;
+            if found < self.reap_batch_size {
+                break;
+            }
+        }
+        self.counters.runs.fetch_add(1, Ordering::Relaxed);
+        if deleted > 0 || evicted > 0 {
+            log::info!("Deleted {deleted} expired sessions, evicted {evicted} from cache");
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SessionStore for SessionBackend {
+    async fn create(&self, record: &mut Record) -> session_store::Result<()> {
+        let rotated_from = record
+            .data
+            .remove(ROTATED_FROM_KEY)
+            .and_then(|id| id.as_str().and_then(|id| Id::from_str(id).ok()));
+        let replaces = rotated_from.map(|id| id.to_string());
+        // Ids are random, but collisions are ours to handle
+        loop {
+            let session = self.to_stored(record)?;
+            let inserted = self
+                .storage
+                .insert(&session, replaces.as_deref())
+                .await
+                .map_err(|err| {
+                    log::error!("Failed to insert session: {err}");
+                    backend_error("Failed to insert session")
+                })?;
+            if inserted {
+                break;
+            }
+            record.id = Id::default();
+        }
+        if let Some(old_id) = rotated_from {
+            self.cache.remove(&[old_id]).await;
+            self.notify_changed(&[old_id]).await;
+        }
+        self.cache.put(record, OffsetDateTime::now_utc()).await;
+        Ok(())
+    }
+
+    async fn save(&self, record: &Record) -> session_store::Result<()> {
+        if self.is_unchanged(record).await {
+            self.store_counters
+                .writes_skipped
+                .fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+        self.storage
+            .update(&self.to_stored(record)?)
+            .await
+            .map_err(|_| backend_error("Failed to update session"))?;
+        self.store_counters.writes.fetch_add(1, Ordering::Relaxed);
+        self.notify_changed(&[record.id]).await;
+        // Otherwise the next load reads the creation time from storage
+        if let Some((_, created_at)) = self.cache.get(&record.id).await {
+            self.cache.put(record, created_at).await;
+        }
+        Ok(())
+    }
+
+    async fn load(&self, session_id: &Id) -> session_store::Result<Option<Record>> {
+        if let Some((record, created_at)) = self.cache.get(session_id).await {
+            log::debug!("Session found in cache: {session_id}");
+            self.store_counters
+                .cache_hits
+                .fetch_add(1, Ordering::Relaxed);
+            if self.lifetimes.of(&record.data).outlived(created_at) {
+                self.cache.remove(&[*session_id]).await;
+                return Ok(None);
+            }
+            return Ok(Some(record));
+        }
+        self.store_counters
+            .cache_misses
+            .fetch_add(1, Ordering::Relaxed);
+        let session = self
+            .storage
+            .get(&session_id.to_string())
+            .await
+            .map_err(|_| backend_error("Failed to load session"))?;
+        let Some(sess) = session.filter(|sess| sess.expires_at > Utc::now()) else {
+            // Expired sessions stay until the reaper gets to them
+            return Ok(None);
+        };
+        let expiry_date = from_stored_time(&sess.expires_at)?;
+        let created_at = from_stored_time(&sess.created_at)?;
+        let Ok(id) = Id::from_str(&sess.id) else {
+            return Err(decode_error("Failed to parse session ID"));
+        };
+        let data = match self.decode_data(&id, sess.data) {
+            Ok(data) => data,
+            Err(err) => {
+                // Typically sealed with a retired key, signing in again is all it takes
+                log::warn!("Ignoring unreadable session: {err}");
+                return Ok(None);
+            }
+        };
+        if self.lifetimes.of(&data).outlived(created_at) {
+            log::debug!("Session {id} outlived its absolute lifetime");
+            return Ok(None);
+        }
+        let record = Record {
+            id,
+            data,
+            expiry_date,
+        };
+        self.cache.put(&record, created_at).await;
+        Ok(Some(record))
+    }
+
+    async fn delete(&self, session_id: &Id) -> session_store::Result<()> {
+        if self.rotating.lock().await.remove(session_id) {
+            // Replaced when the cycled session is created
+            return Ok(());
+        }
+        self.storage
+            .delete(&[session_id.to_string()])
+            .await
+            .map_err(|_| backend_error("Failed to delete session"))?;
+        self.cache.remove(&[*session_id]).await;
+        self.notify_changed(&[*session_id]).await;
+        Ok(())
+    }
+}
+
+fn backend_error(message: &str) -> SessionStoreError {
+    SessionStoreError::Backend(message.to_owned())
+}
+
+fn decode_error(message: &str) -> SessionStoreError {
+    SessionStoreError::Decode(message.to_owned())
+}
+
+fn convert_time(time: &OffsetDateTime) -> session_store::Result<DateTime<FixedOffset>> {
+    let nanos: i64 = time
+        .unix_timestamp_nanos()
+        .try_into()
+        .map_err(|_| SessionStoreError::Encode("Failed to convert expiry time".to_owned()))?;
+    Ok(DateTime::from_timestamp_nanos(nanos).fixed_offset())
+}
+
+fn from_stored_time(time: &DateTime<FixedOffset>) -> session_store::Result<OffsetDateTime> {
+    let Some(nanos) = time.timestamp_nanos_opt().map(i128::from) else {
+        return Err(decode_error("Failed to decode stored time"));
+    };
+    match UtcDateTime::from_unix_timestamp_nanos(nanos) {
+        Ok(date) => Ok(date.to_offset(UtcOffset::UTC)),
+        Err(_) => Err(decode_error("Failed to convert stored time")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use googletest::prelude::*;
+    use std::collections::HashMap;
+
+    pub(super) async fn sqlite_backend() -> SessionBackend {
+        let storage = SeaStorage::sqlite("sqlite::memory:").await.unwrap();
+        SessionBackend::new(Arc::new(storage))
+    }
+
+    #[gtest]
+    #[tokio::test]
+    async fn cycled_session_id_no_longer_loads() {
+        let backend = sqlite_backend().await;
+        let session = Session::new(None, Arc::new(backend.clone()), None);
+        session.insert("auth_params", "pre-login").await.unwrap();
+        session.save().await.unwrap();
+        let pre_login = session.id().unwrap();
+
+        backend.cycle_id(&session).await.unwrap();
+        let signed_in = session.id().unwrap();
+        expect_ne!(signed_in, pre_login);
+        expect_that!(backend.load(&pre_login).await.unwrap(), none());
+
+        // Not even a database read of the old id brings it back
+        backend.cache.clear().await;
+        expect_that!(backend.load(&pre_login).await.unwrap(), none());
+        let record = backend.load(&signed_in).await.unwrap().unwrap();
+        expect_eq!(record.data.get("auth_params"), Some(&"pre-login".into()));
+        expect_that!(record.data.get(ROTATED_FROM_KEY), none());
+    }
+
+    #[gtest]
+    #[tokio::test]
+    async fn pre_login_cookie_gets_an_empty_session() {
+        let backend = sqlite_backend().await;
+        let store = Arc::new(backend.clone());
+        let session = Session::new(None, store.clone(), None);
+        session.insert("auth_params", "pre-login").await.unwrap();
+        session.save().await.unwrap();
+        let pre_login = session.id();
+        backend.cycle_id(&session).await.unwrap();
+        session.insert(user::Model::KEY, "someone").await.unwrap();
+        session.save().await.unwrap();
+
+        // What the session layer does for a request carrying the old cookie
+        let replayed = Session::new(pre_login, store, None);
+        expect_that!(
+            replayed.get::<String>(user::Model::KEY).await.unwrap(),
+            none()
+        );
+    }
+
+    #[gtest]
+    #[tokio::test]
+    async fn sessions_end_after_their_absolute_lifetime() {
+        let backend = sqlite_backend().await.with_lifetimes(SessionLifetimes {
+            default: SessionLifetime {
+                idle: Duration::from_secs(3600),
+                absolute: Duration::from_secs(3600),
+            },
+            ..SessionLifetimes::default()
+        });
+        let expiry_date = OffsetDateTime::now_utc() + time::Duration::hours(1);
+        let kept_signed_in = value::encode(&KeepSignedIn).unwrap();
+        let kept = Record {
+            id: Id::default(),
+            data: HashMap::from([(KeepSignedIn::KEY.to_string(), kept_signed_in)]),
+            expiry_date,
+        };
+        let ordinary = Record {
+            id: Id::default(),
+            data: HashMap::new(),
+            expiry_date,
+        };
+        for record in [&kept, &ordinary] {
+            let mut stored = backend.to_stored(record).unwrap();
+            stored.created_at -= chrono::TimeDelta::hours(2);
+            backend.storage.insert(&stored, None).await.unwrap();
+        }
+
+        expect_that!(backend.load(&ordinary.id).await.unwrap(), none());
+        expect_that!(backend.load(&kept.id).await.unwrap(), some(anything()));
+    }
+
+    #[gtest]
+    #[tokio::test]
+    async fn locked_sessions_see_values_stored_meanwhile() {
+        let backend = sqlite_backend().await;
+        let store = Arc::new(backend.clone());
+        let session = Session::new(None, store.clone(), None);
+        value::write(&session, &KeepSignedIn).await.unwrap();
+        session.save().await.unwrap();
+        let id = session.id().unwrap();
+        // A concurrent request of the same session, loaded before the write
+        let concurrent = Session::new(Some(id), store, None);
+        value::read::<KeepSignedIn>(&concurrent).await.unwrap();
+
+        let guard = backend.lock(id).await;
+        let waiting = tokio::time::timeout(Duration::from_millis(50), backend.lock(id));
+        expect_that!(waiting.await, err(anything()));
+        session.remove_value(KeepSignedIn::KEY).await.unwrap();
+        session.save().await.unwrap();
+        drop(guard);
+
+        let _guard = backend.lock(id).await;
+        expect_that!(backend.stored_value::<KeepSignedIn>(&id).await, none());
+        expect_that!(
+            value::read::<KeepSignedIn>(&concurrent).await,
+            some(anything())
+        );
+    }
+
+    pub(super) async fn create_record(backend: &SessionBackend, expiry_date: OffsetDateTime) -> Id {
+        let mut record = Record {
+            id: Id::default(),
+            data: HashMap::new(),
+            expiry_date,
+        };
+        backend.create(&mut record).await.unwrap();
+        record.id
+    }
+
+    #[gtest]
+    #[tokio::test]
+    async fn reaper_deletes_expired_sessions_in_batches() {
+        let backend = sqlite_backend().await.with_reap_batch_size(2);
+        let past = OffsetDateTime::now_utc() - time::Duration::hours(1);
+        let future = OffsetDateTime::now_utc() + time::Duration::hours(1);
+        let mut expired = vec![];
+        for _ in 0..5 {
+            expired.push(create_record(&backend, past).await);
+        }
+        let live = create_record(&backend, future).await;
+
+        // Cached records are checked too
+        expect_that!(backend.load(&expired[0]).await.unwrap(), none());
+        backend.delete_expired().await.unwrap();
+
+        let counters = backend.reaper_counters();
+        expect_eq!(counters.deleted.load(Ordering::Relaxed), 5);
+        expect_eq!(counters.evicted.load(Ordering::Relaxed), 4);
+        expect_eq!(counters.runs.load(Ordering::Relaxed), 1);
+        let stored = backend.storage.scan(&SessionFilter::All, None, u64::MAX);
+        expect_eq!(stored.await.unwrap().len(), 1);
+        expect_that!(backend.load(&live).await.unwrap(), some(anything()));
+    }
+
+    #[gtest]
+    #[tokio::test]
+    async fn changes_from_other_instances_evict_cached_sessions() {
+        let backend = sqlite_backend().await;
+        let other = SessionBackend::new(backend.storage.clone());
+        let future = OffsetDateTime::now_utc() + time::Duration::hours(1);
+        let id = create_record(&backend, future).await;
+        other.delete(&id).await.unwrap();
+
+        // Stale until told otherwise
+        expect_that!(backend.load(&id).await.unwrap(), some(anything()));
+        backend
+            .handle_notification(&format!("{}:{id}", backend.instance))
+            .await;
+        expect_that!(backend.load(&id).await.unwrap(), some(anything()));
+        backend
+            .handle_notification(&format!("{}:{id}", other.instance))
+            .await;
+        expect_that!(backend.load(&id).await.unwrap(), none());
+    }
+
+    #[gtest]
+    #[tokio::test]
+    async fn cached_sessions_expire_after_the_ttl() {
+        let backend = sqlite_backend()
+            .await
+            .with_cache(DEFAULT_CACHE_CAPACITY, std::time::Duration::ZERO);
+        let other = SessionBackend::new(backend.storage.clone());
+        let future = OffsetDateTime::now_utc() + time::Duration::hours(1);
+        let id = create_record(&backend, future).await;
+        other.delete(&id).await.unwrap();
+        expect_that!(backend.load(&id).await.unwrap(), none());
+    }
+
+    #[gtest]
+    #[tokio::test]
+    async fn unchanged_saves_only_write_past_the_threshold() {
+        let backend = sqlite_backend()
+            .await
+            .with_refresh_threshold(std::time::Duration::from_secs(60));
+        let expiry = OffsetDateTime::now_utc() + time::Duration::hours(1);
+        let id = create_record(&backend, expiry).await;
+        let mut record = backend.load(&id).await.unwrap().unwrap();
+
+        record.expiry_date = expiry + time::Duration::seconds(30);
+        backend.save(&record).await.unwrap();
+        record.expiry_date = expiry + time::Duration::seconds(90);
+        backend.save(&record).await.unwrap();
+        record.data.insert("theme".to_string(), "dark".into());
+        backend.save(&record).await.unwrap();
+
+        let counters = backend.store_counters();
+        expect_eq!(counters.writes_skipped.load(Ordering::Relaxed), 1);
+        expect_eq!(counters.writes.load(Ordering::Relaxed), 2);
+        backend.cache.clear().await;
+        let stored = backend.load(&id).await.unwrap().unwrap();
+        expect_eq!(stored.data.get("theme"), Some(&"dark".into()));
+    }
+}
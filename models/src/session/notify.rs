@@ -0,0 +1,91 @@
+use std::{str::FromStr, time::Duration};
+
+use sea_orm::{
+    ConnectionTrait, DatabaseConnection, DbBackend, Statement,
+    sqlx::{self, postgres::PgListener},
+};
+use tower_sessions::session::Id;
+use uuid::Uuid;
+
+use super::SessionBackend;
+
+/// Postgres channel carrying the ids of changed or deleted sessions
+const CHANGES_CHANNEL: &str = "session_changed";
+/// Notification payloads are limited to 8000 bytes
+const IDS_PER_NOTIFICATION: usize = 200;
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+impl SessionBackend {
+    /// Other instances share the storage through Postgres only
+    fn postgres(&self) -> Option<&DatabaseConnection> {
+        self.storage
+            .database()
+            .filter(|db| db.get_database_backend() == DbBackend::Postgres)
+    }
+
+    /// Tells the other instances to drop these sessions from their cache
+    pub(super) async fn notify_changed(&self, ids: &[Id]) {
+        let Some(db) = self.postgres() else {
+            return;
+        };
+        for chunk in ids.chunks(IDS_PER_NOTIFICATION) {
+            let ids: Vec<String> = chunk.iter().map(ToString::to_string).collect();
+            let payload = format!("{}:{}", self.instance, ids.join(","));
+            let statement = Statement::from_sql_and_values(
+                DbBackend::Postgres,
+                "SELECT pg_notify($1, $2)",
+                [CHANGES_CHANNEL.into(), payload.into()],
+            );
+            // The cache TTL still bounds how stale the others get
+            if let Err(err) = db.execute(statement).await {
+                log::warn!("Failed to notify session changes: {err}");
+            }
+        }
+    }
+
+    /// Drops the sessions other instances change or delete from the cache,
+    /// for as long as the app runs. The whole cache is cleared whenever
+    /// notifications may have been missed.
+    pub async fn listen_for_changes(self) {
+        let Some(db) = self.postgres().filter(|_| self.cache.is_enabled()) else {
+            return;
+        };
+        loop {
+            match self.receive_changes(db).await {
+                Ok(()) => log::warn!("Session change notifications lost their connection"),
+                Err(err) => log::warn!("Failed to listen for session changes: {err}"),
+            }
+            self.cache.clear().await;
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+
+    /// Applies notifications until the connection is lost
+    async fn receive_changes(&self, db: &DatabaseConnection) -> Result<(), sqlx::Error> {
+        let mut listener = PgListener::connect_with(db.get_postgres_connection_pool()).await?;
+        listener.listen(CHANGES_CHANNEL).await?;
+        // Changes made before listening went unnoticed
+        self.cache.clear().await;
+        log::info!("Listening for session changes");
+        while let Some(notification) = listener.try_recv().await? {
+            self.handle_notification(notification.payload()).await;
+        }
+        Ok(())
+    }
+
+    /// Payloads are `<instance>:<id>,<id>...`, our own ones are skipped
+    pub(super) async fn handle_notification(&self, payload: &str) {
+        let Some((instance, ids)) = payload.split_once(':') else {
+            log::warn!("Ignoring malformed session change notification");
+            return;
+        };
+        if Uuid::parse_str(instance).ok() == Some(self.instance) {
+            return;
+        }
+        let ids: Vec<Id> = ids
+            .split(',')
+            .filter_map(|id| Id::from_str(id).ok())
+            .collect();
+        self.cache.remove(&ids).await;
+    }
+}
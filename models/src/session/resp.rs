@@ -0,0 +1,430 @@
+use std::io::{Error as IOError, ErrorKind};
+
+use chrono::{DateTime, FixedOffset};
+use reqwest::Url;
+use sea_orm::prelude::async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream},
+    net::TcpStream,
+    sync::{Mutex, MutexGuard},
+};
+use uuid::Uuid;
+
+use super::storage::{SessionFilter, SessionStorage};
+use crate::generated::session;
+
+/// Sessions are kept under `session:<id>`
+const KEY_PREFIX: &str = "session:";
+/// Keys listed per `SCAN` step, and fetched per `MGET`, while scanning
+const SCAN_CHUNK: usize = 100;
+
+#[derive(Debug)]
+enum Reply {
+    Status,
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+/// Stored form of a session, the entity skips its timestamps when serialized
+#[derive(Serialize, Deserialize)]
+struct StoredSession {
+    id: String,
+    data: Value,
+    expires_at: DateTime<FixedOffset>,
+    refreshed_at: DateTime<FixedOffset>,
+    created_at: DateTime<FixedOffset>,
+    user_id: Option<Uuid>,
+    idp_session_id: Option<String>,
+    ip: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl From<&session::Model> for StoredSession {
+    fn from(session: &session::Model) -> Self {
+        let session = session.clone();
+        Self {
+            id: session.id,
+            data: session.data,
+            expires_at: session.expires_at,
+            refreshed_at: session.refreshed_at,
+            created_at: session.created_at,
+            user_id: session.user_id,
+            idp_session_id: session.idp_session_id,
+            ip: session.ip,
+            user_agent: session.user_agent,
+        }
+    }
+}
+
+impl From<StoredSession> for session::Model {
+    fn from(session: StoredSession) -> Self {
+        Self {
+            id: session.id,
+            data: session.data,
+            expires_at: session.expires_at,
+            refreshed_at: session.refreshed_at,
+            created_at: session.created_at,
+            user_id: session.user_id,
+            idp_session_id: session.idp_session_id,
+            ip: session.ip,
+            user_agent: session.user_agent,
+        }
+    }
+}
+
+/// Sessions in Redis, or anything speaking its protocol, expiring along with
+/// their keys. Lookups other than by id go through every session, this
+/// suits small deployments.
+#[derive(Debug)]
+pub struct RespStorage {
+    address: String,
+    password: Option<String>,
+    database: u32,
+    connection: Mutex<Option<BufStream<TcpStream>>>,
+}
+
+impl RespStorage {
+    /// From `redis://[:<password>@]<host>[:<port>][/<db>]`, connecting lazily
+    pub fn new(url: &str) -> Result<Self, IOError> {
+        let url = Url::parse(url).map_err(IOError::other)?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| IOError::other("Redis URL without host"))?;
+        let database = match url.path().trim_start_matches('/') {
+            "" => 0,
+            db => db
+                .parse()
+                .map_err(|_| IOError::other("Invalid Redis database"))?,
+        };
+        Ok(Self {
+            address: format!("{host}:{}", url.port().unwrap_or(6379)),
+            password: url.password().map(ToOwned::to_owned),
+            database,
+            connection: Mutex::default(),
+        })
+    }
+
+    async fn connect(&self) -> Result<BufStream<TcpStream>, IOError> {
+        let mut stream = BufStream::new(TcpStream::connect(&self.address).await?);
+        if let Some(password) = &self.password {
+            call(&mut stream, &[b"AUTH", password.as_bytes()]).await?;
+        }
+        if self.database != 0 {
+            let database = self.database.to_string();
+            call(&mut stream, &[b"SELECT", database.as_bytes()]).await?;
+        }
+        Ok(stream)
+    }
+
+    /// The connection, held until dropped by commands which must follow
+    /// each other
+    async fn connection(&self) -> Result<Connection<'_>, IOError> {
+        let mut connection = self.connection.lock().await;
+        if connection.is_none() {
+            *connection = Some(self.connect().await?);
+        }
+        Ok(Connection(connection))
+    }
+
+    async fn command(&self, args: &[&[u8]]) -> Result<Reply, IOError> {
+        self.connection().await?.call(args).await
+    }
+
+    /// One `SCAN` step from `cursor`, the next cursor along with the session
+    /// keys listed. Unlike `KEYS` it leaves the server to other clients in
+    /// between steps.
+    async fn scan_step(&self, cursor: &[u8]) -> Result<(Vec<u8>, Vec<Vec<u8>>), IOError> {
+        let pattern = format!("{KEY_PREFIX}*");
+        let count = SCAN_CHUNK.to_string();
+        let args: [&[u8]; 6] = [
+            b"SCAN",
+            cursor,
+            b"MATCH",
+            pattern.as_bytes(),
+            b"COUNT",
+            count.as_bytes(),
+        ];
+        match self.command(&args).await? {
+            Reply::Array(reply) => match <[Reply; 2]>::try_from(reply) {
+                Ok([Reply::Bulk(Some(next)), Reply::Array(keys)]) => {
+                    let keys = keys.into_iter().filter_map(|key| match key {
+                        Reply::Bulk(Some(key)) => Some(key),
+                        _ => None,
+                    });
+                    Ok((next, keys.collect()))
+                }
+                Ok(reply) => Err(unexpected(&Reply::Array(reply.into()))),
+                Err(reply) => Err(unexpected(&Reply::Array(reply))),
+            },
+            reply => Err(unexpected(&reply)),
+        }
+    }
+
+    /// `SET` with the session expiry, `condition` being `NX` or `XX`
+    async fn set(&self, session: &session::Model, condition: &str) -> Result<bool, IOError> {
+        let args = set_command(session, condition)?;
+        match self.command(&borrowed(&args)).await? {
+            Reply::Status => Ok(true),
+            Reply::Bulk(None) => Ok(false),
+            reply => Err(unexpected(&reply)),
+        }
+    }
+}
+
+/// A connection held for a sequence of commands. A failed command drops it,
+/// the server then forgets any transaction or watched key along with it.
+struct Connection<'a>(MutexGuard<'a, Option<BufStream<TcpStream>>>);
+
+impl Connection<'_> {
+    async fn call(&mut self, args: &[&[u8]]) -> Result<Reply, IOError> {
+        let stream = self.0.as_mut().ok_or(ErrorKind::NotConnected)?;
+        let reply = call(stream, args).await;
+        if reply.is_err() {
+            *self.0 = None;
+        }
+        reply
+    }
+
+    /// Runs `commands` as one transaction, false when a watched key changed
+    /// and nothing ran
+    async fn exec(&mut self, commands: &[Vec<Vec<u8>>]) -> Result<bool, IOError> {
+        self.call(&[b"MULTI"]).await?;
+        for command in commands {
+            self.call(&borrowed(command)).await?;
+        }
+        match self.call(&[b"EXEC"]).await? {
+            Reply::Array(_) => Ok(true),
+            Reply::Bulk(None) => Ok(false),
+            reply => Err(unexpected(&reply)),
+        }
+    }
+}
+
+fn session_key(id: &str) -> Vec<u8> {
+    format!("{KEY_PREFIX}{id}").into_bytes()
+}
+
+/// `SET` of the session with its expiry, `condition` being `NX` or `XX`
+fn set_command(session: &session::Model, condition: &str) -> Result<Vec<Vec<u8>>, IOError> {
+    let value = serde_json::to_vec(&StoredSession::from(session)).map_err(IOError::other)?;
+    let expires_at = session.expires_at.timestamp_millis().to_string();
+    Ok(vec![
+        b"SET".to_vec(),
+        session_key(&session.id),
+        value,
+        condition.as_bytes().to_vec(),
+        b"PXAT".to_vec(),
+        expires_at.into_bytes(),
+    ])
+}
+
+fn borrowed(args: &[Vec<u8>]) -> Vec<&[u8]> {
+    args.iter().map(Vec::as_slice).collect()
+}
+
+fn unexpected(reply: &Reply) -> IOError {
+    IOError::new(
+        ErrorKind::InvalidData,
+        format!("Unexpected Redis reply {reply:?}"),
+    )
+}
+
+fn parse_session(value: &[u8]) -> Result<session::Model, IOError> {
+    serde_json::from_slice::<StoredSession>(value)
+        .map(Into::into)
+        .map_err(IOError::other)
+}
+
+async fn call(stream: &mut BufStream<TcpStream>, args: &[&[u8]]) -> Result<Reply, IOError> {
+    let mut request = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        request.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        request.extend_from_slice(arg);
+        request.extend_from_slice(b"\r\n");
+    }
+    stream.write_all(&request).await?;
+    stream.flush().await?;
+    read_reply(stream).await
+}
+
+/// A reply, arrays along with their items
+async fn read_reply(stream: &mut BufStream<TcpStream>) -> Result<Reply, IOError> {
+    match read_scalar(stream).await? {
+        Err(len) => {
+            let mut items = Vec::with_capacity(len);
+            for _ in 0..len {
+                // Only `SCAN` nests, one level deep
+                items.push(Box::pin(read_reply(stream)).await?);
+            }
+            Ok(Reply::Array(items))
+        }
+        Ok(reply) => Ok(reply),
+    }
+}
+
+/// A non array reply, or the length of an array
+async fn read_scalar(stream: &mut BufStream<TcpStream>) -> Result<Result<Reply, usize>, IOError> {
+    let mut line = String::new();
+    if stream.read_line(&mut line).await? == 0 {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    let line = line.trim_end_matches("\r\n");
+    let invalid = || {
+        IOError::new(
+            ErrorKind::InvalidData,
+            format!("Invalid Redis reply {line}"),
+        )
+    };
+    let (kind, rest) = line.split_at_checked(1).ok_or_else(invalid)?;
+    let reply = match kind {
+        "+" => Reply::Status,
+        "-" => return Err(IOError::other(format!("Redis error: {rest}"))),
+        ":" => Reply::Integer(rest.parse().map_err(|_| invalid())?),
+        // Aborted transactions reply with a nil array, which is just as nil
+        "$" | "*" if rest == "-1" => Reply::Bulk(None),
+        "$" => {
+            let len: usize = rest.parse().map_err(|_| invalid())?;
+            let mut value = vec![0; len + 2];
+            stream.read_exact(&mut value).await?;
+            value.truncate(len);
+            Reply::Bulk(Some(value))
+        }
+        "*" => return Ok(Err(rest.parse().map_err(|_| invalid())?)),
+        _ => return Err(invalid()),
+    };
+    Ok(Ok(reply))
+}
+
+#[async_trait]
+impl SessionStorage for RespStorage {
+    async fn insert(
+        &self,
+        session: &session::Model,
+        replaces: Option<&str>,
+    ) -> Result<bool, IOError> {
+        let key = session_key(&session.id);
+        let mut connection = self.connection().await?;
+        // Taking the id in between aborts the transaction
+        connection.call(&[b"WATCH", &key]).await?;
+        match connection.call(&[b"EXISTS", &key]).await? {
+            Reply::Integer(0) => {}
+            Reply::Integer(_) => {
+                connection.call(&[b"UNWATCH"]).await?;
+                return Ok(false);
+            }
+            reply => return Err(unexpected(&reply)),
+        }
+        let mut commands = vec![set_command(session, "NX")?];
+        if let Some(old_id) = replaces {
+            commands.push(vec![b"DEL".to_vec(), session_key(old_id)]);
+        }
+        connection.exec(&commands).await
+    }
+
+    async fn update(&self, session: &session::Model) -> Result<(), IOError> {
+        let Some(stored) = self.get(&session.id).await? else {
+            return Err(IOError::other("Session does not exist"));
+        };
+        let session = session::Model {
+            created_at: stored.created_at,
+            ..session.clone()
+        };
+        if !self.set(&session, "XX").await? {
+            return Err(IOError::other("Session does not exist"));
+        }
+        Ok(())
+    }
+
+    async fn swap_data(&self, id: &str, current: &Value, data: Value) -> Result<bool, IOError> {
+        let key = session_key(id);
+        let mut connection = self.connection().await?;
+        // Any write in between the read and the swap aborts the transaction
+        connection.call(&[b"WATCH", &key]).await?;
+        let stored = match connection.call(&[b"GET", &key]).await? {
+            Reply::Bulk(value) => value.as_deref().map(parse_session).transpose(),
+            reply => Err(unexpected(&reply)),
+        };
+        match stored {
+            Ok(Some(stored)) if stored.data == *current => {
+                let swapped = session::Model { data, ..stored };
+                connection.exec(&[set_command(&swapped, "XX")?]).await
+            }
+            stored => {
+                connection.call(&[b"UNWATCH"]).await?;
+                stored.map(|_| false)
+            }
+        }
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<session::Model>, IOError> {
+        let key = format!("{KEY_PREFIX}{id}");
+        match self.command(&[b"GET", key.as_bytes()]).await? {
+            Reply::Bulk(Some(value)) => parse_session(&value).map(Some),
+            Reply::Bulk(None) => Ok(None),
+            reply => Err(unexpected(&reply)),
+        }
+    }
+
+    async fn delete(&self, ids: &[String]) -> Result<u64, IOError> {
+        if ids.is_empty() {
+            return Ok(0);
+        }
+        let keys: Vec<String> = ids.iter().map(|id| format!("{KEY_PREFIX}{id}")).collect();
+        let mut args: Vec<&[u8]> = vec![b"DEL"];
+        args.extend(keys.iter().map(String::as_bytes));
+        match self.command(&args).await? {
+            Reply::Integer(deleted) => Ok(deleted.unsigned_abs()),
+            reply => Err(unexpected(&reply)),
+        }
+    }
+
+    async fn scan(
+        &self,
+        filter: &SessionFilter,
+        after: Option<&str>,
+        limit: u64,
+    ) -> Result<Vec<session::Model>, IOError> {
+        let mut keys = vec![];
+        let mut cursor = b"0".to_vec();
+        loop {
+            let (next, step) = self.scan_step(&cursor).await?;
+            keys.extend(step.into_iter().filter(|key| {
+                let id = key.get(KEY_PREFIX.len()..).unwrap_or_default();
+                after.is_none_or(|after| id > after.as_bytes())
+            }));
+            if next == b"0" {
+                break;
+            }
+            cursor = next;
+        }
+        // Keys may be listed more than once
+        keys.sort();
+        keys.dedup();
+        let limit = usize::try_from(limit).unwrap_or(usize::MAX);
+        let mut sessions = vec![];
+        for chunk in keys.chunks(SCAN_CHUNK) {
+            let mut args: Vec<&[u8]> = vec![b"MGET"];
+            args.extend(chunk.iter().map(Vec::as_slice));
+            let Reply::Array(values) = self.command(&args).await? else {
+                return Err(IOError::new(ErrorKind::InvalidData, "MGET did not list"));
+            };
+            for value in values {
+                // Expired since listed
+                let Reply::Bulk(Some(value)) = value else {
+                    continue;
+                };
+                let session = parse_session(&value)?;
+                if filter.matches(&session) {
+                    sessions.push(session);
+                    if sessions.len() == limit {
+                        return Ok(sessions);
+                    }
+                }
+            }
+        }
+        Ok(sessions)
+    }
+}
@@ -0,0 +1,199 @@
+use std::io::Error as IOError;
+
+use sea_orm::{
+    ActiveValue, ColumnTrait, Condition, ConnectionTrait, Database, DatabaseConnection,
+    EntityTrait, IntoActiveModel, QueryFilter, QueryOrder, QuerySelect, TransactionTrait,
+    prelude::async_trait::async_trait,
+    sea_query::{ColumnDef, Expr, Index, Table},
+};
+use serde_json::Value;
+
+use super::storage::{SessionFilter, SessionStorage};
+use crate::generated::session;
+
+/// Sessions in the `session` table, of the app database or of a `SQLite` file
+#[derive(Clone, Debug)]
+pub struct SeaStorage {
+    db: DatabaseConnection,
+}
+
+impl SeaStorage {
+    /// Over a database migrated by the `migration` crate
+    #[must_use]
+    pub fn new(db: DatabaseConnection) -> Self {
+        Self { db }
+    }
+
+    /// Opens, or creates, a `SQLite` database holding only the sessions
+    pub async fn sqlite(url: &str) -> Result<Self, IOError> {
+        let url = if url.contains('?') || url.contains(":memory:") {
+            url.to_string()
+        } else {
+            format!("{url}?mode=rwc")
+        };
+        let db = Database::connect(url).await.map_err(IOError::other)?;
+        Self::create_table(&db).await?;
+        Ok(Self::new(db))
+    }
+
+    /// The `session` table without the user foreign key, users live elsewhere
+    pub(crate) async fn create_table(db: &DatabaseConnection) -> Result<(), IOError> {
+        let backend = db.get_database_backend();
+        let table = Table::create()
+            .table(session::Entity)
+            .if_not_exists()
+            .col(ColumnDef::new(session::Column::Id).text().primary_key())
+            .col(
+                ColumnDef::new(session::Column::Data)
+                    .json_binary()
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(session::Column::ExpiresAt)
+                    .timestamp_with_time_zone()
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(session::Column::RefreshedAt)
+                    .timestamp_with_time_zone()
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(session::Column::CreatedAt)
+                    .timestamp_with_time_zone()
+                    .not_null(),
+            )
+            .col(ColumnDef::new(session::Column::UserId).uuid())
+            .col(ColumnDef::new(session::Column::IdpSessionId).text())
+            .col(ColumnDef::new(session::Column::Ip).text())
+            .col(ColumnDef::new(session::Column::UserAgent).text())
+            .to_owned();
+        db.execute(backend.build(&table))
+            .await
+            .map_err(IOError::other)?;
+        let index = Index::create()
+            .name("idx_session_user_id")
+            .table(session::Entity)
+            .col(session::Column::UserId)
+            .if_not_exists()
+            .to_owned();
+        db.execute(backend.build(&index))
+            .await
+            .map_err(IOError::other)?;
+        Ok(())
+    }
+}
+
+fn condition(filter: &SessionFilter) -> Condition {
+    let condition = Condition::all();
+    match filter {
+        SessionFilter::All => condition,
+        SessionFilter::User(user_id) => condition.add(session::Column::UserId.eq(*user_id)),
+        SessionFilter::IdpSession(sid) => {
+            condition.add(session::Column::IdpSessionId.eq(sid.clone()))
+        }
+        SessionFilter::ExpiredAt(at) => condition.add(session::Column::ExpiresAt.lte(*at)),
+    }
+}
+
+#[async_trait]
+impl SessionStorage for SeaStorage {
+    async fn insert(
+        &self,
+        session: &session::Model,
+        replaces: Option<&str>,
+    ) -> Result<bool, IOError> {
+        let txn = self.db.begin().await.map_err(IOError::other)?;
+        let taken = session::Entity::find_by_id(session.id.clone())
+            .one(&txn)
+            .await
+            .map_err(IOError::other)?
+            .is_some();
+        if taken {
+            return Ok(false);
+        }
+        if let Some(old_id) = replaces {
+            session::Entity::delete_by_id(old_id)
+                .exec(&txn)
+                .await
+                .map_err(IOError::other)?;
+        }
+        session::Entity::insert(session.clone().into_active_model())
+            .exec(&txn)
+            .await
+            .map_err(IOError::other)?;
+        txn.commit().await.map_err(IOError::other)?;
+        Ok(true)
+    }
+
+    async fn update(&self, session: &session::Model) -> Result<(), IOError> {
+        let session = session.clone();
+        let active = session::ActiveModel {
+            id: ActiveValue::Unchanged(session.id),
+            data: ActiveValue::Set(session.data),
+            expires_at: ActiveValue::Set(session.expires_at),
+            refreshed_at: ActiveValue::Set(session.refreshed_at),
+            created_at: ActiveValue::NotSet,
+            user_id: ActiveValue::Set(session.user_id),
+            idp_session_id: ActiveValue::Set(session.idp_session_id),
+            ip: ActiveValue::Set(session.ip),
+            user_agent: ActiveValue::Set(session.user_agent),
+        };
+        session::Entity::update(active)
+            .exec(&self.db)
+            .await
+            .map_err(IOError::other)?;
+        Ok(())
+    }
+
+    async fn swap_data(&self, id: &str, current: &Value, data: Value) -> Result<bool, IOError> {
+        let result = session::Entity::update_many()
+            .col_expr(session::Column::Data, Expr::value(data))
+            .filter(session::Column::Id.eq(id))
+            .filter(session::Column::Data.eq(current.clone()))
+            .exec(&self.db)
+            .await
+            .map_err(IOError::other)?;
+        Ok(result.rows_affected > 0)
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<session::Model>, IOError> {
+        session::Entity::find_by_id(id)
+            .one(&self.db)
+            .await
+            .map_err(IOError::other)
+    }
+
+    async fn delete(&self, ids: &[String]) -> Result<u64, IOError> {
+        if ids.is_empty() {
+            return Ok(0);
+        }
+        let result = session::Entity::delete_many()
+            .filter(session::Column::Id.is_in(ids.iter().cloned()))
+            .exec(&self.db)
+            .await
+            .map_err(IOError::other)?;
+        Ok(result.rows_affected)
+    }
+
+    async fn scan(
+        &self,
+        filter: &SessionFilter,
+        after: Option<&str>,
+        limit: u64,
+    ) -> Result<Vec<session::Model>, IOError> {
+        let mut query = session::Entity::find()
+            .filter(condition(filter))
+            .order_by_asc(session::Column::Id)
+            // Limits are bound as signed integers
+            .limit(limit.min(i64::MAX.unsigned_abs()));
+        if let Some(after) = after {
+            query = query.filter(session::Column::Id.gt(after));
+        }
+        query.all(&self.db).await.map_err(IOError::other)
+    }
+
+    fn database(&self) -> Option<&DatabaseConnection> {
+        Some(&self.db)
+    }
+}
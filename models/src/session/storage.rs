@@ -0,0 +1,105 @@
+use std::{fmt, io::Error as IOError, str::FromStr, sync::Arc};
+
+use chrono::{DateTime, FixedOffset};
+use sea_orm::{DatabaseConnection, prelude::async_trait::async_trait};
+use serde_json::Value;
+use uuid::Uuid;
+
+use super::{memory::MemoryStorage, resp::RespStorage, sea::SeaStorage};
+use crate::generated::session;
+
+/// Which stored sessions [`SessionStorage::scan`] returns
+#[derive(Clone, Debug)]
+pub enum SessionFilter {
+    All,
+    User(Uuid),
+    IdpSession(String),
+    /// Expired at the given time
+    ExpiredAt(DateTime<FixedOffset>),
+}
+
+impl SessionFilter {
+    #[must_use]
+    pub fn matches(&self, session: &session::Model) -> bool {
+        match self {
+            Self::All => true,
+            Self::User(user_id) => session.user_id == Some(*user_id),
+            Self::IdpSession(sid) => session.idp_session_id.as_ref() == Some(sid),
+            Self::ExpiredAt(at) => session.expires_at <= *at,
+        }
+    }
+}
+
+/// Where sessions are kept, [`super::SessionBackend`] adds caching,
+/// encryption and the account features on top. Whatever the storage, rows
+/// are [`session::Model`]s with their data already encoded.
+#[async_trait]
+pub trait SessionStorage: fmt::Debug + Send + Sync {
+    /// Stores a new session and deletes `replaces` in the same step, nothing
+    /// changes and false is returned when the id is taken
+    async fn insert(
+        &self,
+        session: &session::Model,
+        replaces: Option<&str>,
+    ) -> Result<bool, IOError>;
+
+    /// Overwrites an existing session but its creation time
+    async fn update(&self, session: &session::Model) -> Result<(), IOError>;
+
+    /// Replaces the data of a session unless it is no longer `current`
+    async fn swap_data(&self, id: &str, current: &Value, data: Value) -> Result<bool, IOError>;
+
+    async fn get(&self, id: &str) -> Result<Option<session::Model>, IOError>;
+
+    /// Returns how many of the given sessions existed
+    async fn delete(&self, ids: &[String]) -> Result<u64, IOError>;
+
+    /// Matching sessions ordered by id, at most `limit` of those after `after`
+    async fn scan(
+        &self,
+        filter: &SessionFilter,
+        after: Option<&str>,
+        limit: u64,
+    ) -> Result<Vec<session::Model>, IOError>;
+
+    /// The database behind the storage, for change notifications
+    fn database(&self) -> Option<&DatabaseConnection> {
+        None
+    }
+}
+
+/// `SESSION_STORE_URL`, the app database unless one of `memory:`,
+/// `sqlite://<path>` or `redis://[:<password>@]<host>[:<port>][/<db>]`
+#[derive(Clone, Debug, Default)]
+pub enum SessionStoreConfig {
+    #[default]
+    Database,
+    Memory,
+    Sqlite(String),
+    Redis(String),
+}
+
+impl FromStr for SessionStoreConfig {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.split_once(':').map(|(scheme, _)| scheme) {
+            _ if value.is_empty() || value == "database" => Ok(Self::Database),
+            Some("memory") => Ok(Self::Memory),
+            Some("sqlite") => Ok(Self::Sqlite(value.to_string())),
+            Some("redis") => Ok(Self::Redis(value.to_string())),
+            _ => Err(format!("Unsupported session store {value}")),
+        }
+    }
+}
+
+impl SessionStoreConfig {
+    pub async fn open(&self, db: &DatabaseConnection) -> Result<Arc<dyn SessionStorage>, IOError> {
+        Ok(match self {
+            Self::Database => Arc::new(SeaStorage::new(db.clone())),
+            Self::Memory => Arc::new(MemoryStorage::default()),
+            Self::Sqlite(url) => Arc::new(SeaStorage::sqlite(url).await?),
+            Self::Redis(url) => Arc::new(RespStorage::new(url)?),
+        })
+    }
+}
@@ -0,0 +1,116 @@
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::Value;
+use tower_sessions::{Session, session::Error as SessionError};
+
+/// Typed session state, stored under its own key along with its version.
+/// Data of another version, or which no longer deserializes, reads as absent
+/// and is dropped from the session.
+pub trait SessionValue: Serialize + DeserializeOwned + Send + Sync {
+    /// Session key as `<namespace>.<name>`, e.g. `auth.tokens`
+    const KEY: &'static str;
+    /// Bumped on incompatible changes to the stored form
+    const VERSION: u32 = 1;
+}
+
+#[derive(Serialize, Deserialize)]
+struct Versioned<T> {
+    version: u32,
+    value: T,
+}
+
+/// The stored form of `value`
+pub fn encode<T: SessionValue>(value: &T) -> serde_json::Result<Value> {
+    serde_json::to_value(Versioned {
+        version: T::VERSION,
+        value,
+    })
+}
+
+/// `None` for data of another version or shape
+#[must_use]
+pub fn decode<T: SessionValue>(stored: Value) -> Option<T> {
+    let stored: Versioned<Value> = serde_json::from_value(stored).ok()?;
+    if stored.version != T::VERSION {
+        return None;
+    }
+    serde_json::from_value(stored.value).ok()
+}
+
+/// The `T` in the session, stale data is removed
+pub async fn read<T: SessionValue>(session: &Session) -> Option<T> {
+    let stored = session.get_value(T::KEY).await.ok().flatten()?;
+    let value = decode(stored);
+    if value.is_none() {
+        log::debug!("Dropping stale session value {}", T::KEY);
+        session.remove_value(T::KEY).await.ok();
+    }
+    value
+}
+
+/// Stores `value`, replacing any other version
+pub async fn write<T: SessionValue>(session: &Session, value: &T) -> Result<(), SessionError> {
+    session.insert_value(T::KEY, encode(value)?).await?;
+    Ok(())
+}
+
+/// Removes the `T` from the session, returning it unless stale
+pub async fn take<T: SessionValue>(session: &Session) -> Option<T> {
+    session
+        .remove_value(T::KEY)
+        .await
+        .ok()
+        .flatten()
+        .and_then(decode)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use googletest::prelude::*;
+    use serde_json::json;
+
+    use super::*;
+    use crate::session::{MemoryStorage, SessionBackend};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Attempt {
+        csrf: String,
+    }
+
+    impl SessionValue for Attempt {
+        const KEY: &'static str = "test.attempt";
+        const VERSION: u32 = 2;
+    }
+
+    fn session() -> Session {
+        let backend = SessionBackend::new(Arc::new(MemoryStorage::default()));
+        Session::new(None, Arc::new(backend), None)
+    }
+
+    #[gtest]
+    #[tokio::test]
+    async fn values_round_trip() {
+        let session = session();
+        let attempt = Attempt {
+            csrf: "state".to_string(),
+        };
+        write(&session, &attempt).await.unwrap();
+        expect_eq!(read::<Attempt>(&session).await, Some(attempt));
+        expect_that!(take::<Attempt>(&session).await, some(anything()));
+        expect_that!(read::<Attempt>(&session).await, none());
+    }
+
+    #[rstest::rstest]
+    #[case::older_version(json!({ "version": 1, "value": { "csrf": "state" } }))]
+    #[case::changed_shape(json!({ "version": 2, "value": { "state": "state" } }))]
+    #[case::unversioned(json!({ "csrf": "state" }))]
+    #[gtest]
+    #[tokio::test]
+    async fn stale_values_are_dropped(#[case] stored: Value) {
+        let session = session();
+        session.insert_value(Attempt::KEY, stored).await.unwrap();
+        expect_that!(read::<Attempt>(&session).await, none());
+        expect_that!(session.get_value(Attempt::KEY).await.unwrap(), none());
+    }
+}
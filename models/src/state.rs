@@ -1,17 +1,39 @@
 use reqwest::Url;
 use sea_orm::DatabaseConnection;
+use std::time::Duration;
 
-use crate::oauth::OpenIdConfiguration;
+use crate::{
+    provider::ProviderRegistry,
+    session::{SessionBackend, SessionKeyring, SessionLifetimes, SessionStoreConfig},
+};
 
 #[derive(Clone, Debug)]
 pub struct AppConfig {
     pub db_url: String,
     pub port: u16,
-    pub oauth: OpenIdConfiguration,
-    pub oauth_client_id: String,
-    pub oauth_autodiscover_url: String,
-    pub oauth_client_secret: String,
+    pub providers: ProviderRegistry,
     pub self_url: Url,
+    /// How often discovery documents and signing keys are re-fetched
+    pub discovery_refresh: Duration,
+    /// Seconds since the last sign in after which destructive actions ask
+    /// for a new one
+    pub reauth_max_age: u64,
+    /// Sessions cached in memory, zero disables the cache
+    pub session_cache_size: usize,
+    /// How long a cached session is trusted without reading it again
+    pub session_cache_ttl: Duration,
+    /// Expiry extension below which unchanged sessions are not written back
+    pub session_refresh_threshold: Duration,
+    /// Keys session data is encrypted with, stored in plaintext without
+    pub session_keys: Option<SessionKeyring>,
+    /// How often expired sessions are deleted
+    pub session_reap_interval: Duration,
+    /// Expired sessions deleted per statement
+    pub session_reap_batch_size: u64,
+    /// Where sessions are kept, the app database by default
+    pub session_store: SessionStoreConfig,
+    /// Idle and absolute limits of sessions
+    pub session_lifetimes: SessionLifetimes,
 }
 
 #[derive(Clone, Debug)]
@@ -19,4 +41,5 @@ pub struct AppState {
     pub db: DatabaseConnection,
     pub requests: reqwest::Client,
     pub config: AppConfig,
+    pub sessions: SessionBackend,
 }
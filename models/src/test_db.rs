@@ -0,0 +1,37 @@
+//! `SQLite` stand-ins for the Postgres tables, as much of them as the tests
+//! need
+
+use sea_orm::{ConnectionTrait, Database, DatabaseConnection};
+
+const SCHEMA: &str = r#"
+    CREATE TABLE "user" (
+        id BLOB PRIMARY KEY,
+        email TEXT NOT NULL UNIQUE,
+        name TEXT NOT NULL,
+        _created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ')),
+        _updated_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ')),
+        given_name TEXT,
+        family_name TEXT,
+        preferred_username TEXT,
+        picture TEXT,
+        locale TEXT,
+        role TEXT NOT NULL,
+        email_verified BOOLEAN NOT NULL DEFAULT FALSE
+    );
+    CREATE TABLE identity (
+        id BLOB PRIMARY KEY,
+        provider TEXT NOT NULL,
+        subject TEXT NOT NULL,
+        user_id BLOB NOT NULL REFERENCES "user" (id),
+        email TEXT,
+        _created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ')),
+        UNIQUE (provider, subject)
+    );
+"#;
+
+/// A fresh in-memory database holding users and their identities
+pub(crate) async fn database() -> DatabaseConnection {
+    let db = Database::connect("sqlite::memory:").await.unwrap();
+    db.execute_unprepared(SCHEMA).await.unwrap();
+    db
+}
@@ -1,21 +1,46 @@
-use std::io::Error as IOError;
+use sea_orm::{DatabaseConnection, TransactionTrait};
+use std::io::{Error as IOError, ErrorKind};
 
 use crate::{
     generated::user,
-    oauth::{TokenResponse, UserInfo},
-    repositories::user::{get_user_by_sub, upsert_user},
+    oauth::{Claims, LogoutTokenClaims, TokenResponse, UserInfo},
+    provider::OAuthProvider,
+    repositories::{
+        identity::{get_identity, get_user_by_identity, insert_identity},
+        user::{get_user_by_email, insert_user, sync_profile},
+    },
+    role::Role,
+    session::provider_session_id,
     state::AppState,
 };
 
-pub async fn authenticate(
+/// Userinfo of the ID token subject
+async fn fetch_user_info(
     state: &AppState,
-    creds: TokenResponse,
-) -> Result<Option<user::Model>, IOError> {
-    log::debug!("Authenticating user with credentials");
+    provider: &OAuthProvider,
+    creds: &TokenResponse,
+    claims: &Claims,
+) -> Result<UserInfo, IOError> {
+    let resp = request_user_info(state, provider, creds).await?;
+    if resp.sub != claims.sub {
+        return Err(IOError::new(
+            ErrorKind::InvalidData,
+            "Userinfo subject does not match the ID token",
+        ));
+    }
+    Ok(resp)
+}
+
+async fn request_user_info(
+    state: &AppState,
+    provider: &OAuthProvider,
+    creds: &TokenResponse,
+) -> Result<UserInfo, IOError> {
+    let oauth = provider.oauth().await?;
     let resp = state
         .requests
-        .get(&state.config.oauth.userinfo_endpoint)
-        .bearer_auth(creds.access_token)
+        .get(&oauth.userinfo_endpoint)
+        .bearer_auth(&creds.access_token)
         .send()
         .await
         .map_err(IOError::other)?
@@ -23,13 +48,256 @@ pub async fn authenticate(
         .await
         .map_err(IOError::other)?;
     log::debug!("User data fetched successfully");
+    Ok(resp)
+}
 
-    if let Ok(Some(user)) = get_user_by_sub(&state.db, &resp.sub).await {
+/// Resolves the local user behind a provider login, see [`resolve_user`]
+pub async fn authenticate(
+    state: &AppState,
+    provider: &OAuthProvider,
+    creds: &TokenResponse,
+    claims: &Claims,
+) -> Result<Option<user::Model>, IOError> {
+    log::debug!("Authenticating user with credentials");
+    let resp = fetch_user_info(state, provider, creds, claims).await?;
+    let role = provider
+        .roles
+        .resolve(&[&claims.extra, &resp.extra])
+        .ok_or_else(|| {
+            IOError::new(
+                ErrorKind::PermissionDenied,
+                "Your account has no role in this application",
+            )
+        })?;
+    resolve_user(&state.db, &provider.name, &resp, role)
+        .await
+        .map(Some)
+}
+
+/// The user owning the `provider` identity of `info`, its profile and role
+/// refreshed. Unknown identities are linked to the user with the same email
+/// only when both the provider and that user verified it, otherwise someone
+/// registering the email unverified could take over the account of its owner.
+/// Without such a user a new one is created.
+async fn resolve_user(
+    db: &DatabaseConnection,
+    provider: &str,
+    info: &UserInfo,
+    role: Role,
+) -> Result<user::Model, IOError> {
+    if let Some(user) = get_user_by_identity(db, provider, &info.sub).await? {
         log::debug!("User found in database");
-        return Ok(Some(user));
+        return sync_user_profile(db, user, info, role).await;
+    }
+    if let Some(user) = get_user_by_email(db, &info.email).await? {
+        if !info.has_verified_email() || !user.email_verified {
+            return Err(IOError::new(
+                ErrorKind::PermissionDenied,
+                "Email already registered and not verified on both sides, link it from settings",
+            ));
+        }
+        log::info!(
+            "Linking {provider} identity to existing user {} by verified email",
+            user.id
+        );
+        insert_identity(db, &user, provider, &info.sub, Some(info.email.clone())).await?;
+        return sync_user_profile(db, user, info, role).await;
+    }
+    let txn = db.begin().await.map_err(IOError::other)?;
+    let user = insert_user(&txn, info, role)
+        .await
+        .inspect_err(|err| log::error!("Failed to insert user: {err}"))?;
+    insert_identity(&txn, &user, provider, &info.sub, Some(info.email.clone())).await?;
+    txn.commit().await.map_err(IOError::other)?;
+    Ok(user)
+}
+
+/// Brings the signed in `user` up to date with the provider after a token
+/// refresh, so roles revoked there do not outlive the access token. `claims`
+/// are those of the refreshed ID token, when the provider sent one. `None`
+/// when the user no longer has any role.
+pub async fn refresh_user(
+    state: &AppState,
+    provider: &OAuthProvider,
+    creds: &TokenResponse,
+    claims: Option<&Claims>,
+    user: &user::Model,
+) -> Result<Option<user::Model>, IOError> {
+    let resp = request_user_info(state, provider, creds).await?;
+    if claims.is_some_and(|claims| claims.sub != resp.sub) {
+        return Err(IOError::new(
+            ErrorKind::InvalidData,
+            "Userinfo subject does not match the ID token",
+        ));
     }
-    let user = upsert_user(&state.db, resp)
+    let stored = get_user_by_identity(&state.db, &provider.name, &resp.sub).await?;
+    let Some(stored) = stored.filter(|stored| stored.id == user.id) else {
+        return Err(IOError::new(
+            ErrorKind::PermissionDenied,
+            "Refreshed tokens are not those of the signed in user",
+        ));
+    };
+    let no_claims = serde_json::Map::new();
+    let id_claims = claims.map_or(&no_claims, |claims| &claims.extra);
+    let Some(role) = provider.roles.resolve(&[id_claims, &resp.extra]) else {
+        return Ok(None);
+    };
+    sync_user_profile(&state.db, stored, &resp, role)
         .await
-        .inspect_err(|err| log::error!("Failed to upsert user: {err}"))?;
-    Ok(Some(user))
+        .map(Some)
+}
+
+async fn sync_user_profile(
+    db: &DatabaseConnection,
+    user: user::Model,
+    info: &UserInfo,
+    role: Role,
+) -> Result<user::Model, IOError> {
+    let (user, changed) = sync_profile(db, user, info, role).await?;
+    if !changed.is_empty() {
+        log::info!(
+            "Profile of user {} updated from provider claims: {}",
+            user.id,
+            changed.join(", ")
+        );
+    }
+    Ok(user)
+}
+
+/// Links a provider login to an already signed in user, an identity already
+/// owned by someone else is refused.
+pub async fn link_identity(
+    state: &AppState,
+    provider: &OAuthProvider,
+    creds: &TokenResponse,
+    claims: &Claims,
+    user: &user::Model,
+) -> Result<(), IOError> {
+    let resp = fetch_user_info(state, provider, creds, claims).await?;
+    match get_identity(&state.db, &provider.name, &resp.sub).await? {
+        Some(identity) if identity.user_id == user.id => Ok(()),
+        Some(_) => Err(IOError::new(
+            ErrorKind::AlreadyExists,
+            "This login is already linked to another user",
+        )),
+        None => {
+            log::info!("Linking {} identity to user {}", provider.name, user.id);
+            insert_identity(&state.db, user, &provider.name, &resp.sub, Some(resp.email))
+                .await
+                .map(|_| ())
+        }
+    }
+}
+
+/// Terminates the local sessions named by a back-channel logout token, by
+/// provider session when `sid` is present, otherwise every session of `sub`.
+pub async fn end_sessions(
+    state: &AppState,
+    provider: &OAuthProvider,
+    claims: &LogoutTokenClaims,
+) -> Result<u64, IOError> {
+    if let Some(sid) = &claims.sid {
+        let idp_session_id = provider_session_id(&provider.name, sid);
+        return state.sessions.delete_by_idp_session(&idp_session_id).await;
+    }
+    let Some(sub) = &claims.sub else {
+        return Err(IOError::new(
+            ErrorKind::InvalidInput,
+            "Logout token without sid nor sub",
+        ));
+    };
+    match get_user_by_identity(&state.db, &provider.name, sub).await? {
+        Some(user) => state.sessions.delete_by_user(user.id).await,
+        None => Ok(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use googletest::prelude::*;
+    use rstest::rstest;
+    use sea_orm::{ActiveModelTrait, ActiveValue, EntityTrait};
+
+    use super::*;
+    use crate::{generated::identity, test_db};
+
+    fn user_info(sub: &str, name: &str, email_verified: bool) -> UserInfo {
+        serde_json::from_value(serde_json::json!({
+            "sub": sub,
+            "name": name,
+            "email": "freddie@example.com",
+            "email_verified": email_verified,
+        }))
+        .unwrap()
+    }
+
+    #[gtest]
+    #[tokio::test]
+    async fn known_identities_get_their_user() {
+        let db = test_db::database().await;
+        let info = user_info("freddie", "Freddie", false);
+        let user = resolve_user(&db, "keycloak", &info, Role::Reader)
+            .await
+            .unwrap();
+
+        let renamed = user_info("freddie", "Farrokh", false);
+        let again = resolve_user(&db, "keycloak", &renamed, Role::Editor).await;
+        expect_that!(
+            again,
+            ok(all![
+                field!(user::Model.id, eq(&user.id)),
+                field!(user::Model.name, eq("Farrokh")),
+                field!(user::Model.role, eq(&Role::Editor)),
+            ])
+        );
+    }
+
+    #[gtest]
+    #[tokio::test]
+    async fn verified_emails_link_to_verified_users() {
+        let db = test_db::database().await;
+        let info = user_info("freddie", "Freddie", true);
+        let user = resolve_user(&db, "keycloak", &info, Role::Reader)
+            .await
+            .unwrap();
+        expect_true!(user.email_verified);
+
+        let other = user_info("f.mercury", "Freddie", true);
+        let linked = resolve_user(&db, "google", &other, Role::Reader).await;
+        expect_that!(linked, ok(field!(user::Model.id, eq(&user.id))));
+        let identities = identity::Entity::find().all(&db).await.unwrap();
+        expect_eq!(identities.len(), 2);
+    }
+
+    #[rstest]
+    #[case::unverified_login(true, false)]
+    #[case::unverified_user(false, true)]
+    #[gtest]
+    #[tokio::test]
+    async fn unverified_emails_are_not_linked(
+        #[case] user_verified: bool,
+        #[case] login_verified: bool,
+    ) {
+        let db = test_db::database().await;
+        let info = user_info("freddie", "Freddie", true);
+        let user = resolve_user(&db, "keycloak", &info, Role::Reader)
+            .await
+            .unwrap();
+        user::ActiveModel {
+            email_verified: ActiveValue::Set(user_verified),
+            ..user.into()
+        }
+        .update(&db)
+        .await
+        .unwrap();
+
+        let other = user_info("f.mercury", "Freddie", login_verified);
+        let linked = resolve_user(&db, "google", &other, Role::Reader).await;
+        expect_eq!(
+            linked.err().map(|err| err.kind()),
+            Some(ErrorKind::PermissionDenied)
+        );
+        let identities = identity::Entity::find().all(&db).await.unwrap();
+        expect_eq!(identities.len(), 1);
+    }
 }
@@ -1,132 +1,914 @@
-use models::oauth::{OAUTH_CALLBACK_ENDPOINT, TokenExchangePayload};
+use jsonwebtoken::{
+    Algorithm, DecodingKey, Validation, decode, decode_header,
+    jwk::{Jwk, JwkSet},
+};
+use models::oauth::{
+    AccessTokenClaims, BACKCHANNEL_LOGOUT_EVENT, Claims, IntrospectionPayload,
+    IntrospectionResponse, LogoutTokenClaims, OAUTH_CALLBACK_ENDPOINT, RefreshPayload,
+    TokenExchangePayload,
+};
 use models::{
     oauth::{AuthRedirectQuery, AuthorizationParams, OpenIdConfiguration, TokenResponse},
+    provider::{AccessTokenValidation, OAuthProvider},
     state::{AppConfig, AppState},
 };
-use reqwest::Url;
-use serde::Serialize;
+use reqwest::{Client, RequestBuilder, Url, header::CONTENT_TYPE};
+use serde::{Serialize, de::DeserializeOwned};
 use std::error::Error;
-use std::io::Error as IOError;
+use std::io::{Error as IOError, ErrorKind};
+use std::time::Duration;
 
 pub fn generate_auth_url(
     params: AuthorizationParams,
-    config: &AppConfig,
+    oauth: &OpenIdConfiguration,
 ) -> Result<String, IOError> {
     let encoded_params = serde_urlencoded::to_string(params).map_err(IOError::other)?;
-    let mut url = Url::parse(&config.oauth.authorization_endpoint).map_err(IOError::other)?;
+    let mut url = Url::parse(&oauth.authorization_endpoint).map_err(IOError::other)?;
     url.set_query(Some(&encoded_params));
     Ok(url.to_string())
 }
 
-pub async fn load_openid_config(url: &str) -> OpenIdConfiguration {
+pub async fn load_openid_config(
+    request: &Client,
+    url: &str,
+) -> Result<OpenIdConfiguration, IOError> {
     let trimmed = url.strip_suffix('/').unwrap_or(url);
     let url = format!("{trimmed}/.well-known/openid-configuration");
-    log::info!("Loading OpenID configuration from {url}");
-    let issuer_url = Url::parse(&url).expect("Invalid oauth config URL");
-    log::info!("Fetching oauth config at {issuer_url}");
-    reqwest::get(issuer_url)
+    log::info!("Fetching OIDC configuration at {url}");
+    request
+        .get(url)
+        .send()
         .await
-        .expect("Failed to fetch oauth config URL")
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(IOError::other)?
         .json::<OpenIdConfiguration>()
         .await
-        .expect("Failed to deserialized oauth response")
+        .map_err(IOError::other)
+}
+
+/// Discovers the provider configuration and signing keys, retrying with
+/// backoff until it succeeds and refreshing both every `refresh` afterwards.
+pub async fn keep_discovered(request: Client, provider: OAuthProvider, refresh: Duration) {
+    loop {
+        let result = match load_openid_config(&request, &provider.autodiscover_url).await {
+            Ok(oauth) => fetch_remote_jwks(&request, &oauth)
+                .await
+                .map(|keys| (oauth, keys)),
+            Err(err) => Err(err),
+        };
+        let wait = match result {
+            Ok((oauth, keys)) => {
+                log::info!("Discovered provider {}", provider.name);
+                provider.discovery.succeeded(oauth).await;
+                provider.jwks.replace(keys).await;
+                refresh
+            }
+            Err(err) => {
+                let wait = provider.discovery.failed(err.to_string()).await;
+                log::warn!(
+                    "Discovery of provider {} failed, retrying in {wait:?}: {err}",
+                    provider.name
+                );
+                wait
+            }
+        };
+        tokio::time::sleep(wait).await;
+    }
 }
 
 #[must_use]
 pub fn from_redirect_to_token_payload(
     config: &AppConfig,
+    provider: &OAuthProvider,
     value: AuthRedirectQuery,
     pkce: String,
 ) -> TokenExchangePayload {
     TokenExchangePayload {
         code: value.code,
-        client_id: config.oauth_client_id.clone(),
-        client_secret: config.oauth_client_secret.clone(),
+        client_id: provider.client_id.clone(),
         code_verifier: pkce,
         grant_type: "authorization_code".to_string(),
-        redirect_uri: build_redirect_url(config),
+        redirect_uri: build_redirect_url(config, provider),
     }
 }
 
+/// Posts a grant to the token endpoint, a rejection by the provider (e.g. an
+/// expired refresh token) is reported as [`ErrorKind::PermissionDenied`].
 pub async fn exchange_token<T: Serialize>(
     state: &AppState,
+    provider: &OAuthProvider,
     payload: &T,
-) -> Result<TokenResponse, Box<dyn std::error::Error>> {
-    let client_id = state.config.oauth_client_id.clone();
-    let client_secret = state.config.oauth_client_secret.clone();
-    let response = state
-        .requests
-        .post(&state.config.oauth.token_endpoint)
-        .form(&payload)
-        .basic_auth(client_id, Some(client_secret))
+) -> Result<TokenResponse, IOError> {
+    let oauth = provider.oauth().await?;
+    let response = client_request(state, provider, &oauth, &oauth.token_endpoint, payload)?
         .send()
         .await
-        .map_err(Box::new)?;
-    response.json::<TokenResponse>().await.map_err(|x| {
-        let err = x.source();
-        log::error!("Failed to deserialize token response: {err:?}");
-        Box::new(x) as Box<dyn std::error::Error>
-    })
+        .map_err(IOError::other)?;
+    let status = response.status();
+    if status.is_client_error() {
+        let body = response.text().await.unwrap_or_default();
+        log::warn!("Token endpoint rejected the grant ({status}): {body}");
+        return Err(IOError::new(ErrorKind::PermissionDenied, body));
+    }
+    response
+        .error_for_status()
+        .map_err(IOError::other)?
+        .json::<TokenResponse>()
+        .await
+        .map_err(|x| {
+            let err = x.source();
+            log::error!("Failed to deserialize token response: {err:?}");
+            IOError::other(x)
+        })
+}
+
+/// Form POST to one of the provider endpoints, authenticated as the client
+/// with the method both sides support.
+fn client_request<T: Serialize>(
+    state: &AppState,
+    provider: &OAuthProvider,
+    oauth: &OpenIdConfiguration,
+    endpoint: &str,
+    payload: &T,
+) -> Result<RequestBuilder, IOError> {
+    let auth = provider.credentials.authenticate(
+        &provider.client_id,
+        &oauth.token_endpoint_auth_methods_supported,
+        endpoint,
+    )?;
+    let mut body = serde_urlencoded::to_string(payload).map_err(IOError::other)?;
+    if !auth.form.is_empty() {
+        body.push('&');
+        body.push_str(&serde_urlencoded::to_string(&auth.form).map_err(IOError::other)?);
+    }
+    let mut request = state
+        .requests
+        .post(endpoint)
+        .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .body(body);
+    if let Some((id, secret)) = auth.basic {
+        request = request.basic_auth(id, Some(secret));
+    }
+    Ok(request)
 }
 
 #[must_use]
-pub fn build_redirect_url(config: &AppConfig) -> String {
+pub fn build_redirect_url(config: &AppConfig, provider: &OAuthProvider) -> String {
     let mut redirect_url = config.self_url.clone();
-    redirect_url.set_path(OAUTH_CALLBACK_ENDPOINT);
+    redirect_url.set_path(&provider.path(OAUTH_CALLBACK_ENDPOINT));
     redirect_url.to_string()
 }
 
-// pub(crate) fn from_refresh_to_token_payload(config: &AppConfig, token: String) -> RefreshPayload {
-//     RefreshPayload {
-//         refresh_token: token,
-//         client_id: config.oauth_client_id.clone(),
-//         client_secret: config.oauth_client_secret.clone(),
-//         grant_type: "refresh_token".to_string(),
-//         redirect_uri: build_redirect_url(config),
-//     }
-// }
-
-// #[must_use]
-// pub fn generate_logout_url(config: &AppConfig) -> String {
-//     let Ok(mut end_session_url) = Url::parse(&config.oauth.end_session_endpoint) else {
-//         return "Failed to generate logout URL".to_string();
-//     };
-//     let mut logout_params = HashMap::new();
-//     logout_params.insert("client_id", config.oauth_client_id.clone());
-//     end_session_url.set_query(serde_urlencoded::to_string(logout_params).ok().as_deref());
-//     end_session_url.to_string()
-// }
-
-// # Panics
-// if cant get the JWKS
-// pub async fn fetch_remote_jwk(request: &Client, config: &OpenIdConfiguration) -> DecodingKey {
-//     log::info!("Fetching JWKS remotely");
-//     let resp = request
-//         .get(&config.jwks_uri)
-//         .send()
-//         .await
-//         .expect("Failed to rearch clerk, invalid URL?")
-//         .json::<JwkSet>()
-//         .await
-//         .expect("Failed to deserialize JWKS response");
-//     log::info!("Fetched JWKS successfully");
-//     let jwk = resp
-//         .keys
-//         .iter()
-//         .find(|&x| is_sig_key(x))
-//         .expect("JWKS without any sig keys?!?!");
-//     DecodingKey::from_jwk(jwk).unwrap()
-// }
-
-// fn build_validation() -> Validation {
-//     let mut val = Validation::new(Algorithm::RS256);
-//     val.validate_aud = false;
-//     val
-// }
-
-// fn is_sig_key(key: &Jwk) -> bool {
-//     key.common
-//         .public_key_use
-//         .as_ref()
-//         .is_some_and(|k| *k == PublicKeyUse::Signature)
-// }
+#[must_use]
+pub(crate) fn from_refresh_to_token_payload(
+    config: &AppConfig,
+    provider: &OAuthProvider,
+    token: String,
+) -> RefreshPayload {
+    RefreshPayload {
+        refresh_token: token,
+        client_id: provider.client_id.clone(),
+        grant_type: "refresh_token".to_string(),
+        redirect_uri: build_redirect_url(config, provider),
+    }
+}
+
+/// RP-initiated logout URL, ending the provider session as well and coming
+/// back to our home page afterwards.
+pub async fn generate_logout_url(
+    config: &AppConfig,
+    provider: &OAuthProvider,
+    id_token: Option<&str>,
+) -> Result<String, IOError> {
+    let oauth = provider.oauth().await?;
+    let mut end_session_url = Url::parse(&oauth.end_session_endpoint).map_err(IOError::other)?;
+    let mut logout_params = vec![
+        ("client_id", provider.client_id.as_str()),
+        ("post_logout_redirect_uri", config.self_url.as_str()),
+    ];
+    if let Some(id_token) = id_token {
+        logout_params.push(("id_token_hint", id_token));
+    }
+    let encoded_params = serde_urlencoded::to_string(logout_params).map_err(IOError::other)?;
+    end_session_url.set_query(Some(&encoded_params));
+    Ok(end_session_url.to_string())
+}
+
+pub async fn fetch_remote_jwks(
+    request: &Client,
+    config: &OpenIdConfiguration,
+) -> Result<JwkSet, IOError> {
+    log::info!("Fetching JWKS remotely");
+    let keys = request
+        .get(&config.jwks_uri)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(IOError::other)?
+        .json::<JwkSet>()
+        .await
+        .map_err(IOError::other)?;
+    log::info!("Fetched JWKS successfully, {} keys", keys.keys.len());
+    Ok(keys)
+}
+
+/// Looks up the signing key in the cache, re-fetching the remote set once when
+/// the `kid` is unknown so a key rotation at the provider goes unnoticed.
+async fn find_decoding_key(
+    state: &AppState,
+    provider: &OAuthProvider,
+    kid: Option<&str>,
+) -> Result<Jwk, IOError> {
+    if let Some(jwk) = provider.jwks.find(kid).await {
+        return Ok(jwk);
+    }
+    if !provider.jwks.should_refetch().await {
+        return Err(IOError::new(
+            ErrorKind::NotFound,
+            format!("Unknown signing key {kid:?}, JWKS recently refreshed"),
+        ));
+    }
+    log::info!("Signing key {kid:?} not cached, refreshing JWKS");
+    let keys = fetch_remote_jwks(&state.requests, &*provider.oauth().await?).await?;
+    provider.jwks.replace(keys).await;
+    provider
+        .jwks
+        .find(kid)
+        .await
+        .ok_or_else(|| IOError::new(ErrorKind::NotFound, format!("Unknown signing key {kid:?}")))
+}
+
+fn build_validation(alg: Algorithm, issuer: &str, audience: &str) -> Validation {
+    let mut val = Validation::new(alg);
+    val.set_issuer(&[issuer]);
+    val.set_audience(&[audience]);
+    val.set_required_spec_claims(&["exp", "iss", "aud"]);
+    val
+}
+
+/// Verifies a JWT issued by the provider for `audience`, checking signature
+/// against the JWKS along with `iss`, `aud` and `exp`.
+async fn verify_jwt<T: DeserializeOwned>(
+    state: &AppState,
+    provider: &OAuthProvider,
+    token: &str,
+    audience: &str,
+) -> Result<T, IOError> {
+    let header = decode_header(token).map_err(|err| IOError::new(ErrorKind::InvalidData, err))?;
+    let jwk = find_decoding_key(state, provider, header.kid.as_deref()).await?;
+    if let Some(key_alg) = jwk.common.key_algorithm
+        && key_alg.to_string().parse::<Algorithm>().ok() != Some(header.alg)
+    {
+        return Err(IOError::new(
+            ErrorKind::InvalidData,
+            "Token algorithm does not match signing key",
+        ));
+    }
+    let key = DecodingKey::from_jwk(&jwk).map_err(IOError::other)?;
+    let issuer = provider.oauth().await?.issuer.clone();
+    let validation = build_validation(header.alg, &issuer, audience);
+    decode::<T>(token, &key, &validation)
+        .map(|data| data.claims)
+        .map_err(|err| IOError::new(ErrorKind::InvalidData, err))
+}
+
+/// Verifies the ID token and checks the `nonce` bound to this login attempt.
+pub async fn verify_id_token(
+    state: &AppState,
+    provider: &OAuthProvider,
+    id_token: &str,
+    nonce: &str,
+) -> Result<Claims, IOError> {
+    let claims = verify_jwt::<Claims>(state, provider, id_token, &provider.client_id).await?;
+    if claims.nonce.as_deref() != Some(nonce) {
+        return Err(IOError::new(
+            ErrorKind::InvalidData,
+            "ID token nonce does not match login attempt",
+        ));
+    }
+    Ok(claims)
+}
+
+/// Verifies an ID token from a refresh response, which carries no new nonce
+pub async fn verify_refreshed_id_token(
+    state: &AppState,
+    provider: &OAuthProvider,
+    id_token: &str,
+) -> Result<Claims, IOError> {
+    verify_jwt::<Claims>(state, provider, id_token, &provider.client_id).await
+}
+
+/// Verifies a back-channel `logout_token` as specified by OIDC
+/// Back-Channel Logout 1.0, section 2.6.
+pub async fn verify_logout_token(
+    state: &AppState,
+    provider: &OAuthProvider,
+    logout_token: &str,
+) -> Result<LogoutTokenClaims, IOError> {
+    let claims =
+        verify_jwt::<LogoutTokenClaims>(state, provider, logout_token, &provider.client_id).await?;
+    if !claims.events.contains_key(BACKCHANNEL_LOGOUT_EVENT) {
+        return Err(IOError::new(
+            ErrorKind::InvalidData,
+            "Logout token without the back-channel logout event",
+        ));
+    }
+    if claims.nonce.is_some() {
+        return Err(IOError::new(
+            ErrorKind::InvalidData,
+            "Logout token must not carry a nonce",
+        ));
+    }
+    if claims.sid.is_none() && claims.sub.is_none() {
+        return Err(IOError::new(
+            ErrorKind::InvalidData,
+            "Logout token without sid nor sub",
+        ));
+    }
+    Ok(claims)
+}
+
+/// Issuer claimed by a JWT, read without any verification to pick the
+/// provider which must then verify it. `None` for opaque tokens.
+#[must_use]
+pub fn unverified_issuer(token: &str) -> Option<String> {
+    #[derive(serde::Deserialize)]
+    struct Issuer {
+        iss: String,
+    }
+    let mut validation = Validation::default();
+    validation.insecure_disable_signature_validation();
+    validation.validate_exp = false;
+    validation.validate_aud = false;
+    validation.required_spec_claims.clear();
+    decode::<Issuer>(token, &DecodingKey::from_secret(&[]), &validation)
+        .ok()
+        .map(|data| data.claims.iss)
+}
+
+/// Validates an API bearer token the way the provider is configured to and
+/// returns its subject.
+pub async fn verify_access_token(
+    state: &AppState,
+    provider: &OAuthProvider,
+    token: &str,
+) -> Result<String, IOError> {
+    match provider.access_tokens {
+        AccessTokenValidation::Jwt => {
+            let claims =
+                verify_jwt::<AccessTokenClaims>(state, provider, token, &provider.api_audience)
+                    .await?;
+            if claims.typ.as_deref() == Some("ID") {
+                return Err(IOError::new(
+                    ErrorKind::InvalidData,
+                    "ID tokens are not access tokens",
+                ));
+            }
+            Ok(claims.sub)
+        }
+        AccessTokenValidation::Introspection => introspect_token(state, provider, token).await,
+    }
+}
+
+/// RFC 7662 introspection, inactive tokens are [`ErrorKind::PermissionDenied`].
+async fn introspect_token(
+    state: &AppState,
+    provider: &OAuthProvider,
+    token: &str,
+) -> Result<String, IOError> {
+    let oauth = provider.oauth().await?;
+    let Some(endpoint) = &oauth.introspection_endpoint else {
+        return Err(IOError::new(
+            ErrorKind::Unsupported,
+            format!("Provider {} has no introspection endpoint", provider.name),
+        ));
+    };
+    let payload = IntrospectionPayload {
+        token: token.to_string(),
+        token_type_hint: "access_token".to_string(),
+    };
+    let resp = client_request(state, provider, &oauth, endpoint, &payload)?
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(IOError::other)?
+        .json::<IntrospectionResponse>()
+        .await
+        .map_err(IOError::other)?;
+    match resp {
+        IntrospectionResponse {
+            active: true,
+            sub: Some(sub),
+        } => Ok(sub),
+        IntrospectionResponse { active: true, .. } => Err(IOError::new(
+            ErrorKind::InvalidData,
+            "Introspected token without a subject",
+        )),
+        IntrospectionResponse { active: false, .. } => Err(IOError::new(
+            ErrorKind::PermissionDenied,
+            "Token is not active",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use googletest::prelude::*;
+    use mock_oidc::{MockOidc, MockOidcConfig};
+    use models::{
+        ConnectionTrait, DatabaseConnection,
+        client_auth::{ClientAuthMethod, ClientCredentials},
+        discovery::Discovery,
+        generated::{session, user},
+        get_database,
+        jwks::JwksCache,
+        oauth::{
+            AuthorizationOptions, OAUTH_BACKCHANNEL_LOGOUT_ENDPOINT, OAUTH_PROVIDER_LOGIN_ENDPOINT,
+        },
+        provider::ProviderRegistry,
+        repositories::identity::get_user_by_identity,
+        role::{Role, RoleMapping},
+        session::{MemoryStorage, SessionBackend, SessionLifetimes, SessionStoreConfig},
+        user_auth::refresh_user,
+    };
+    use reqwest::{
+        StatusCode,
+        header::{COOKIE, LOCATION, SET_COOKIE},
+        redirect::Policy,
+    };
+    use serde_json::json;
+    use std::{net::SocketAddr, sync::Arc};
+    use tokio::net::TcpListener;
+
+    const CLIENT_ID: &str = "transversal";
+
+    async fn mock_state() -> (AppState, OAuthProvider) {
+        provider_state(&spawn_mock().await).await
+    }
+
+    async fn spawn_mock() -> MockOidcConfig {
+        MockOidc::spawn(CLIENT_ID, "secret", MockOidcConfig::default_users())
+            .await
+            .unwrap()
+    }
+
+    /// State signing in at `mock`
+    async fn provider_state(mock: &MockOidcConfig) -> (AppState, OAuthProvider) {
+        let requests = Client::builder().redirect(Policy::none()).build().unwrap();
+        let provider = OAuthProvider {
+            name: "mock".to_string(),
+            display_name: "Mock".to_string(),
+            autodiscover_url: mock.issuer.clone(),
+            client_id: CLIENT_ID.to_string(),
+            credentials: ClientCredentials {
+                secret: Some("secret".to_string()),
+                ..Default::default()
+            },
+            discovery: Discovery::default(),
+            jwks: JwksCache::default(),
+            authorization: AuthorizationOptions::default(),
+            roles: RoleMapping {
+                rules: vec![("transversal-admin".to_string(), Role::Admin)],
+                ..Default::default()
+            },
+            access_tokens: AccessTokenValidation::Jwt,
+            api_audience: CLIENT_ID.to_string(),
+        };
+        let oauth = load_openid_config(&requests, &mock.issuer).await.unwrap();
+        provider.discovery.succeeded(oauth).await;
+        let db = DatabaseConnection::default();
+        let state = AppState {
+            sessions: SessionBackend::new(Arc::new(MemoryStorage::default())),
+            db,
+            requests,
+            config: AppConfig {
+                db_url: String::new(),
+                port: 0,
+                providers: ProviderRegistry::new(vec![provider.clone()]),
+                self_url: Url::parse("http://localhost:8889").unwrap(),
+                discovery_refresh: Duration::from_secs(3600),
+                reauth_max_age: 300,
+                session_cache_size: 1000,
+                session_cache_ttl: Duration::from_secs(60),
+                session_refresh_threshold: Duration::from_secs(60),
+                session_keys: None,
+                session_reap_interval: Duration::from_secs(300),
+                session_reap_batch_size: 500,
+                session_store: SessionStoreConfig::Database,
+                session_lifetimes: SessionLifetimes::default(),
+            },
+        };
+        (state, provider)
+    }
+
+    /// Users and identities in `SQLite`, as much of them as signing in needs.
+    /// Alice and Bob signed in before.
+    const SIGN_IN_SCHEMA: &str = r#"
+        CREATE TABLE "user" (
+            id BLOB PRIMARY KEY,
+            email TEXT NOT NULL UNIQUE,
+            name TEXT NOT NULL,
+            _created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ')),
+            _updated_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ')),
+            given_name TEXT,
+            family_name TEXT,
+            preferred_username TEXT,
+            picture TEXT,
+            locale TEXT,
+            role TEXT NOT NULL,
+            email_verified BOOLEAN NOT NULL DEFAULT FALSE
+        );
+        CREATE TABLE identity (
+            id BLOB PRIMARY KEY,
+            provider TEXT NOT NULL,
+            subject TEXT NOT NULL,
+            user_id BLOB NOT NULL REFERENCES "user" (id),
+            email TEXT,
+            _created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ'))
+        );
+        INSERT INTO "user" (id, email, name, role)
+        VALUES (x'0000000000000000000000000000a11c', 'alice@example.com', 'Alice', 'reader');
+        INSERT INTO "user" (id, email, name, role)
+        VALUES (x'00000000000000000000000000000b0b', 'bob@example.com', 'Bob', 'editor');
+        INSERT INTO identity (id, provider, subject, user_id)
+        VALUES
+            (x'01', 'mock', 'alice', x'0000000000000000000000000000a11c'),
+            (x'02', 'mock', 'bob', x'00000000000000000000000000000b0b');
+    "#;
+
+    /// Signs `user` in at the mock provider, returning where it sends them back
+    async fn sign_in_at_provider(state: &AppState, mut url: Url, user: &str) -> Url {
+        url.query_pairs_mut().append_pair("login_hint", user);
+        let resp = state.requests.get(url).send().await.unwrap();
+        Url::parse(resp.headers()[LOCATION].to_str().unwrap()).unwrap()
+    }
+
+    /// Signs `user` in at the mock provider, returning the callback query
+    async fn authorize(
+        state: &AppState,
+        provider: &OAuthProvider,
+        params: &AuthorizationParams,
+        user: &str,
+    ) -> AuthRedirectQuery {
+        let oauth = provider.oauth().await.unwrap();
+        let url = Url::parse(&generate_auth_url(params.clone(), &oauth).unwrap()).unwrap();
+        let location = sign_in_at_provider(state, url, user).await;
+        serde_urlencoded::from_str(location.query().unwrap()).unwrap()
+    }
+
+    /// The whole app along with a database to sign in to, returning its
+    /// address and the provider it signs in at
+    async fn serve_app() -> (AppState, SocketAddr, MockOidcConfig) {
+        let mock = spawn_mock().await;
+        let (state, _) = provider_state(&mock).await;
+        let db = get_database("sqlite::memory:").await.unwrap();
+        db.execute_unprepared(SIGN_IN_SCHEMA).await.unwrap();
+        let state = AppState { db, ..state };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let app = crate::router(state.clone()).unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (state, address, mock)
+    }
+
+    /// The `name=value` of the session cookie set by `resp`
+    fn session_cookie(resp: &reqwest::Response) -> Option<String> {
+        let cookie = resp.headers().get(SET_COOKIE)?.to_str().ok()?;
+        cookie.split(';').next().map(ToString::to_string)
+    }
+
+    /// Starts a login at the app, returning the session cookie and the
+    /// provider authorization URL
+    async fn start_app_login(state: &AppState, address: SocketAddr) -> (String, Url) {
+        let login = OAUTH_PROVIDER_LOGIN_ENDPOINT.replace("{provider}", "mock");
+        let url = format!("http://{address}{login}?return_to=/settings");
+        let resp = state.requests.get(url).send().await.unwrap();
+        let location = Url::parse(resp.headers()[LOCATION].to_str().unwrap()).unwrap();
+        (session_cookie(&resp).unwrap(), location)
+    }
+
+    /// Sends the provider `callback` on to the app
+    async fn app_callback(
+        state: &AppState,
+        address: SocketAddr,
+        cookie: &str,
+        mut callback: Url,
+    ) -> reqwest::Response {
+        callback.set_host(Some(&address.ip().to_string())).unwrap();
+        callback.set_port(Some(address.port())).unwrap();
+        let request = state.requests.get(callback).header(COOKIE, cookie);
+        request.send().await.unwrap()
+    }
+
+    fn with_query_param(mut url: Url, name: &str, value: &str) -> Url {
+        let pairs: Vec<(String, String)> = url
+            .query_pairs()
+            .map(|(key, x)| (key.into_owned(), x.into_owned()))
+            .filter(|(key, _)| key != name)
+            .collect();
+        url.query_pairs_mut()
+            .clear()
+            .extend_pairs(pairs)
+            .append_pair(name, value);
+        url
+    }
+
+    fn query_param(url: &Url, name: &str) -> String {
+        let mut pairs = url.query_pairs();
+        pairs.find(|(key, _)| key == name).unwrap().1.into_owned()
+    }
+
+    async fn mock_user(state: &AppState, sub: &str) -> user::Model {
+        let user = get_user_by_identity(&state.db, "mock", sub).await;
+        user.unwrap().unwrap()
+    }
+
+    async fn sessions_of(state: &AppState, sub: &str) -> Vec<session::Model> {
+        let user = mock_user(state, sub).await;
+        state.sessions.list_by_user(user.id).await.unwrap()
+    }
+
+    #[gtest]
+    #[tokio::test]
+    async fn signs_in_through_the_app() {
+        let (state, address, _) = serve_app().await;
+        let (cookie, authorization) = start_app_login(&state, address).await;
+        let callback = sign_in_at_provider(&state, authorization, "alice").await;
+        let resp = app_callback(&state, address, &cookie, callback.clone()).await;
+        expect_eq!(resp.status(), StatusCode::SEE_OTHER);
+        expect_eq!(resp.headers()[LOCATION], "/settings");
+        let signed_in = session_cookie(&resp).unwrap();
+        expect_ne!(signed_in, cookie);
+
+        let alice = mock_user(&state, "alice").await;
+        expect_eq!(alice.role, Role::Admin);
+        expect_that!(sessions_of(&state, "alice").await, len(eq(1)));
+
+        // The attempt is spent, the same callback can not sign in again
+        let replayed = app_callback(&state, address, &signed_in, callback).await;
+        expect_ne!(replayed.status(), StatusCode::SEE_OTHER);
+        expect_that!(sessions_of(&state, "alice").await, len(eq(1)));
+    }
+
+    #[gtest]
+    #[tokio::test]
+    async fn refreshes_roles_from_the_provider() {
+        let (state, _, _) = serve_app().await;
+        let provider = state.config.providers.get("mock").unwrap().clone();
+        let redirect_uri = build_redirect_url(&state.config, &provider);
+        let params = AuthorizationParams::new(
+            CLIENT_ID.to_string(),
+            redirect_uri,
+            &AuthorizationOptions::default(),
+        );
+        let query = authorize(&state, &provider, &params, "alice").await;
+        let payload =
+            from_redirect_to_token_payload(&state.config, &provider, query, params.code_verifier);
+        let tokens = exchange_token(&state, &provider, &payload).await.unwrap();
+        let alice = mock_user(&state, "alice").await;
+
+        let demoted = OAuthProvider {
+            roles: RoleMapping {
+                rules: vec![("transversal-admin".to_string(), Role::Editor)],
+                ..Default::default()
+            },
+            ..provider.clone()
+        };
+        let refreshed = refresh_user(&state, &demoted, &tokens, None, &alice).await;
+        expect_that!(
+            refreshed,
+            ok(some(field!(user::Model.role, eq(&Role::Editor))))
+        );
+        expect_eq!(mock_user(&state, "alice").await.role, Role::Editor);
+
+        let revoked = OAuthProvider {
+            roles: RoleMapping {
+                default_role: None,
+                ..Default::default()
+            },
+            ..provider
+        };
+        let refreshed = refresh_user(&state, &revoked, &tokens, None, &alice).await;
+        expect_that!(refreshed, ok(none()));
+    }
+
+    #[gtest]
+    #[tokio::test]
+    async fn rejects_a_tampered_state() {
+        let (state, address, _) = serve_app().await;
+        let (cookie, authorization) = start_app_login(&state, address).await;
+        let callback = sign_in_at_provider(&state, authorization, "alice").await;
+        let callback = with_query_param(callback, "state", "forged");
+        let resp = app_callback(&state, address, &cookie, callback).await;
+        expect_eq!(resp.headers()[LOCATION], "/");
+        expect_that!(sessions_of(&state, "alice").await, is_empty());
+    }
+
+    #[gtest]
+    #[tokio::test]
+    async fn rejects_a_replayed_nonce() {
+        let (state, address, _) = serve_app().await;
+        let (_, earlier) = start_app_login(&state, address).await;
+        let (cookie, authorization) = start_app_login(&state, address).await;
+        // The provider signs an ID token for the nonce of the earlier attempt
+        let authorization =
+            with_query_param(authorization, "nonce", &query_param(&earlier, "nonce"));
+        let callback = sign_in_at_provider(&state, authorization, "alice").await;
+        let resp = app_callback(&state, address, &cookie, callback).await;
+        expect_eq!(resp.headers()[LOCATION], "/");
+        expect_that!(sessions_of(&state, "alice").await, is_empty());
+    }
+
+    #[gtest]
+    #[tokio::test]
+    async fn logs_in_against_the_mock_provider() {
+        let (state, provider) = mock_state().await;
+        let redirect_uri = build_redirect_url(&state.config, &provider);
+        let params = AuthorizationParams::new(
+            CLIENT_ID.to_string(),
+            redirect_uri,
+            &AuthorizationOptions::default(),
+        );
+        let query = authorize(&state, &provider, &params, "alice").await;
+        expect_eq!(query.state, params.state);
+
+        let payload = from_redirect_to_token_payload(
+            &state.config,
+            &provider,
+            query,
+            params.code_verifier.clone(),
+        );
+        let tokens = exchange_token(&state, &provider, &payload).await.unwrap();
+        let id_token = tokens.id_token.as_deref().unwrap();
+        let claims = verify_id_token(&state, &provider, id_token, &params.nonce)
+            .await
+            .unwrap();
+        expect_eq!(claims.sub, "alice");
+        expect_that!(claims.sid, some(anything()));
+        expect_that!(
+            provider.roles.resolve(&[&claims.extra]),
+            some(eq(Role::Admin))
+        );
+        expect_that!(
+            verify_id_token(&state, &provider, id_token, "another nonce").await,
+            err(anything())
+        );
+        expect_that!(
+            verify_access_token(&state, &provider, &tokens.access_token).await,
+            ok(eq("alice"))
+        );
+        expect_that!(
+            verify_access_token(&state, &provider, id_token).await,
+            err(anything())
+        );
+
+        let refresh_token = tokens.refresh_token.clone().unwrap();
+        let payload = from_refresh_to_token_payload(&state.config, &provider, refresh_token);
+        let refreshed = exchange_token(&state, &provider, &payload).await.unwrap();
+        expect_ne!(refreshed.access_token, tokens.access_token);
+        let reused = exchange_token(&state, &provider, &payload).await;
+        expect_eq!(
+            reused.err().map(|err| err.kind()),
+            Some(ErrorKind::PermissionDenied)
+        );
+    }
+
+    #[gtest]
+    #[tokio::test]
+    async fn rejects_a_wrong_pkce_verifier() {
+        let (state, provider) = mock_state().await;
+        let redirect_uri = build_redirect_url(&state.config, &provider);
+        let params = AuthorizationParams::new(
+            CLIENT_ID.to_string(),
+            redirect_uri,
+            &AuthorizationOptions::default(),
+        );
+        let query = authorize(&state, &provider, &params, "bob").await;
+        let payload =
+            from_redirect_to_token_payload(&state.config, &provider, query, "forged".to_string());
+        let exchanged = exchange_token(&state, &provider, &payload).await;
+        expect_eq!(
+            exchanged.err().map(|err| err.kind()),
+            Some(ErrorKind::PermissionDenied)
+        );
+    }
+
+    #[gtest]
+    #[tokio::test]
+    async fn authenticates_with_the_configured_client_method() {
+        let (state, mut provider) = mock_state().await;
+        for (method, secret, accepted) in [
+            (ClientAuthMethod::ClientSecretBasic, "secret", true),
+            (ClientAuthMethod::ClientSecretPost, "secret", true),
+            (ClientAuthMethod::ClientSecretPost, "wrong", false),
+        ] {
+            provider.credentials = ClientCredentials {
+                secret: Some(secret.to_string()),
+                method: Some(method),
+                ..Default::default()
+            };
+            let redirect_uri = build_redirect_url(&state.config, &provider);
+            let params = AuthorizationParams::new(
+                CLIENT_ID.to_string(),
+                redirect_uri,
+                &AuthorizationOptions::default(),
+            );
+            let query = authorize(&state, &provider, &params, "carol").await;
+            let payload = from_redirect_to_token_payload(
+                &state.config,
+                &provider,
+                query,
+                params.code_verifier.clone(),
+            );
+            let exchanged = exchange_token(&state, &provider, &payload).await;
+            expect_eq!(exchanged.is_ok(), accepted, "{method} with {secret}");
+        }
+    }
+
+    /// Signs `user` in through the app
+    async fn sign_in(state: &AppState, address: SocketAddr, user: &str) {
+        let (cookie, authorization) = start_app_login(state, address).await;
+        let callback = sign_in_at_provider(state, authorization, user).await;
+        let resp = app_callback(state, address, &cookie, callback).await;
+        assert_that!(resp.status(), eq(StatusCode::SEE_OTHER));
+    }
+
+    async fn backchannel_logout(
+        state: &AppState,
+        address: SocketAddr,
+        logout_token: &str,
+    ) -> StatusCode {
+        let path = OAUTH_BACKCHANNEL_LOGOUT_ENDPOINT.replace("{provider}", "mock");
+        let request = state.requests.post(format!("http://{address}{path}"));
+        let form = [("logout_token", logout_token)];
+        request.form(&form).send().await.unwrap().status()
+    }
+
+    fn claims(claims: serde_json::Value) -> serde_json::Map<String, serde_json::Value> {
+        serde_json::from_value(claims).unwrap()
+    }
+
+    #[gtest]
+    #[tokio::test]
+    async fn backchannel_logout_ends_the_named_sessions() {
+        let (state, address, mock) = serve_app().await;
+        for user in ["alice", "alice", "bob"] {
+            sign_in(&state, address, user).await;
+        }
+        let sessions = sessions_of(&state, "alice").await;
+        let idp_session = sessions[0].idp_session_id.as_deref().unwrap();
+        let sid = idp_session.strip_prefix("mock:").unwrap();
+
+        let by_sid = mock.logout_token(&claims(json!({ "sid": sid }))).unwrap();
+        expect_eq!(
+            backchannel_logout(&state, address, &by_sid).await,
+            StatusCode::OK
+        );
+        expect_that!(
+            sessions_of(&state, "alice").await,
+            elements_are![field!(session::Model.id, eq(&sessions[1].id))]
+        );
+
+        let by_sub = mock
+            .logout_token(&claims(json!({ "sub": "alice" })))
+            .unwrap();
+        expect_eq!(
+            backchannel_logout(&state, address, &by_sub).await,
+            StatusCode::OK
+        );
+        expect_that!(sessions_of(&state, "alice").await, is_empty());
+        expect_that!(sessions_of(&state, "bob").await, len(eq(1)));
+    }
+
+    #[rstest::rstest]
+    #[case::without_the_event(json!({ "sub": "alice", "events": {} }), false)]
+    #[case::with_a_nonce(json!({ "sub": "alice", "nonce": "replayed" }), false)]
+    #[case::without_sid_nor_sub(json!({}), false)]
+    #[case::for_another_client(json!({ "sub": "alice", "aud": "someone-else" }), false)]
+    #[case::expired(json!({ "sub": "alice", "exp": 1 }), false)]
+    #[case::with_a_forged_signature(json!({ "sub": "alice" }), true)]
+    #[gtest]
+    #[tokio::test]
+    async fn rejects_forged_logout_tokens(
+        #[case] logout_claims: serde_json::Value,
+        #[case] forge_signature: bool,
+    ) {
+        let (state, address, mock) = serve_app().await;
+        sign_in(&state, address, "alice").await;
+        let mut logout_token = mock.logout_token(&claims(logout_claims)).unwrap();
+        if forge_signature {
+            // The claims of another token under this one's signature
+            let other = mock.logout_token(&claims(json!({ "sub": "bob" }))).unwrap();
+            let (header, rest) = logout_token.split_once('.').unwrap();
+            let (_, signature) = rest.rsplit_once('.').unwrap();
+            let payload = other.split('.').nth(1).unwrap();
+            logout_token = format!("{header}.{payload}.{signature}");
+        }
+        expect_eq!(
+            backchannel_logout(&state, address, &logout_token).await,
+            StatusCode::BAD_REQUEST
+        );
+        expect_that!(sessions_of(&state, "alice").await, len(eq(1)));
+    }
+}
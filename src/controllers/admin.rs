@@ -0,0 +1,20 @@
+use axum::{Extension, extract::State, http::StatusCode, response::IntoResponse};
+use models::{generated::user, repositories::user::list_users, state::AppState};
+use views::AdminPage;
+
+pub(crate) const ADMIN_PATH: &str = "/admin";
+
+#[axum_macros::debug_handler]
+pub(crate) async fn admin_page(
+    State(state): State<AppState>,
+    Extension(user): Extension<user::Model>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let users = list_users(&state.db)
+        .await
+        .inspect_err(|err| log::error!("Failed to list users: {err}"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(AdminPage {
+        users,
+        user: Some(user),
+    })
+}
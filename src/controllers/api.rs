@@ -0,0 +1,226 @@
+use axum::{
+    Extension, Json,
+    extract::{Path, Request, State, rejection::JsonRejection},
+    http::{StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use models::{
+    Uuid,
+    generated::{album, user},
+    provider::{AccessTokenValidation, OAuthProvider},
+    repositories::{
+        album::{create_album, delete_album, get_album_by_id, list_albums, update_album},
+        api_token::get_user_by_api_token,
+        identity::get_user_by_identity,
+    },
+    scope::TokenScopes,
+    state::AppState,
+};
+use serde_json::json;
+use std::io::{Error as IOError, ErrorKind};
+
+use crate::auth_utils::{unverified_issuer, verify_access_token};
+
+/// JSON API, authenticated with provider access tokens instead of cookies
+pub(crate) const API_PATH: &str = "/api";
+
+pub(crate) fn api_error(status: StatusCode, error: &str, description: &str) -> Response {
+    let body = Json(json!({ "error": error, "error_description": description }));
+    if status == StatusCode::UNAUTHORIZED {
+        let challenge = format!("Bearer error=\"{error}\"");
+        return (status, [(header::WWW_AUTHENTICATE, challenge)], body).into_response();
+    }
+    (status, body).into_response()
+}
+
+/// Authenticates API calls with an `Authorization: Bearer` provider access
+/// token, injecting the user like [`super::auth::load_user`] does for cookies.
+/// Our own API tokens were already handled by [`super::auth::load_token_user`].
+#[axum_macros::debug_middleware]
+pub(crate) async fn bearer_auth(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if request.extensions().get::<TokenScopes>().is_some() {
+        // Already authenticated by one of our API tokens
+        return next.run(request).await;
+    }
+    let Some(token) = bearer_token(&request) else {
+        return api_error(
+            StatusCode::UNAUTHORIZED,
+            "invalid_request",
+            "Missing bearer token",
+        );
+    };
+    match token_user(&state, token).await {
+        Ok(user) => {
+            request.extensions_mut().insert(user);
+            next.run(request).await
+        }
+        Err(err) => {
+            log::warn!("Rejected API bearer token: {err}");
+            api_error(StatusCode::UNAUTHORIZED, "invalid_token", &err.to_string())
+        }
+    }
+}
+
+pub(crate) fn bearer_token(request: &Request) -> Option<&str> {
+    request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+}
+
+/// Owner and scopes of one of our API tokens, personal or from a device login
+pub(crate) async fn api_token_user(
+    state: &AppState,
+    token: &str,
+) -> Result<(user::Model, TokenScopes), IOError> {
+    get_user_by_api_token(&state.db, token)
+        .await?
+        .ok_or_else(|| {
+            IOError::new(
+                ErrorKind::PermissionDenied,
+                "Unknown, expired or revoked token",
+            )
+        })
+}
+
+/// Provider access tokens, JWTs go to the providers sharing their issuer and
+/// opaque tokens to the providers configured for introspection.
+async fn token_user(state: &AppState, token: &str) -> Result<user::Model, IOError> {
+    let issuer = unverified_issuer(token);
+    let mut candidates: Vec<&OAuthProvider> = vec![];
+    for provider in state.config.providers.iter() {
+        let candidate = match &issuer {
+            Some(issuer) => provider
+                .discovery
+                .config()
+                .await
+                .is_some_and(|oauth| oauth.issuer == *issuer),
+            None => provider.access_tokens == AccessTokenValidation::Introspection,
+        };
+        if candidate {
+            candidates.push(provider);
+        }
+    }
+    let mut last_err = IOError::new(ErrorKind::NotFound, "Token from an unknown issuer");
+    for provider in candidates {
+        let sub = match verify_access_token(state, provider, token).await {
+            Ok(sub) => sub,
+            Err(err) => {
+                log::debug!("Token not valid for provider {}: {err}", provider.name);
+                last_err = err;
+                continue;
+            }
+        };
+        return get_user_by_identity(&state.db, &provider.name, &sub)
+            .await?
+            .ok_or_else(|| {
+                IOError::new(
+                    ErrorKind::PermissionDenied,
+                    "Unknown user, sign in through the browser once first",
+                )
+            });
+    }
+    Err(last_err)
+}
+
+fn internal_error(err: &IOError) -> Response {
+    log::error!("API request failed: {err}");
+    api_error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "server_error",
+        "Something went wrong",
+    )
+}
+
+fn not_found() -> Response {
+    api_error(StatusCode::NOT_FOUND, "not_found", "Album not found")
+}
+
+fn invalid_album(err: &impl ToString) -> Response {
+    api_error(StatusCode::BAD_REQUEST, "invalid_request", &err.to_string())
+}
+
+#[axum_macros::debug_handler]
+pub(crate) async fn api_album_list(
+    State(state): State<AppState>,
+    Extension(user): Extension<user::Model>,
+) -> Result<Json<Vec<album::Model>>, Response> {
+    list_albums(&state.db, &user)
+        .await
+        .map(Json)
+        .map_err(|err| internal_error(&err))
+}
+
+#[axum_macros::debug_handler]
+pub(crate) async fn api_album_details(
+    State(state): State<AppState>,
+    Extension(user): Extension<user::Model>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<album::Model>, Response> {
+    get_album_by_id(&state.db, &user, &id)
+        .await
+        .map_err(|err| internal_error(&err))?
+        .map(Json)
+        .ok_or_else(not_found)
+}
+
+#[axum_macros::debug_handler]
+pub(crate) async fn api_album_create(
+    State(state): State<AppState>,
+    Extension(user): Extension<user::Model>,
+    album: Result<Json<serde_json::Value>, JsonRejection>,
+) -> Result<impl IntoResponse, Response> {
+    let Json(album) = album.map_err(|err| invalid_album(&err))?;
+    create_album(&state.db, &user, album)
+        .await
+        .map(|album| (StatusCode::CREATED, Json(album)))
+        .map_err(|err| invalid_album(&err))
+}
+
+#[axum_macros::debug_handler]
+pub(crate) async fn api_album_update(
+    State(state): State<AppState>,
+    Extension(user): Extension<user::Model>,
+    Path(id): Path<Uuid>,
+    album: Result<Json<serde_json::Value>, JsonRejection>,
+) -> Result<Json<album::Model>, Response> {
+    let Json(album) = album.map_err(|err| invalid_album(&err))?;
+    if get_album_by_id(&state.db, &user, &id)
+        .await
+        .map_err(|err| internal_error(&err))?
+        .is_none()
+    {
+        return Err(not_found());
+    }
+    update_album(&state.db, &id, &user, album)
+        .await
+        .map(Json)
+        .map_err(|err| invalid_album(&err))
+}
+
+#[axum_macros::debug_handler]
+pub(crate) async fn api_album_delete(
+    State(state): State<AppState>,
+    Extension(user): Extension<user::Model>,
+    Path(id): Path<Uuid>,
+) -> Result<StatusCode, Response> {
+    if get_album_by_id(&state.db, &user, &id)
+        .await
+        .map_err(|err| internal_error(&err))?
+        .is_none()
+    {
+        return Err(not_found());
+    }
+    delete_album(&state.db, &id)
+        .await
+        .map(|()| StatusCode::NO_CONTENT)
+        .map_err(|err| internal_error(&err))
+}
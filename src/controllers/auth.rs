@@ -1,60 +1,526 @@
 use crate::auth_utils::{
-    build_redirect_url, exchange_token, from_redirect_to_token_payload, generate_auth_url,
+    build_redirect_url, exchange_token, from_redirect_to_token_payload,
+    from_refresh_to_token_payload, generate_auth_url, generate_logout_url, verify_id_token,
+    verify_logout_token, verify_refreshed_id_token,
 };
 use axum::{
-    Extension,
-    extract::{Query, Request, State, rejection::ExtensionRejection},
+    Extension, Form, Json,
+    extract::{ConnectInfo, Path, Query, Request, State, rejection::ExtensionRejection},
+    http::{HeaderMap, Method, StatusCode, header},
     middleware::Next,
     response::{IntoResponse, Redirect, Response},
 };
 use models::{
     generated::user,
-    oauth::{AuthRedirectQuery, AuthorizationParams, LoginAttempt},
+    oauth::{
+        AuthRedirectQuery, AuthTime, AuthorizationOptions, AuthorizationParams,
+        BackchannelLogoutForm, Claims, LoginAttempt, LoginQuery, OAUTH_LOGIN_ENDPOINT,
+        OAUTH_PROVIDER_LOGIN_ENDPOINT, OAUTH_RESUME_ENDPOINT, PendingAction, TokenResponse,
+        TokenSet, auth_time_within,
+    },
+    provider::OAuthProvider,
+    repositories::api_token::API_TOKEN_PREFIX,
+    role::Role,
+    scope::{Scope, TokenScopes},
+    session::{
+        IdpSession, KeepSignedIn, SessionClient, SessionLifetimes, provider_session_id, value,
+    },
     state::AppState,
-    user_auth::authenticate,
+    user_auth::{authenticate, end_sessions, link_identity, refresh_user},
 };
-use tower_sessions::Session;
+use serde_json::json;
+use std::{
+    io::{Error as IOError, ErrorKind},
+    net::SocketAddr,
+};
+use tower_sessions::{Expiry, Session, cookie::time::Duration};
+use views::{ConfirmActionPage, LoginOption, LoginPage};
+
+use crate::{
+    controllers::{
+        api::{api_error, api_token_user, bearer_token},
+        settings::SETTINGS_PATH,
+    },
+    session_state::{Sess, SessWriter},
+};
+
+/// Access tokens expiring within this window are refreshed ahead of time
+const REFRESH_MARGIN: chrono::TimeDelta = chrono::TimeDelta::seconds(30);
+/// Pages a user may be sent back to after signing in
+const RETURN_TO_ALLOWED: &[&str] = &[
+    "/album",
+    "/settings",
+    "/admin",
+    "/device",
+    OAUTH_RESUME_ENDPOINT,
+];
+/// Sent by Unpoly on fragment requests
+const UP_VERSION_HEADER: &str = "X-Up-Version";
+const UP_LOCATION_HEADER: &str = "X-Up-Location";
+
+/// Keeps `return_to` only when it is a local path from the allow-list, anything
+/// else could turn the login into an open redirect.
+fn safe_return_to(return_to: Option<&str>) -> Option<String> {
+    let path = return_to?;
+    let local = path.starts_with('/')
+        && !path.starts_with("//")
+        && !path.contains('\\')
+        && !path.chars().any(char::is_control);
+    let allowed = RETURN_TO_ALLOWED.iter().any(|prefix| {
+        path.strip_prefix(prefix)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with(['/', '?']))
+    });
+    (local && allowed).then(|| path.to_string())
+}
 
-const USER_SESSION_KEY: &str = "user";
-const AUTH_PARAMS_KEY: &str = "auth_params";
+/// The query without an unsafe `return_to`
+fn safe_query(query: &LoginQuery) -> LoginQuery {
+    LoginQuery {
+        return_to: safe_return_to(query.return_to.as_deref()),
+        ..query.clone()
+    }
+}
+
+/// Login URL carrying the query along, `return_to` only when it is safe
+fn login_url(route: &str, query: &LoginQuery) -> String {
+    match serde_urlencoded::to_string(safe_query(query)) {
+        Ok(query) if !query.is_empty() => format!("{route}?{query}"),
+        _ => route.to_string(),
+    }
+}
 
+/// Authenticates scripts using an API token (see [`super::tokens`]) instead of
+/// the session, injecting the user along with the token [`TokenScopes`]. Other
+/// bearer tokens are left to [`super::api::bearer_auth`].
 #[axum_macros::debug_middleware]
-pub(crate) async fn load_user(session: Session, mut request: Request, next: Next) -> Response {
-    if let Some(user) = session_user(&session).await {
+pub(crate) async fn load_token_user(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let Some(token) = bearer_token(&request).filter(|x| x.starts_with(API_TOKEN_PREFIX)) else {
+        return next.run(request).await;
+    };
+    match api_token_user(&state, token).await {
+        Ok((user, scopes)) => {
+            request.extensions_mut().insert(user);
+            request.extensions_mut().insert(scopes);
+            next.run(request).await
+        }
+        Err(err) => {
+            log::warn!("Rejected API token: {err}");
+            api_error(StatusCode::UNAUTHORIZED, "invalid_token", &err.to_string())
+        }
+    }
+}
+
+#[axum_macros::debug_middleware]
+pub(crate) async fn load_user(
+    Sess(user): Sess<user::Model>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if let Some(user) = user {
         log::info!("User loaded from session injecting into extensions");
         request.extensions_mut().insert(user);
     }
     next.run(request).await
 }
 
+/// Keeps the stored access token fresh with the `refresh_token` grant, a
+/// session whose refresh is rejected by the provider is logged out. The user
+/// profile and role are synced from the provider along the way.
+#[axum_macros::debug_middleware]
+pub(crate) async fn refresh_tokens(
+    State(state): State<AppState>,
+    session: Session,
+    stored_tokens: SessWriter<TokenSet>,
+    stored_user: SessWriter<user::Model>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let tokens = stored_tokens
+        .get()
+        .await
+        .filter(|tokens| tokens.expires_within(REFRESH_MARGIN));
+    let Some(tokens) = tokens else {
+        return next.run(request).await;
+    };
+    let Some(refresh_token) = tokens.refresh_token.clone() else {
+        log::debug!("Access token expiring but no refresh token in session");
+        return next.run(request).await;
+    };
+    let Some(provider) = state.config.providers.get(&tokens.provider) else {
+        log::warn!("Session tokens from unknown provider {}", tokens.provider);
+        session.flush().await.ok();
+        return next.run(request).await;
+    };
+    refresh_session(
+        &state,
+        &session,
+        &stored_tokens,
+        &stored_user,
+        provider,
+        tokens,
+        &refresh_token,
+    )
+    .await;
+    next.run(request).await
+}
+
+/// Refreshes the tokens of the session, and the user along with them.
+/// Concurrent requests of the session refresh one at a time, the later ones
+/// find the tokens refreshed instead of spending the rotated refresh token.
+async fn refresh_session(
+    state: &AppState,
+    session: &Session,
+    stored_tokens: &SessWriter<TokenSet>,
+    stored_user: &SessWriter<user::Model>,
+    provider: &OAuthProvider,
+    tokens: TokenSet,
+    refresh_token: &str,
+) {
+    let _refreshing = match session.id() {
+        Some(id) => Some(state.sessions.lock(id).await),
+        None => None,
+    };
+    if adopt_stored_tokens(state, session, stored_tokens, stored_user, refresh_token).await {
+        log::debug!("Tokens already refreshed by a concurrent request");
+        return;
+    }
+    log::info!("Access token expiring, refreshing");
+    let payload = from_refresh_to_token_payload(&state.config, provider, refresh_token.to_string());
+    match exchange_token(state, provider, &payload).await {
+        Ok(resp) => {
+            if let Some(user) = stored_user.get().await {
+                match refreshed_user(state, provider, &resp, &user).await {
+                    Ok(Some(user)) => {
+                        stored_user.set(&user).await.ok();
+                    }
+                    Ok(None) => {
+                        log::warn!("User {} has no role anymore, logging out", user.id);
+                        session.flush().await.ok();
+                        return;
+                    }
+                    Err(err) => log::error!("Failed to refresh user {}: {err}", user.id),
+                }
+            }
+            stored_tokens.set(&tokens.refreshed(resp)).await.ok();
+            // Stored now rather than after the response, for the requests
+            // waiting on the lock
+            session.save().await.ok();
+        }
+        Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+            // Another instance may have won the race with the same token
+            if adopt_stored_tokens(state, session, stored_tokens, stored_user, refresh_token).await
+            {
+                log::info!("Refresh token already rotated by a concurrent request");
+                return;
+            }
+            log::warn!("Refresh token rejected, logging out: {err}");
+            session.flush().await.ok();
+        }
+        Err(err) => log::error!("Failed to refresh access token, keeping session: {err}"),
+    }
+}
+
+/// Takes over the tokens, and the user, a concurrent request of the session
+/// refreshed and stored. False when the stored tokens still hold
+/// `refresh_token`.
+async fn adopt_stored_tokens(
+    state: &AppState,
+    session: &Session,
+    stored_tokens: &SessWriter<TokenSet>,
+    stored_user: &SessWriter<user::Model>,
+    refresh_token: &str,
+) -> bool {
+    let Some(id) = session.id() else {
+        return false;
+    };
+    let latest = state.sessions.stored_value::<TokenSet>(&id).await;
+    let Some(latest) = latest.filter(|x| x.refresh_token.as_deref() != Some(refresh_token)) else {
+        return false;
+    };
+    stored_tokens.set(&latest).await.ok();
+    if let Some(user) = state.sessions.stored_value::<user::Model>(&id).await {
+        stored_user.set(&user).await.ok();
+    }
+    true
+}
+
+/// The signed in `user` as the provider now sees them, see [`refresh_user`]
+async fn refreshed_user(
+    state: &AppState,
+    provider: &OAuthProvider,
+    resp: &TokenResponse,
+    user: &user::Model,
+) -> Result<Option<user::Model>, IOError> {
+    let claims = match resp.id_token.as_deref() {
+        Some(id_token) => Some(verify_refreshed_id_token(state, provider, id_token).await?),
+        None => None,
+    };
+    refresh_user(state, provider, resp, claims.as_ref(), user).await
+}
+
 #[axum_macros::debug_middleware]
 pub(crate) async fn login_required(
     user: Result<Extension<user::Model>, ExtensionRejection>,
     request: Request,
     next: Next,
 ) -> Response {
-    if user.is_err() {
+    if user.is_ok() {
+        return next.run(request).await;
+    }
+    let return_to = (request.method() == Method::GET)
+        .then(|| request.uri().path_and_query())
+        .flatten()
+        .map(ToString::to_string);
+    let query = LoginQuery {
+        return_to,
+        ..Default::default()
+    };
+    log::warn!("Unauthorized access attempt, redirecting to login");
+    sign_in_response(&request, login_url(OAUTH_LOGIN_ENDPOINT, &query))
+}
+
+/// Redirect to `login`, Unpoly fragment requests get a `401` pointing there
+/// instead as it would render the provider page inside the fragment.
+fn sign_in_response(request: &Request, login: String) -> Response {
+    if request.headers().contains_key(UP_VERSION_HEADER) {
+        log::warn!("Fragment request needs a sign in, asking Unpoly to follow");
+        let body = format!("<a href=\"{login}\" up-follow=\"false\">Sign in</a>");
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(UP_LOCATION_HEADER, login)],
+            axum::response::Html(body),
+        )
+            .into_response();
+    }
+    Redirect::to(&login).into_response()
+}
+
+/// Step-up guard, lets through sessions which authenticated at most the
+/// layer state seconds ago, e.g. `from_fn_with_state(300, require_recent_auth)`.
+/// Others sign in again with the same provider (`prompt=login` and `max_age`),
+/// coming back to the page for `GET` requests or to a confirmation of the
+/// interrupted action otherwise.
+#[axum_macros::debug_middleware]
+pub(crate) async fn require_recent_auth(
+    State(max_age): State<u64>,
+    Sess(auth_time): Sess<AuthTime>,
+    Sess(tokens): Sess<TokenSet>,
+    pending_action: SessWriter<PendingAction>,
+    user: Result<Extension<user::Model>, ExtensionRejection>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if request.extensions().get::<TokenScopes>().is_some() {
+        // Tokens can not sign in again, their scope is what counts
+        return next.run(request).await;
+    }
+    if auth_time.is_some_and(|AuthTime(auth_time)| auth_time_within(auth_time, max_age)) {
+        return next.run(request).await;
+    }
+    let Ok(Extension(user)) = user else {
+        return Redirect::to("/").into_response();
+    };
+    let provider = tokens.map_or_else(
+        || OAUTH_LOGIN_ENDPOINT.to_string(),
+        |tokens| OAUTH_PROVIDER_LOGIN_ENDPOINT.replace("{provider}", &tokens.provider),
+    );
+    let return_to = if request.method() == Method::GET {
+        request.uri().path_and_query().map(ToString::to_string)
+    } else {
+        let pending = PendingAction {
+            user: user.id,
+            method: request.method().to_string(),
+            path: request.uri().path().to_string(),
+        };
+        pending_action.set(&pending).await.ok();
+        Some(OAUTH_RESUME_ENDPOINT.to_string())
+    };
+    let query = LoginQuery {
+        return_to,
+        prompt: Some("login".to_string()),
+        max_age: Some(max_age),
+        ..Default::default()
+    };
+    log::info!(
+        "User {} must re-authenticate for {}",
+        user.id,
+        request.uri()
+    );
+    sign_in_response(&request, login_url(&provider, &query))
+}
+
+/// Offers to repeat the action [`require_recent_auth`] interrupted, never
+/// replayed automatically so a forged login can not trigger it.
+#[axum_macros::debug_handler]
+pub(crate) async fn resume_handler(
+    pending_action: SessWriter<PendingAction>,
+    Extension(user): Extension<user::Model>,
+) -> Response {
+    let pending = pending_action
+        .remove()
+        .await
+        .filter(|pending| pending.user == user.id);
+    let Some(pending) = pending else {
+        log::warn!("No pending action to resume for {}", user.id);
+        return Redirect::to("/").into_response();
+    };
+    ConfirmActionPage {
+        method: pending.method,
+        path: pending.path,
+        user: Some(user),
+    }
+    .into_response()
+}
+
+/// Lets through users holding at least the role given as the layer state,
+/// e.g. `from_fn_with_state(Role::Editor, require_role)`.
+#[axum_macros::debug_middleware]
+pub(crate) async fn require_role(
+    State(role): State<Role>,
+    user: Result<Extension<user::Model>, ExtensionRejection>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Ok(Extension(user)) = user else {
         log::warn!("Unauthorized access attempt, redirecting to homepage");
         return Redirect::to("/").into_response();
+    };
+    if !user.has_role(role) {
+        log::warn!("User {} lacks the {role} role", user.id);
+        return StatusCode::FORBIDDEN.into_response();
     }
     next.run(request).await
 }
 
+/// Lets through sessions and API tokens granted the scope given as the layer
+/// state, e.g. `from_fn_with_state(Scope::AlbumsWrite, require_scope)`.
+#[axum_macros::debug_middleware]
+pub(crate) async fn require_scope(
+    State(scope): State<Scope>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if let Some(scopes) = request.extensions().get::<TokenScopes>()
+        && !scopes.allows(scope)
+    {
+        log::warn!("API token lacks the {scope} scope");
+        let description = format!("The token is not granted the {scope} scope");
+        return api_error(StatusCode::FORBIDDEN, "insufficient_scope", &description);
+    }
+    next.run(request).await
+}
+
+/// Keeps API tokens away from account pages, e.g. a token must not be able to
+/// mint more tokens.
+#[axum_macros::debug_middleware]
+pub(crate) async fn session_only(request: Request, next: Next) -> Response {
+    if request.extensions().get::<TokenScopes>().is_some() {
+        log::warn!("API token used on {}, refused", request.uri().path());
+        return api_error(
+            StatusCode::FORBIDDEN,
+            "insufficient_scope",
+            "Only available from a browser session",
+        );
+    }
+    next.run(request).await
+}
+
+fn find_provider<'a>(state: &'a AppState, name: &str) -> Result<&'a OAuthProvider, String> {
+    state.config.providers.get(name).ok_or_else(|| {
+        log::warn!("Login attempt with unknown provider {name}");
+        format!("Unknown provider {name}")
+    })
+}
+
+/// Lets the user pick a provider and whether to stay signed in, the query is
+/// passed on to the provider login
+#[axum_macros::debug_handler]
+pub(crate) async fn login_chooser(
+    State(state): State<AppState>,
+    Query(query): Query<LoginQuery>,
+) -> LoginPage {
+    let mut options = vec![];
+    for provider in state.config.providers.iter() {
+        options.push(LoginOption {
+            display_name: provider.display_name.clone(),
+            href: provider.path(OAUTH_PROVIDER_LOGIN_ENDPOINT),
+            available: provider.discovery.config().await.is_some(),
+        });
+    }
+    let query = LoginQuery {
+        keep_signed_in: None,
+        ..safe_query(&query)
+    };
+    let fields = serde_urlencoded::to_string(query)
+        .ok()
+        .and_then(|query| serde_urlencoded::from_str(&query).ok())
+        .unwrap_or_default();
+    LoginPage {
+        providers: options,
+        fields,
+        user: None,
+    }
+}
+
 #[axum_macros::debug_handler]
 pub(crate) async fn login_handler(
-    session: Session,
+    attempts: SessWriter<LoginAttempt>,
     State(state): State<AppState>,
+    Path(provider): Path<String>,
+    Query(query): Query<LoginQuery>,
 ) -> Result<Redirect, String> {
-    log::info!("starting login");
-    let config = &state.config;
+    log::info!("starting login with {provider}");
+    let provider = find_provider(&state, &provider)?;
+    let attempt = |params| LoginAttempt {
+        return_to: safe_return_to(query.return_to.as_deref()),
+        keep_signed_in: query.keep_signed_in.unwrap_or_default(),
+        ..LoginAttempt::new(provider.name.clone(), params)
+    };
+    let options = provider.authorization.overridden(&query);
+    start_login(&attempts, &state, provider, &options, attempt).await
+}
 
-    let redirect_uri = build_redirect_url(config);
-    let params = AuthorizationParams::new(config.oauth_client_id.clone(), redirect_uri);
+/// Starts a login whose identity gets linked to the signed in user
+#[axum_macros::debug_handler]
+pub(crate) async fn link_handler(
+    attempts: SessWriter<LoginAttempt>,
+    State(state): State<AppState>,
+    Extension(user): Extension<user::Model>,
+    Path(provider): Path<String>,
+) -> Result<Redirect, String> {
+    log::info!("starting {provider} identity link for {}", user.id);
+    let provider = find_provider(&state, &provider)?;
+    let attempt = |params| LoginAttempt {
+        link_user: Some(user.id),
+        ..LoginAttempt::new(provider.name.clone(), params)
+    };
+    start_login(
+        &attempts,
+        &state,
+        provider,
+        &provider.authorization,
+        attempt,
+    )
+    .await
+}
+
+async fn start_login(
+    attempts: &SessWriter<LoginAttempt>,
+    state: &AppState,
+    provider: &OAuthProvider,
+    options: &AuthorizationOptions,
+    attempt: impl FnOnce(AuthorizationParams) -> LoginAttempt,
+) -> Result<Redirect, String> {
+    let oauth = provider.oauth().await.map_err(|err| err.to_string())?;
+    let redirect_uri = build_redirect_url(&state.config, provider);
+    let params = AuthorizationParams::new(provider.client_id.clone(), redirect_uri, options);
     log::debug!("Generating auth URL with params: {params:?}");
-    let attempt = LoginAttempt::from(params.clone());
-    session.insert(AUTH_PARAMS_KEY, attempt).await.ok();
-    let url =
-        generate_auth_url(params, &state.config).map_err(|_| "Failed to generate auth URL")?;
+    attempts.set(&attempt(params.clone())).await.ok();
+    let url = generate_auth_url(params, &oauth).map_err(|_| "Failed to generate auth URL")?;
     log::info!("generated auth url, redirecting to {url}");
     Ok(Redirect::temporary(&url))
 }
@@ -62,17 +528,30 @@ pub(crate) async fn login_handler(
 #[axum_macros::debug_handler]
 pub(crate) async fn redirect_handler(
     session: Session,
+    attempts: SessWriter<LoginAttempt>,
     State(state): State<AppState>,
+    Path(provider): Path<String>,
+    connect_info: Option<Extension<ConnectInfo<SocketAddr>>>,
+    headers: HeaderMap,
     Query(query): Query<AuthRedirectQuery>,
 ) -> Result<Redirect, String> {
-    log::info!("Got oauth2 redirect, reading cookies");
-    let attempt = session
-        .get::<LoginAttempt>(AUTH_PARAMS_KEY)
+    log::info!("Got oauth2 redirect from {provider}, reading cookies");
+    let provider = find_provider(&state, &provider)?;
+    let attempt = attempts
+        .get()
         .await
-        .map_err(|_| "Failed to deserialize login attempt from session")?
         .ok_or("Failed to find login attempt from session")?;
+    if attempt.provider != provider.name {
+        log::error!(
+            "Mix-up attack?! callback from '{}', login started with '{}'",
+            provider.name,
+            attempt.provider
+        );
+        return Ok(Redirect::to("/"));
+    }
     let crsf_token = attempt.csrf;
     let pkce = attempt.pkce;
+    let nonce = attempt.nonce;
     if query.state != crsf_token {
         log::error!(
             "CRSF attack?! state '{}', stored cookie '{}'",
@@ -82,39 +561,201 @@ pub(crate) async fn redirect_handler(
         return Ok(Redirect::to("/"));
     }
     log::info!("cookies pass, converting to token exchage payload");
-    let token_payload = from_redirect_to_token_payload(&state.config, query, pkce);
-    let code = match exchange_token(&state, &token_payload).await {
+    let token_payload = from_redirect_to_token_payload(&state.config, provider, query, pkce);
+    let code = match exchange_token(&state, provider, &token_payload).await {
         Ok(code) => code,
         Err(err) => {
             log::error!("Failed to exchange token {err:?}");
             return Ok(Redirect::to("/"));
         }
     };
-    let user = authenticate(&state, code)
+    let Some(id_token) = code.id_token.as_deref() else {
+        log::error!("Token response without an id_token, is the openid scope granted?");
+        return Ok(Redirect::to("/"));
+    };
+    let claims = match verify_id_token(&state, provider, id_token, &nonce).await {
+        Ok(claims) => claims,
+        Err(err) => {
+            log::error!("Failed to verify id token {err:?}");
+            return Ok(Redirect::to("/"));
+        }
+    };
+    if let Some(max_age) = attempt.max_age
+        && !claims.authenticated_within(max_age)
+    {
+        log::error!("ID token auth_time does not honour the requested max_age {max_age}");
+        return Ok(Redirect::to("/"));
+    }
+    // The pre-login id may have been planted, the signed in session gets a new one
+    state.sessions.cycle_id(&session).await.map_err(|err| {
+        log::error!("Failed to cycle session id: {err}");
+        "Failed to start session".to_string()
+    })?;
+    if let Some(link_user) = attempt.link_user {
+        attempts.remove().await;
+        let user = value::read::<user::Model>(&session).await;
+        let Some(user) = user.filter(|u| u.id == link_user) else {
+            log::error!("Identity link callback without the linking user signed in");
+            return Ok(Redirect::to("/"));
+        };
+        return link_identity(&state, provider, &code, &claims, &user)
+            .await
+            .map(|()| Redirect::to(SETTINGS_PATH))
+            .map_err(|err| {
+                log::warn!("Failed to link identity: {err:?}");
+                format!("Failed to link identity: {err}")
+            });
+    }
+    let user = authenticate(&state, provider, &code, &claims)
         .await
         .map_err(|err| {
             log::error!("Failed to authenticate session: {err:?}");
+            if err.kind() == ErrorKind::PermissionDenied {
+                return err.to_string();
+            }
             "Failed to authenticate session".to_string()
         })?
         .ok_or_else(|| {
             log::error!("Failed to authenticate session, no user found");
             "Failed to authenticate session, no user found".to_string()
         })?;
-    session.insert(USER_SESSION_KEY, user).await.ok();
-    session.remove::<LoginAttempt>(AUTH_PARAMS_KEY).await.ok();
-    Ok(Redirect::to("/"))
+    let client = SessionClient {
+        ip: connect_info.map(|Extension(ConnectInfo(addr))| addr.ip().to_string()),
+        user_agent: headers
+            .get(header::USER_AGENT)
+            .and_then(|x| x.to_str().ok())
+            .map(ToString::to_string),
+    };
+    start_session(&session, provider, user, code, &claims, client).await;
+    if attempt.keep_signed_in {
+        value::write(&session, &KeepSignedIn).await.ok();
+        keep_signed_in(&session, &state.config.session_lifetimes);
+    }
+    attempts.remove().await;
+    let return_to = safe_return_to(attempt.return_to.as_deref());
+    Ok(Redirect::to(return_to.as_deref().unwrap_or("/")))
 }
 
-pub(crate) async fn session_user(session: &Session) -> Option<user::Model> {
-    session
-        .get::<user::Model>(USER_SESSION_KEY)
-        .await
-        .ok()
-        .flatten()
+/// Sessions kept signed in outlast the inactivity the session layer allows,
+/// their absolute lifetime is enforced by the session backend
+#[axum_macros::debug_middleware]
+pub(crate) async fn apply_session_lifetime(
+    State(lifetimes): State<SessionLifetimes>,
+    session: Session,
+    Sess(kept): Sess<KeepSignedIn>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if kept.is_some() {
+        keep_signed_in(&session, &lifetimes);
+    }
+    next.run(request).await
+}
+
+fn keep_signed_in(session: &Session, lifetimes: &SessionLifetimes) {
+    if let Ok(idle) = Duration::try_from(lifetimes.kept_signed_in.idle) {
+        session.set_expiry(Some(Expiry::OnInactivity(idle)));
+    }
+}
+
+/// Stores everything a signed in session needs
+async fn start_session(
+    session: &Session,
+    provider: &OAuthProvider,
+    user: user::Model,
+    code: TokenResponse,
+    claims: &Claims,
+    client: SessionClient,
+) {
+    value::write(session, &user).await.ok();
+    let auth_time = claims
+        .auth_time
+        .unwrap_or_else(|| chrono::Utc::now().timestamp());
+    value::write(session, &AuthTime(auth_time)).await.ok();
+    value::write(session, &client).await.ok();
+    if let Some(sid) = &claims.sid {
+        let idp_session = IdpSession(provider_session_id(&provider.name, sid));
+        value::write(session, &idp_session).await.ok();
+    }
+    let tokens = TokenSet::new(provider.name.clone(), code);
+    value::write(session, &tokens).await.ok();
+}
+
+/// Ends the local session, deleting it so the next visit gets a new id, and
+/// sends the browser to the provider so its session ends too, otherwise the
+/// next login would be silent.
+#[axum_macros::debug_handler]
+pub(crate) async fn logout_handler(
+    session: Session,
+    Sess(tokens): Sess<TokenSet>,
+    State(state): State<AppState>,
+) -> Redirect {
+    session.flush().await.ok();
+    let Some(tokens) = tokens else {
+        return Redirect::to("/");
+    };
+    let Some(provider) = state.config.providers.get(&tokens.provider) else {
+        return Redirect::to("/");
+    };
+    match generate_logout_url(&state.config, provider, tokens.id_token.as_deref()).await {
+        Ok(url) => Redirect::to(&url),
+        Err(err) => {
+            log::error!("Failed to generate logout URL: {err}");
+            Redirect::to("/")
+        }
+    }
 }
 
+/// OIDC back-channel logout, the provider posts a signed
+/// `logout_token` naming the sessions to end.
 #[axum_macros::debug_handler]
-pub(crate) async fn logout_handler(session: Session) -> Redirect {
-    session.clear().await;
-    Redirect::to("/")
+pub(crate) async fn backchannel_logout_handler(
+    State(state): State<AppState>,
+    Path(provider): Path<String>,
+    Form(form): Form<BackchannelLogoutForm>,
+) -> Response {
+    let no_store = [(header::CACHE_CONTROL, "no-store")];
+    let Some(provider) = state.config.providers.get(&provider) else {
+        return (StatusCode::NOT_FOUND, no_store).into_response();
+    };
+    let claims = match verify_logout_token(&state, provider, &form.logout_token).await {
+        Ok(claims) => claims,
+        Err(err) => {
+            log::warn!("Rejected back-channel logout token: {err}");
+            let body = Json(json!({ "error": "invalid_request" }));
+            return (StatusCode::BAD_REQUEST, no_store, body).into_response();
+        }
+    };
+    match end_sessions(&state, provider, &claims).await {
+        Ok(count) => {
+            log::info!("Back-channel logout ended {count} sessions");
+            (StatusCode::OK, no_store).into_response()
+        }
+        Err(err) => {
+            log::error!("Failed to end sessions on back-channel logout: {err}");
+            (StatusCode::INTERNAL_SERVER_ERROR, no_store).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use googletest::prelude::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("/album", true)]
+    #[case("/album/0198c0de-7e57-7000-8000-000000000000?tab=tracks", true)]
+    #[case("/settings", true)]
+    #[case("/albums-elsewhere", false)]
+    #[case("/", false)]
+    #[case("//evil.example/album", false)]
+    #[case("/album/\\evil.example", false)]
+    #[case("https://evil.example/album", false)]
+    #[case("/auth/login", false)]
+    #[gtest]
+    fn only_returns_to_allowed_local_paths(#[case] path: &str, #[case] allowed: bool) {
+        expect_eq!(safe_return_to(Some(path)).is_some(), allowed);
+    }
 }
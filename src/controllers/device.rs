@@ -0,0 +1,223 @@
+use axum::{
+    Extension, Form, Json,
+    extract::{Query, State, rejection::FormRejection},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use chrono::TimeDelta;
+use models::{
+    generated::user,
+    repositories::device::{
+        DEVICE_CODE_LIFETIME, DevicePoll, decide_device_authorization,
+        get_pending_device_authorization, normalize_user_code, poll_device_authorization,
+        start_device_authorization,
+    },
+    state::AppState,
+};
+use serde::Deserialize;
+use serde_json::json;
+use views::DevicePage;
+
+use crate::controllers::api::api_error;
+
+/// Page where users enter the code shown by a device
+pub(crate) const DEVICE_PATH: &str = "/device";
+/// RFC 8628 device authorization endpoint
+pub(crate) const DEVICE_AUTHORIZATION_ENDPOINT: &str = "/auth/device";
+pub(crate) const DEVICE_TOKEN_ENDPOINT: &str = "/auth/device/token";
+const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+/// Lifetime of the API tokens handed to devices
+const DEVICE_TOKEN_LIFETIME: TimeDelta = TimeDelta::days(30);
+const CLIENT_NAME_MAX_LENGTH: usize = 64;
+
+#[derive(Deserialize)]
+pub(crate) struct DeviceAuthorizationForm {
+    /// Free form name of the client, shown to the user approving it
+    client_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct DeviceQuery {
+    user_code: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct DeviceDecisionForm {
+    user_code: String,
+    action: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct DeviceTokenForm {
+    grant_type: String,
+    device_code: String,
+}
+
+fn invalid_form(err: &FormRejection) -> Response {
+    api_error(StatusCode::BAD_REQUEST, "invalid_request", &err.body_text())
+}
+
+/// Starts a device login, the device shows the user code and polls
+/// [`DEVICE_TOKEN_ENDPOINT`] meanwhile
+#[axum_macros::debug_handler]
+pub(crate) async fn device_authorization(
+    State(state): State<AppState>,
+    form: Result<Form<DeviceAuthorizationForm>, FormRejection>,
+) -> Result<Json<serde_json::Value>, Response> {
+    let Form(form) = form.map_err(|err| invalid_form(&err))?;
+    let client_name = form
+        .client_id
+        .map(|name| name.trim().chars().take(CLIENT_NAME_MAX_LENGTH).collect())
+        .filter(|name: &String| !name.is_empty())
+        .unwrap_or_else(|| "Command line client".to_string());
+    let (request, device_code) = start_device_authorization(&state.db, &client_name)
+        .await
+        .map_err(|err| {
+            log::error!("Failed to start device authorization: {err}");
+            api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "server_error",
+                "Something went wrong",
+            )
+        })?;
+    let mut verification_uri = state.config.self_url.clone();
+    verification_uri.set_path(DEVICE_PATH);
+    let mut verification_uri_complete = verification_uri.clone();
+    verification_uri_complete
+        .query_pairs_mut()
+        .append_pair("user_code", &request.user_code);
+    log::info!("Device login started for {client_name}");
+    Ok(Json(json!({
+        "device_code": device_code,
+        "user_code": request.user_code,
+        "verification_uri": verification_uri.to_string(),
+        "verification_uri_complete": verification_uri_complete.to_string(),
+        "expires_in": DEVICE_CODE_LIFETIME.num_seconds(),
+        "interval": request.interval,
+    })))
+}
+
+/// Code entry, or the approval prompt once a pending code is given
+#[axum_macros::debug_handler]
+pub(crate) async fn device_page(
+    State(state): State<AppState>,
+    Extension(user): Extension<user::Model>,
+    Query(query): Query<DeviceQuery>,
+) -> Response {
+    let Some(input) = query.user_code else {
+        return DevicePage {
+            user_code: String::new(),
+            client_name: None,
+            message: None,
+            user: Some(user),
+        }
+        .into_response();
+    };
+    let request = match normalize_user_code(&input) {
+        Some(code) => get_pending_device_authorization(&state.db, &code)
+            .await
+            .inspect_err(|err| log::error!("Failed to find device authorization: {err}"))
+            .ok()
+            .flatten(),
+        None => None,
+    };
+    let message = request
+        .is_none()
+        .then(|| "This code is unknown or expired, check the device for a new one".to_string());
+    DevicePage {
+        user_code: request.as_ref().map_or(input, |x| x.user_code.clone()),
+        client_name: request.map(|x| x.client_name),
+        message,
+        user: Some(user),
+    }
+    .into_response()
+}
+
+#[axum_macros::debug_handler]
+pub(crate) async fn device_decision(
+    State(state): State<AppState>,
+    Extension(user): Extension<user::Model>,
+    Form(form): Form<DeviceDecisionForm>,
+) -> Response {
+    let request = match normalize_user_code(&form.user_code) {
+        Some(code) => get_pending_device_authorization(&state.db, &code)
+            .await
+            .inspect_err(|err| log::error!("Failed to find device authorization: {err}"))
+            .ok()
+            .flatten(),
+        None => None,
+    };
+    let approve = form.action == "approve";
+    let message = match request {
+        None => "This code is unknown or expired, check the device for a new one",
+        Some(request) => {
+            match decide_device_authorization(&state.db, request, &user, approve).await {
+                Ok(()) if approve => "Device signed in, you can go back to it",
+                Ok(()) => "Device sign in denied",
+                Err(err) => {
+                    log::error!("Failed to record device decision: {err}");
+                    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                }
+            }
+        }
+    };
+    log::info!(
+        "User {} decided on a device login, approved: {approve}",
+        user.id
+    );
+    DevicePage {
+        user_code: String::new(),
+        client_name: None,
+        message: Some(message.to_string()),
+        user: Some(user),
+    }
+    .into_response()
+}
+
+/// Device polling, answered with RFC 8628 errors until the user decided and
+/// then with an API token of the approving user
+#[axum_macros::debug_handler]
+pub(crate) async fn device_token(
+    State(state): State<AppState>,
+    form: Result<Form<DeviceTokenForm>, FormRejection>,
+) -> Result<Json<serde_json::Value>, Response> {
+    let Form(form) = form.map_err(|err| invalid_form(&err))?;
+    if form.grant_type != DEVICE_CODE_GRANT {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "unsupported_grant_type",
+            "Only the device code grant is supported",
+        ));
+    }
+    let server_error = |err: std::io::Error| {
+        log::error!("Failed to poll device authorization: {err}");
+        api_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "server_error",
+            "Something went wrong",
+        )
+    };
+    let poll = poll_device_authorization(&state.db, &form.device_code, DEVICE_TOKEN_LIFETIME)
+        .await
+        .map_err(server_error)?;
+    let (error, description) = match poll {
+        DevicePoll::Approved {
+            user,
+            client_name,
+            token,
+        } => {
+            log::info!("Device login of {client_name} completed for {}", user.id);
+            return Ok(Json(json!({
+                "access_token": token,
+                "token_type": "Bearer",
+                "expires_in": DEVICE_TOKEN_LIFETIME.num_seconds(),
+            })));
+        }
+        DevicePoll::Pending => ("authorization_pending", "The user has not decided yet"),
+        DevicePoll::SlowDown => ("slow_down", "Polling too fast, increase the interval"),
+        DevicePoll::Denied => ("access_denied", "The user denied the request"),
+        DevicePoll::Expired => ("expired_token", "The device code expired"),
+        DevicePoll::Unknown => ("invalid_grant", "Unknown device code"),
+    };
+    Err(api_error(StatusCode::BAD_REQUEST, error, description))
+}
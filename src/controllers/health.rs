@@ -0,0 +1,55 @@
+use axum::{Json, extract::State, http::StatusCode, response::IntoResponse};
+use models::state::AppState;
+use serde_json::json;
+use std::sync::atomic::Ordering;
+
+pub(crate) const HEALTH_PATH: &str = "/health";
+
+/// Database and provider discovery status, `503` while degraded, along with
+/// the session store and expired session reaper counters
+#[axum_macros::debug_handler]
+pub(crate) async fn health_check(State(state): State<AppState>) -> impl IntoResponse {
+    let database = state.db.ping().await.is_ok();
+    let mut providers = vec![];
+    let mut healthy = database;
+    for provider in state.config.providers.iter() {
+        let status = provider.discovery.status().await;
+        let available = status.config.is_some();
+        healthy &= available;
+        // Errors name internal hosts, they stay in the logs
+        if !available && let Some(err) = &status.last_error {
+            log::warn!("Provider {} unavailable: {err}", provider.name);
+        }
+        providers.push(json!({
+            "name": provider.name,
+            "available": available,
+            "last_success": status.last_success,
+            "failures": status.failures,
+        }));
+    }
+    let status = if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let reaper = state.sessions.reaper_counters();
+    let store = state.sessions.store_counters();
+    let body = json!({
+        "status": if healthy { "ok" } else { "degraded" },
+        "database": database,
+        "providers": providers,
+        "session_reaper": {
+            "runs": reaper.runs.load(Ordering::Relaxed),
+            "failures": reaper.failures.load(Ordering::Relaxed),
+            "deleted": reaper.deleted.load(Ordering::Relaxed),
+            "evicted": reaper.evicted.load(Ordering::Relaxed),
+        },
+        "session_store": {
+            "cache_hits": store.cache_hits.load(Ordering::Relaxed),
+            "cache_misses": store.cache_misses.load(Ordering::Relaxed),
+            "writes": store.writes.load(Ordering::Relaxed),
+            "writes_skipped": store.writes_skipped.load(Ordering::Relaxed),
+        },
+    });
+    (status, Json(body))
+}
@@ -1,17 +1,25 @@
 use axum::{extract::State, response::IntoResponse};
-use models::state::AppState;
-use tower_sessions::Session;
+use models::{generated::user, state::AppState};
 use views::IndexPage;
 
-use crate::controllers::auth::session_user;
+use crate::session_state::Sess;
 
+pub(crate) mod admin;
 pub(crate) mod album;
+pub(crate) mod api;
 pub(crate) mod auth;
+pub(crate) mod device;
+pub(crate) mod health;
+pub(crate) mod sessions;
+pub(crate) mod settings;
+pub(crate) mod tokens;
 
 #[axum_macros::debug_handler]
-pub(crate) async fn home(session: Session, State(state): State<AppState>) -> impl IntoResponse {
+pub(crate) async fn home(
+    Sess(user): Sess<user::Model>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
     state.db.ping().await.ok();
-    let user = session_user(&session).await;
     log::info!("Rendering home page for user: {user:?}");
     IndexPage { user }
 }
@@ -0,0 +1,93 @@
+use axum::{
+    Extension,
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Redirect, Response},
+};
+use models::{generated::user, session::session_handle, state::AppState};
+use tower_sessions::Session;
+use views::{ActiveSession, SessionsPage};
+
+pub(crate) const SESSIONS_PATH: &str = "/settings/sessions";
+const DATE_FORMAT: &str = "%Y-%m-%d %H:%M";
+
+#[axum_macros::debug_handler]
+pub(crate) async fn sessions_page(
+    session: Session,
+    State(state): State<AppState>,
+    Extension(user): Extension<user::Model>,
+) -> Response {
+    let sessions = match state.sessions.list_by_user(user.id).await {
+        Ok(sessions) => sessions,
+        Err(err) => {
+            log::error!("Failed to list sessions: {err}");
+            return Redirect::to("/").into_response();
+        }
+    };
+    let current = session.id().map(|id| id.to_string());
+    let sessions = sessions
+        .into_iter()
+        .map(|x| ActiveSession {
+            handle: session_handle(&x.id),
+            current: current.as_ref() == Some(&x.id),
+            ip: x.ip,
+            user_agent: x.user_agent,
+            created_at: x.created_at.format(DATE_FORMAT).to_string(),
+            refreshed_at: x.refreshed_at.format(DATE_FORMAT).to_string(),
+        })
+        .collect();
+    SessionsPage {
+        sessions,
+        user: Some(user),
+    }
+    .into_response()
+}
+
+/// Signs out one of the other sessions of the user
+#[axum_macros::debug_handler]
+pub(crate) async fn session_revoke(
+    session: Session,
+    State(state): State<AppState>,
+    Extension(user): Extension<user::Model>,
+    Path(handle): Path<String>,
+) -> Result<Redirect, StatusCode> {
+    if session
+        .id()
+        .is_some_and(|id| session_handle(&id.to_string()) == handle)
+    {
+        // Signing out this session is what the logout button is for
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    match state.sessions.delete_by_handle(user.id, &handle).await {
+        Ok(true) => {
+            log::info!("User {} revoked one of their sessions", user.id);
+            Ok(Redirect::to(SESSIONS_PATH))
+        }
+        Ok(false) => Err(StatusCode::NOT_FOUND),
+        Err(err) => {
+            log::error!("Failed to revoke session: {err}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[axum_macros::debug_handler]
+pub(crate) async fn sessions_revoke_others(
+    session: Session,
+    State(state): State<AppState>,
+    Extension(user): Extension<user::Model>,
+) -> Result<Redirect, StatusCode> {
+    let Some(current) = session.id() else {
+        return Err(StatusCode::BAD_REQUEST);
+    };
+    match state.sessions.delete_others(user.id, &current).await {
+        Ok(count) => {
+            log::info!("User {} revoked {count} other sessions", user.id);
+            Ok(Redirect::to(SESSIONS_PATH))
+        }
+        Err(err) => {
+            log::error!("Failed to revoke sessions: {err}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
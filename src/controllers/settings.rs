@@ -0,0 +1,36 @@
+use axum::{
+    Extension,
+    extract::State,
+    response::{IntoResponse, Redirect},
+};
+use models::{
+    generated::user, oauth::OAUTH_LINK_ENDPOINT, repositories::identity::list_identities,
+    state::AppState,
+};
+use views::{LoginOption, SettingsPage};
+
+pub(crate) const SETTINGS_PATH: &str = "/settings";
+
+#[axum_macros::debug_handler]
+pub(crate) async fn settings_page(
+    State(state): State<AppState>,
+    Extension(user): Extension<user::Model>,
+) -> Result<impl IntoResponse, Redirect> {
+    let identities = list_identities(&state.db, &user)
+        .await
+        .inspect_err(|err| log::error!("Failed to list identities: {err}"))
+        .map_err(|_| Redirect::to("/"))?;
+    let mut link_options = vec![];
+    for provider in state.config.providers.iter() {
+        link_options.push(LoginOption {
+            display_name: provider.display_name.clone(),
+            href: provider.path(OAUTH_LINK_ENDPOINT),
+            available: provider.discovery.config().await.is_some(),
+        });
+    }
+    Ok(SettingsPage {
+        identities,
+        link_options,
+        user: Some(user),
+    })
+}
@@ -0,0 +1,150 @@
+use axum::{
+    Extension, Form,
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Redirect, Response},
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use models::{
+    Uuid,
+    generated::user,
+    repositories::api_token::{create_api_token, delete_api_token, list_api_tokens},
+    scope::{Scope, TokenScopes},
+    state::AppState,
+};
+use views::TokensPage;
+
+pub(crate) const TOKENS_PATH: &str = "/settings/tokens";
+const TOKEN_NAME_MAX_LENGTH: usize = 64;
+
+async fn render(
+    state: &AppState,
+    user: user::Model,
+    new_token: Option<String>,
+    message: Option<&str>,
+) -> Response {
+    match list_api_tokens(&state.db, &user).await {
+        Ok(tokens) => TokensPage {
+            tokens,
+            scopes: Scope::ALL.to_vec(),
+            new_token,
+            message: message.map(ToString::to_string),
+            user: Some(user),
+        }
+        .into_response(),
+        Err(err) => {
+            log::error!("Failed to list API tokens: {err}");
+            Redirect::to("/").into_response()
+        }
+    }
+}
+
+#[axum_macros::debug_handler]
+pub(crate) async fn tokens_page(
+    State(state): State<AppState>,
+    Extension(user): Extension<user::Model>,
+) -> Response {
+    render(&state, user, None, None).await
+}
+
+/// Expiry of a token lasting `expires_in_days`, never when empty
+fn token_expiry(expires_in_days: Option<&str>) -> Result<Option<DateTime<Utc>>, String> {
+    let Some(days) = expires_in_days.filter(|x| !x.is_empty()) else {
+        return Ok(None);
+    };
+    let days: u16 = days
+        .parse()
+        .map_err(|_| format!("Invalid lifetime {days}"))?;
+    TimeDelta::try_days(days.into())
+        .and_then(|lifetime| Utc::now().checked_add_signed(lifetime))
+        .map(Some)
+        .ok_or_else(|| format!("Lifetime of {days} days out of range"))
+}
+
+/// Creates a token from the form fields `name`, `expires_in_days` (never
+/// when empty) and one `scope` per granted scope
+#[axum_macros::debug_handler]
+pub(crate) async fn token_create(
+    State(state): State<AppState>,
+    Extension(user): Extension<user::Model>,
+    Form(form): Form<Vec<(String, String)>>,
+) -> Response {
+    let field = |key: &str| {
+        form.iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.trim())
+    };
+    let name: String = field("name")
+        .unwrap_or_default()
+        .chars()
+        .take(TOKEN_NAME_MAX_LENGTH)
+        .collect();
+    let scopes = TokenScopes(
+        form.iter()
+            .filter(|(key, _)| key == "scope")
+            .filter_map(|(_, value)| value.parse().ok())
+            .collect(),
+    );
+    if name.is_empty() || scopes.0.is_empty() {
+        return render(&state, user, None, Some("Name the token and pick a scope")).await;
+    }
+    let Ok(expires_at) = token_expiry(field("expires_in_days")) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    let expires_at = expires_at.map(Into::into);
+    match create_api_token(&state.db, &user, &name, &scopes, expires_at).await {
+        Ok((token, secret)) => {
+            log::info!("User {} created API token {}", user.id, token.id);
+            render(&state, user, Some(secret), None).await
+        }
+        Err(err) => {
+            log::error!("Failed to create API token: {err}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[axum_macros::debug_handler]
+pub(crate) async fn token_revoke(
+    State(state): State<AppState>,
+    Extension(user): Extension<user::Model>,
+    Path(id): Path<Uuid>,
+) -> Result<Redirect, StatusCode> {
+    match delete_api_token(&state.db, &user, &id).await {
+        Ok(true) => {
+            log::info!("User {} revoked API token {id}", user.id);
+            Ok(Redirect::to(TOKENS_PATH))
+        }
+        Ok(false) => Err(StatusCode::NOT_FOUND),
+        Err(err) => {
+            log::error!("Failed to revoke API token: {err}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use googletest::prelude::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case::huge("99999999999999")]
+    #[case::negative("-1")]
+    #[case::not_a_number("soon")]
+    #[gtest]
+    fn out_of_range_lifetimes_are_rejected(#[case] days: &str) {
+        expect_that!(token_expiry(Some(days)), err(anything()));
+    }
+
+    #[gtest]
+    fn lifetimes_are_optional() {
+        expect_that!(token_expiry(None), ok(none()));
+        expect_that!(token_expiry(Some("")), ok(none()));
+        expect_that!(
+            token_expiry(Some("30")),
+            ok(some(gt(&(Utc::now() + TimeDelta::days(29)))))
+        );
+    }
+}
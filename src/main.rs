@@ -1,69 +1,252 @@
-use auth_utils::load_openid_config;
+use auth_utils::keep_discovered;
 use axum::{
     Router, ServiceExt,
     extract::Request,
     middleware,
-    routing::{delete, get, post},
+    routing::{delete, get, post, put},
 };
+use jsonwebtoken::{Algorithm, EncodingKey};
 use models::{
+    DatabaseConnection,
+    client_auth::{ClientCredentials, ClientKey},
+    discovery::Discovery,
     get_database,
-    oauth::{OAUTH_CALLBACK_ENDPOINT, OAUTH_LOGIN_ENDPOINT, OAUTH_LOGOUT_ENDPOINT},
-    session::SeaSessionBackend,
+    jwks::JwksCache,
+    oauth::{
+        AuthorizationOptions, OAUTH_BACKCHANNEL_LOGOUT_ENDPOINT, OAUTH_CALLBACK_ENDPOINT,
+        OAUTH_LINK_ENDPOINT, OAUTH_LOGIN_ENDPOINT, OAUTH_LOGOUT_ENDPOINT,
+        OAUTH_PROVIDER_LOGIN_ENDPOINT, OAUTH_RESUME_ENDPOINT,
+    },
+    provider::{AccessTokenValidation, OAuthProvider, ProviderRegistry},
+    role::{Role, RoleMapping},
+    scope::Scope,
+    session::{
+        DEFAULT_CACHE_CAPACITY, DEFAULT_CACHE_TTL, DEFAULT_REFRESH_THRESHOLD, SessionBackend,
+        SessionKeyring, SessionLifetime, SessionLifetimes, SessionStoreConfig,
+    },
     state::{AppConfig, AppState},
 };
 use reqwest::Url;
-use std::env::var;
+use std::{env::var, net::SocketAddr, time::Duration as StdDuration};
 use tokio::net::TcpListener;
 use tower_http::{normalize_path::NormalizePath, trace::TraceLayer};
 use tower_sessions::{Expiry, SessionManagerLayer, cookie::time::Duration};
 use tracing_subscriber::{filter::LevelFilter, prelude::*};
 
 use crate::controllers::{
+    admin::{ADMIN_PATH, admin_page},
     album::{album_create, album_delete, album_details, album_list, album_update},
-    auth::{self, login_required},
+    api::{
+        API_PATH, api_album_create, api_album_delete, api_album_details, api_album_list,
+        api_album_update, bearer_auth,
+    },
+    auth::{self, login_required, require_recent_auth, require_role, require_scope},
+    device::{
+        DEVICE_AUTHORIZATION_ENDPOINT, DEVICE_PATH, DEVICE_TOKEN_ENDPOINT, device_authorization,
+        device_decision, device_page, device_token,
+    },
+    health::{HEALTH_PATH, health_check},
     home,
+    sessions::{SESSIONS_PATH, session_revoke, sessions_page, sessions_revoke_others},
+    settings::{SETTINGS_PATH, settings_page},
+    tokens::{TOKENS_PATH, token_create, token_revoke, tokens_page},
 };
 
 mod auth_utils;
 mod controllers;
+mod session_state;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenvy::dotenv().ok();
     setup_tracing();
-    let config = load_app_config().await;
+    #[cfg(feature = "mock-oidc")]
+    if std::env::args().nth(1).as_deref() == Some("mock-oidc") {
+        return run_mock_oidc().await;
+    }
+    if std::env::args().nth(1).as_deref() == Some("sessions") {
+        return run_sessions_command().await;
+    }
+    let config = load_app_config();
     let port = config.port;
+    let db = get_database(&config.db_url).await?;
     let state = AppState {
-        db: get_database(&config.db_url).await?,
+        sessions: session_backend(&config, &db).await?,
+        db,
         requests: reqwest::Client::new(),
         config,
     };
-    let session_layer = SessionManagerLayer::new(SeaSessionBackend::new(state.db.clone()))
+    for provider in state.config.providers.iter() {
+        tokio::spawn(keep_discovered(
+            state.requests.clone(),
+            provider.clone(),
+            state.config.discovery_refresh,
+        ));
+    }
+    tokio::spawn(
+        state
+            .sessions
+            .clone()
+            .reap_expired(state.config.session_reap_interval),
+    );
+    tokio::spawn(state.sessions.clone().listen_for_changes());
+    let app = NormalizePath::trim_trailing_slash(router(state)?);
+    // run our app with hyper, listening globally on port 3000
+    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+    log::info!("Listening on port {port}");
+    axum::serve(
+        listener,
+        ServiceExt::<Request>::into_make_service_with_connect_info::<SocketAddr>(app),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Every route along with the session and authentication layers
+fn router(state: AppState) -> Result<Router, Box<dyn std::error::Error>> {
+    let idle_timeout = Duration::try_from(state.config.session_lifetimes.default.idle)?;
+    let session_layer = SessionManagerLayer::new(state.sessions.clone())
         .with_secure(true)
-        .with_expiry(Expiry::OnInactivity(Duration::days(7)));
+        .with_expiry(Expiry::OnInactivity(idle_timeout));
+
+    let admins = Router::new()
+        .route(ADMIN_PATH, get(admin_page))
+        .route_layer(middleware::from_fn_with_state(Role::Admin, require_role));
+
+    let api = api_routes(&state);
+
+    // Browser only, API tokens are refused
+    let account = Router::new()
+        .merge(admins)
+        .route(SETTINGS_PATH, get(settings_page))
+        .route(SESSIONS_PATH, get(sessions_page))
+        .route(
+            "/settings/sessions/revoke-others",
+            post(sessions_revoke_others),
+        )
+        .route("/settings/sessions/{handle}", delete(session_revoke))
+        .route(TOKENS_PATH, get(tokens_page).post(token_create))
+        .route("/settings/tokens/{id}", delete(token_revoke))
+        .route(OAUTH_LINK_ENDPOINT, get(auth::link_handler))
+        .route(OAUTH_RESUME_ENDPOINT, get(auth::resume_handler))
+        .route(DEVICE_PATH, get(device_page).post(device_decision))
+        .route_layer(middleware::from_fn(auth::session_only));
 
     let app = Router::new()
         // Private routes (login required)
-        .route("/album/{id}", get(album_details))
-        .route("/album/{id}", post(album_update))
-        .route("/album/{id}", delete(album_delete))
-        .route("/album", get(album_list))
-        .route("/album", post(album_create))
+        .merge(album_routes(state.config.reauth_max_age))
+        .merge(account)
         .layer(middleware::from_fn(login_required))
+        // Bearer token routes
+        .nest(API_PATH, api)
         // Public routes
+        .route(DEVICE_AUTHORIZATION_ENDPOINT, post(device_authorization))
+        .route(DEVICE_TOKEN_ENDPOINT, post(device_token))
         .route(OAUTH_CALLBACK_ENDPOINT, get(auth::redirect_handler))
         .route(OAUTH_LOGOUT_ENDPOINT, get(auth::logout_handler))
-        .route(OAUTH_LOGIN_ENDPOINT, get(auth::login_handler))
+        .route(
+            OAUTH_BACKCHANNEL_LOGOUT_ENDPOINT,
+            post(auth::backchannel_logout_handler),
+        )
+        .route(OAUTH_PROVIDER_LOGIN_ENDPOINT, get(auth::login_handler))
+        .route(OAUTH_LOGIN_ENDPOINT, get(auth::login_chooser))
+        .route(HEALTH_PATH, get(health_check))
         .route("/", get(home))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::load_token_user,
+        ))
         .layer(middleware::from_fn(auth::load_user))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::refresh_tokens,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.config.session_lifetimes,
+            auth::apply_session_lifetime,
+        ))
         .layer(session_layer)
         .layer(TraceLayer::new_for_http())
         .with_state(state);
-    let app = NormalizePath::trim_trailing_slash(app);
-    // run our app with hyper, listening globally on port 3000
+    Ok(app)
+}
+
+/// Album pages, open to sessions and API tokens with the matching scope
+fn album_routes(reauth_max_age: u64) -> Router<AppState> {
+    let readers = Router::new()
+        .route("/album/{id}", get(album_details))
+        .route("/album", get(album_list))
+        .route_layer(middleware::from_fn_with_state(
+            Scope::AlbumsRead,
+            require_scope,
+        ))
+        .route_layer(middleware::from_fn_with_state(Role::Reader, require_role));
+    let destructive = Router::new()
+        .route("/album/{id}", delete(album_delete))
+        .route_layer(middleware::from_fn_with_state(
+            reauth_max_age,
+            require_recent_auth,
+        ));
+    let editors = Router::new()
+        .route("/album/{id}", post(album_update))
+        .route("/album", post(album_create))
+        .merge(destructive)
+        .route_layer(middleware::from_fn_with_state(
+            Scope::AlbumsWrite,
+            require_scope,
+        ))
+        .route_layer(middleware::from_fn_with_state(Role::Editor, require_role));
+    readers.merge(editors)
+}
+
+/// JSON API, authenticated with bearer tokens only
+fn api_routes(state: &AppState) -> Router<AppState> {
+    let api_readers = Router::new()
+        .route("/albums/{id}", get(api_album_details))
+        .route("/albums", get(api_album_list))
+        .route_layer(middleware::from_fn_with_state(
+            Scope::AlbumsRead,
+            require_scope,
+        ))
+        .route_layer(middleware::from_fn_with_state(Role::Reader, require_role));
+    let api_editors = Router::new()
+        .route("/albums/{id}", put(api_album_update))
+        .route("/albums/{id}", delete(api_album_delete))
+        .route("/albums", post(api_album_create))
+        .route_layer(middleware::from_fn_with_state(
+            Scope::AlbumsWrite,
+            require_scope,
+        ))
+        .route_layer(middleware::from_fn_with_state(Role::Editor, require_role));
+    Router::new()
+        .merge(api_readers)
+        .merge(api_editors)
+        .route_layer(middleware::from_fn_with_state(state.clone(), bearer_auth))
+}
+
+/// Serves the embedded OIDC provider on `MOCK_OIDC_PORT` (8090 by default) for
+/// the un-prefixed `OAUTH_CLIENT_ID` and `OAUTH_CLIENT_SECRET`, test users are
+/// read from the JSON array file named by `MOCK_OIDC_USERS` when set.
+#[cfg(feature = "mock-oidc")]
+async fn run_mock_oidc() -> Result<(), Box<dyn std::error::Error>> {
+    use mock_oidc::{MockOidc, MockOidcConfig};
+
+    let port = var("MOCK_OIDC_PORT")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(8090);
+    let users = match var("MOCK_OIDC_USERS") {
+        Ok(path) => serde_json::from_str(&std::fs::read_to_string(path)?)?,
+        Err(_) => MockOidcConfig::default_users(),
+    };
+    let config = MockOidcConfig {
+        issuer: format!("http://localhost:{port}"),
+        client_id: var("OAUTH_CLIENT_ID").unwrap_or_else(|_| "transversal".to_string()),
+        client_secret: var("OAUTH_CLIENT_SECRET").unwrap_or_else(|_| "secret".to_string()),
+        users,
+    };
     let listener = TcpListener::bind(("0.0.0.0", port)).await?;
-    log::info!("Listening on port {port}");
-    axum::serve(listener, ServiceExt::<Request>::into_make_service(app)).await?;
+    MockOidc::new(config).serve(listener).await?;
     Ok(())
 }
 
@@ -79,8 +262,7 @@ fn setup_tracing() {
 
 /// # Panics
 /// if environment variables are not set correctly
-async fn load_app_config() -> AppConfig {
-    let oauth_url = var("OAUTH_DISCOVER_URL").expect("OAUTH_DISCOVER_URL must be set");
+fn load_app_config() -> AppConfig {
     let self_url = var("SELF_URL").expect("SELF_URL must be set");
     let self_url = Url::parse(&self_url).expect("Invalid SELF_URL format");
     let port = var("PORT")
@@ -90,10 +272,310 @@ async fn load_app_config() -> AppConfig {
     AppConfig {
         db_url: var("DATABASE_URL").expect("DATABASE_URL must be set"),
         port,
-        oauth: load_openid_config(&oauth_url).await,
-        oauth_client_id: var("OAUTH_CLIENT_ID").expect("OAUTH_CLIENT_ID must be set"),
-        oauth_autodiscover_url: oauth_url,
-        oauth_client_secret: var("OAUTH_CLIENT_SECRET").expect("OAUTH_CLIENT_SECRET must be set"),
+        providers: load_providers(),
         self_url,
+        discovery_refresh: StdDuration::from_secs(
+            var("OAUTH_DISCOVERY_REFRESH_SECS")
+                .ok()
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(3600),
+        ),
+        reauth_max_age: var("REAUTH_MAX_AGE_SECS")
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(300),
+        session_cache_size: var("SESSION_CACHE_SIZE")
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(DEFAULT_CACHE_CAPACITY),
+        session_cache_ttl: var("SESSION_CACHE_TTL_SECS")
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .map_or(DEFAULT_CACHE_TTL, StdDuration::from_secs),
+        session_refresh_threshold: var("SESSION_REFRESH_THRESHOLD_SECS")
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .map_or(DEFAULT_REFRESH_THRESHOLD, StdDuration::from_secs),
+        session_keys: load_session_keyring(),
+        session_reap_interval: StdDuration::from_secs(
+            var("SESSION_REAP_INTERVAL_SECS")
+                .ok()
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(300),
+        ),
+        session_reap_batch_size: var("SESSION_REAP_BATCH_SIZE")
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(500),
+        session_store: load_session_store(),
+        session_lifetimes: load_session_lifetimes(),
+    }
+}
+
+/// `SESSION_IDLE_TIMEOUT_SECS` and `SESSION_MAX_LIFETIME_SECS`, along with
+/// `SESSION_KEPT_IDLE_TIMEOUT_SECS` and `SESSION_KEPT_MAX_LIFETIME_SECS` for
+/// sessions kept signed in, see [`SessionLifetimes::default`]
+fn load_session_lifetimes() -> SessionLifetimes {
+    let secs = |name: &str, default: StdDuration| {
+        var(name)
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .map_or(default, StdDuration::from_secs)
+    };
+    let defaults = SessionLifetimes::default();
+    SessionLifetimes {
+        default: SessionLifetime {
+            idle: secs("SESSION_IDLE_TIMEOUT_SECS", defaults.default.idle),
+            absolute: secs("SESSION_MAX_LIFETIME_SECS", defaults.default.absolute),
+        },
+        kept_signed_in: SessionLifetime {
+            idle: secs(
+                "SESSION_KEPT_IDLE_TIMEOUT_SECS",
+                defaults.kept_signed_in.idle,
+            ),
+            absolute: secs(
+                "SESSION_KEPT_MAX_LIFETIME_SECS",
+                defaults.kept_signed_in.absolute,
+            ),
+        },
+    }
+}
+
+/// Sessions as configured, in the configured storage
+async fn session_backend(
+    config: &AppConfig,
+    db: &DatabaseConnection,
+) -> Result<SessionBackend, std::io::Error> {
+    let storage = config.session_store.open(db).await?;
+    let mut sessions = SessionBackend::new(storage)
+        .with_cache(config.session_cache_size, config.session_cache_ttl)
+        .with_refresh_threshold(config.session_refresh_threshold)
+        .with_reap_batch_size(config.session_reap_batch_size)
+        .with_lifetimes(config.session_lifetimes);
+    if let Some(keyring) = config.session_keys.clone() {
+        sessions = sessions.with_encryption(keyring);
+    }
+    Ok(sessions)
+}
+
+/// `SESSION_STORE_URL`, see [`SessionStoreConfig`]
+///
+/// # Panics
+/// if the URL is not a supported store
+fn load_session_store() -> SessionStoreConfig {
+    var("SESSION_STORE_URL")
+        .unwrap_or_default()
+        .parse()
+        .unwrap_or_else(|err| panic!("Invalid SESSION_STORE_URL: {err}"))
+}
+
+/// `SESSION_ENCRYPTION_KEYS` as `<key id>:<base64 key>,...`, see
+/// [`SessionKeyring`]
+///
+/// # Panics
+/// if the keys are malformed
+fn load_session_keyring() -> Option<SessionKeyring> {
+    var("SESSION_ENCRYPTION_KEYS")
+        .ok()
+        .filter(|keys| !keys.trim().is_empty())
+        .map(|keys| {
+            keys.parse()
+                .unwrap_or_else(|err| panic!("Invalid SESSION_ENCRYPTION_KEYS: {err}"))
+        })
+}
+
+/// `sessions re-encrypt` seals every stored session with the current key,
+/// `sessions purge <key id>` deletes those sealed with a retired one
+async fn run_sessions_command() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().skip(2).collect();
+    let db_url = var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let storage = load_session_store()
+        .open(&get_database(&db_url).await?)
+        .await?;
+    let mut sessions = SessionBackend::new(storage);
+    if let Some(keyring) = load_session_keyring() {
+        sessions = sessions.with_encryption(keyring);
+    }
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        ["re-encrypt"] => {
+            let count = sessions.reencrypt_all().await?;
+            log::info!("Re-encrypted {count} sessions");
+        }
+        ["purge", key_id] => {
+            let count = sessions.purge_key(key_id).await?;
+            log::info!("Purged {count} sessions sealed with key {key_id}");
+        }
+        _ => return Err("Usage: sessions re-encrypt | sessions purge <key id>".into()),
+    }
+    Ok(())
+}
+
+/// Providers are listed in `OAUTH_PROVIDERS` (e.g. `keycloak,partner`) and each
+/// one reads `OAUTH_<NAME>_DISCOVER_URL`, `OAUTH_<NAME>_CLIENT_ID`,
+/// `OAUTH_<NAME>_CLIENT_SECRET` and optionally `OAUTH_<NAME>_DISPLAY_NAME`
+/// along with the role mapping, see [`load_role_mapping`], and the
+/// authorization request options, see [`load_authorization_options`]. API
+/// access tokens are checked as JWTs for `OAUTH_<NAME>_API_AUDIENCE` (the
+/// client id by default) unless `OAUTH_<NAME>_ACCESS_TOKENS` is `introspection`.
+/// Without `OAUTH_PROVIDERS` a single `default` provider is read from the
+/// un-prefixed `OAUTH_DISCOVER_URL`, `OAUTH_CLIENT_ID`...
+///
+/// # Panics
+/// if environment variables are not set correctly
+fn load_providers() -> ProviderRegistry {
+    let names = var("OAUTH_PROVIDERS").unwrap_or_else(|_| "default".to_string());
+    let mut providers = vec![];
+    for name in names.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+        assert!(
+            name.chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-'),
+            "Invalid OAuth provider name {name}, use lowercase letters, digits and dashes"
+        );
+        let provider_var = |key: &str| {
+            let key = if name == "default" {
+                format!("OAUTH_{key}")
+            } else {
+                format!(
+                    "OAUTH_{}_{key}",
+                    name.to_ascii_uppercase().replace('-', "_")
+                )
+            };
+            var(&key).map_err(|_| key)
+        };
+        let required =
+            |key: &str| provider_var(key).unwrap_or_else(|key| panic!("{key} must be set"));
+        let autodiscover_url = required("DISCOVER_URL");
+        let client_id = required("CLIENT_ID");
+        let access_tokens = match provider_var("ACCESS_TOKENS").as_deref() {
+            Err(_) | Ok("jwt") => AccessTokenValidation::Jwt,
+            Ok("introspection") => AccessTokenValidation::Introspection,
+            Ok(other) => panic!("Invalid access token validation {other}"),
+        };
+        providers.push(OAuthProvider {
+            name: name.to_string(),
+            display_name: provider_var("DISPLAY_NAME").unwrap_or_else(|_| name.to_string()),
+            discovery: Discovery::default(),
+            autodiscover_url,
+            api_audience: provider_var("API_AUDIENCE").unwrap_or_else(|_| client_id.clone()),
+            client_id,
+            credentials: load_client_credentials(provider_var),
+            jwks: JwksCache::default(),
+            roles: load_role_mapping(provider_var),
+            authorization: load_authorization_options(provider_var),
+            access_tokens,
+        });
+    }
+    assert!(
+        !providers.is_empty(),
+        "At least one OAuth provider must be configured"
+    );
+    ProviderRegistry::new(providers)
+}
+
+/// `OAUTH_<NAME>_ROLE_CLAIMS` lists the claim paths holding role or group
+/// names (`realm_access.roles,groups` by default), `OAUTH_<NAME>_ADMIN_ROLES`,
+/// `_EDITOR_ROLES` and `_READER_ROLES` list the values granting each role and
+/// `OAUTH_<NAME>_DEFAULT_ROLE` applies to everybody else (`editor` by default,
+/// `none` refuses them).
+///
+/// # Panics
+/// if a role name is invalid
+fn load_role_mapping(provider_var: impl Fn(&str) -> Result<String, String>) -> RoleMapping {
+    let list = |key: &str| -> Vec<String> {
+        provider_var(key)
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|x| !x.is_empty())
+            .map(str::to_string)
+            .collect()
+    };
+    let mut mapping = RoleMapping::default();
+    let claim_paths = list("ROLE_CLAIMS");
+    if !claim_paths.is_empty() {
+        mapping.claim_paths = claim_paths;
+    }
+    for (key, role) in [
+        ("ADMIN_ROLES", Role::Admin),
+        ("EDITOR_ROLES", Role::Editor),
+        ("READER_ROLES", Role::Reader),
+    ] {
+        mapping
+            .rules
+            .extend(list(key).into_iter().map(|value| (value, role)));
+    }
+    if let Ok(default_role) = provider_var("DEFAULT_ROLE") {
+        mapping.default_role = match default_role.trim() {
+            "none" => None,
+            role => Some(role.parse().unwrap_or_else(|err| panic!("{err}"))),
+        };
+    }
+    mapping
+}
+
+/// `OAUTH_<NAME>_SCOPE` (`offline_access openid email profile` by default),
+/// `_AUDIENCE`, `_RESPONSE_MODE`, `_PROMPT`, `_LOGIN_HINT`, `_ACR_VALUES` and
+/// `_MAX_AGE` are sent with every authorization request when set.
+///
+/// # Panics
+/// if `OAUTH_<NAME>_MAX_AGE` is not a number of seconds
+fn load_authorization_options(
+    provider_var: impl Fn(&str) -> Result<String, String>,
+) -> AuthorizationOptions {
+    let defaults = AuthorizationOptions::default();
+    AuthorizationOptions {
+        scope: provider_var("SCOPE").unwrap_or(defaults.scope),
+        audience: provider_var("AUDIENCE").ok(),
+        response_mode: provider_var("RESPONSE_MODE").ok(),
+        prompt: provider_var("PROMPT").ok(),
+        login_hint: provider_var("LOGIN_HINT").ok(),
+        acr_values: provider_var("ACR_VALUES").ok(),
+        max_age: provider_var("MAX_AGE").ok().map(|max_age| {
+            max_age
+                .parse()
+                .unwrap_or_else(|_| panic!("Invalid max age {max_age}"))
+        }),
+    }
+}
+
+/// `OAUTH_<NAME>_CLIENT_SECRET` and or `OAUTH_<NAME>_PRIVATE_KEY` (a PEM file
+/// signing `private_key_jwt` assertions with `_PRIVATE_KEY_ALG`, `RS256` by
+/// default, and `_PRIVATE_KEY_ID`), neither makes a public client.
+/// `OAUTH_<NAME>_CLIENT_AUTH` forces a token endpoint authentication method
+/// instead of picking one the provider supports.
+///
+/// # Panics
+/// if the key can not be read or the method or algorithm is invalid
+fn load_client_credentials(
+    provider_var: impl Fn(&str) -> Result<String, String>,
+) -> ClientCredentials {
+    let key = provider_var("PRIVATE_KEY").ok().map(|path| {
+        let alg: Algorithm = provider_var("PRIVATE_KEY_ALG")
+            .unwrap_or_else(|_| "RS256".to_string())
+            .parse()
+            .unwrap_or_else(|err| panic!("Invalid private key algorithm: {err}"));
+        let pem = std::fs::read(&path)
+            .unwrap_or_else(|err| panic!("Failed to read private key {path}: {err}"));
+        let key = match alg {
+            Algorithm::ES256 | Algorithm::ES384 => EncodingKey::from_ec_pem(&pem),
+            Algorithm::EdDSA => EncodingKey::from_ed_pem(&pem),
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                panic!("Private key algorithm must be asymmetric")
+            }
+            _ => EncodingKey::from_rsa_pem(&pem),
+        }
+        .unwrap_or_else(|err| panic!("Invalid private key {path}: {err}"));
+        ClientKey {
+            key,
+            alg,
+            kid: provider_var("PRIVATE_KEY_ID").ok(),
+        }
+    });
+    ClientCredentials {
+        secret: provider_var("CLIENT_SECRET").ok(),
+        key,
+        method: provider_var("CLIENT_AUTH")
+            .ok()
+            .map(|method| method.parse().unwrap_or_else(|err| panic!("{err}"))),
     }
 }
@@ -0,0 +1,56 @@
+use std::marker::PhantomData;
+
+use axum::{extract::FromRequestParts, http::request::Parts};
+use models::session::{SessionValue, value};
+use tower_sessions::{Session, session::Error as SessionError};
+
+/// The [`SessionValue`] stored in the session, `None` when absent or stale
+pub(crate) struct Sess<T>(pub Option<T>);
+
+impl<S, T> FromRequestParts<S> for Sess<T>
+where
+    S: Send + Sync,
+    T: SessionValue,
+{
+    type Rejection = <Session as FromRequestParts<S>>::Rejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let session = Session::from_request_parts(parts, state).await?;
+        Ok(Self(value::read(&session).await))
+    }
+}
+
+/// Reads and writes one [`SessionValue`] of the session
+pub(crate) struct SessWriter<T> {
+    session: Session,
+    value: PhantomData<fn() -> T>,
+}
+
+impl<T: SessionValue> SessWriter<T> {
+    pub(crate) async fn get(&self) -> Option<T> {
+        value::read(&self.session).await
+    }
+
+    pub(crate) async fn set(&self, value: &T) -> Result<(), SessionError> {
+        value::write(&self.session, value).await
+    }
+
+    pub(crate) async fn remove(&self) -> Option<T> {
+        value::take(&self.session).await
+    }
+}
+
+impl<S, T> FromRequestParts<S> for SessWriter<T>
+where
+    S: Send + Sync,
+    T: SessionValue,
+{
+    type Rejection = <Session as FromRequestParts<S>>::Rejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self {
+            session: Session::from_request_parts(parts, state).await?,
+            value: PhantomData,
+        })
+    }
+}
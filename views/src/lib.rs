@@ -1,6 +1,11 @@
 use askama::Template;
 use askama_web::WebTemplate;
-use models::{ActiveValue, Value, generated::album};
+use models::{
+    ActiveValue, Value,
+    generated::{album, api_token, identity, user},
+    role::Role,
+    scope::Scope,
+};
 
 #[derive(Template, WebTemplate)]
 #[template(path = "index.html")]
@@ -8,6 +13,89 @@ pub struct IndexPage {
     pub user: Option<models::generated::user::Model>,
 }
 
+/// A provider button on the login page
+pub struct LoginOption {
+    pub display_name: String,
+    pub href: String,
+    /// Whether the provider was discovered, login is unavailable otherwise
+    pub available: bool,
+}
+
+#[derive(Template, WebTemplate)]
+#[template(path = "login.html")]
+pub struct LoginPage {
+    pub providers: Vec<LoginOption>,
+    /// Login query passed on to the chosen provider
+    pub fields: Vec<(String, String)>,
+    pub user: Option<models::generated::user::Model>,
+}
+
+#[derive(Template, WebTemplate)]
+#[template(path = "settings.html")]
+pub struct SettingsPage {
+    pub identities: Vec<identity::Model>,
+    pub link_options: Vec<LoginOption>,
+    pub user: Option<models::generated::user::Model>,
+}
+
+/// A signed in session on the sessions page
+pub struct ActiveSession {
+    /// See [`models::session::session_handle`]
+    pub handle: String,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: String,
+    pub refreshed_at: String,
+    /// The session viewing the page
+    pub current: bool,
+}
+
+#[derive(Template, WebTemplate)]
+#[template(path = "sessions.html")]
+pub struct SessionsPage {
+    pub sessions: Vec<ActiveSession>,
+    pub user: Option<user::Model>,
+}
+
+/// API tokens of the user, the clear text of a new token is shown only once
+#[derive(Template, WebTemplate)]
+#[template(path = "tokens.html")]
+pub struct TokensPage {
+    pub tokens: Vec<api_token::Model>,
+    pub scopes: Vec<Scope>,
+    pub new_token: Option<String>,
+    pub message: Option<String>,
+    pub user: Option<user::Model>,
+}
+
+#[derive(Template, WebTemplate)]
+#[template(path = "admin.html")]
+pub struct AdminPage {
+    pub users: Vec<user::Model>,
+    pub user: Option<user::Model>,
+}
+
+/// Device login verification, entering a user code then approving the
+/// device it belongs to
+#[derive(Template, WebTemplate)]
+#[template(path = "device.html")]
+pub struct DevicePage {
+    pub user_code: String,
+    /// Set once the code matched a pending device login
+    pub client_name: Option<String>,
+    pub message: Option<String>,
+    pub user: Option<user::Model>,
+}
+
+/// Asks a freshly re-authenticated user to repeat the interrupted action
+#[derive(Template, WebTemplate)]
+#[template(path = "confirm_action.html")]
+pub struct ConfirmActionPage {
+    pub method: String,
+    pub path: String,
+    pub user: Option<user::Model>,
+}
+
 #[derive(Template, WebTemplate)]
 #[template(path = "album_view.html")]
 pub struct AlbumView {
@@ -23,6 +111,11 @@ pub(crate) fn is_value_set<T: Into<Value>>(value: &ActiveValue<T>) -> bool {
     )
 }
 
+/// Whether the signed in user, if any, holds at least `role`
+pub(crate) fn has_role(user: Option<&user::Model>, role: Role) -> bool {
+    user.is_some_and(|user| user.has_role(role))
+}
+
 pub(crate) mod filters {
     use models::{ActiveValue, Value};
 
@@ -46,9 +139,31 @@ pub(crate) mod filters {
 mod tests {
     use super::*;
     use googletest::prelude::*;
+    use models::{DateTimeWithTimeZone, Uuid};
+    use rstest::rstest;
+
+    fn user_with(role: Role) -> user::Model {
+        user::Model {
+            id: Uuid::nil(),
+            email: "freddie@example.com".to_string(),
+            name: "Freddie".to_string(),
+            created_at: DateTimeWithTimeZone::default(),
+            updated_at: DateTimeWithTimeZone::default(),
+            given_name: None,
+            family_name: None,
+            preferred_username: None,
+            picture: None,
+            locale: None,
+            role,
+            email_verified: true,
+        }
+    }
 
+    #[rstest]
+    #[case(Role::Editor, true)]
+    #[case(Role::Reader, false)]
     #[gtest]
-    fn renders_album_title() {
+    fn renders_album_form_for_editors(#[case] role: Role, #[case] shows_form: bool) {
         let album = album::ActiveModel {
             title: ActiveValue::Set("A night at the opera".to_string()),
             ..Default::default()
@@ -56,9 +171,9 @@ mod tests {
         let value = AlbumView {
             album,
             albums: vec![],
-            user: None,
+            user: Some(user_with(role)),
         };
         let rendered = value.render().unwrap();
-        expect_that!(rendered, contains_substring("A night at the opera"));
+        expect_eq!(rendered.contains("A night at the opera"), shows_form);
     }
 }